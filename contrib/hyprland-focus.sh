@@ -0,0 +1,24 @@
+#!/bin/sh
+# Tells keyswitch which application has focus in Hyprland, by sending
+# `set-context app=<class>` to the control socket whenever focus changes.
+#
+# Needs `socat`. Pass the control socket path from the `[control]` section of
+# your config as the first argument.
+
+set -eu
+
+socket="${1:-/run/keyswitch/keyswitch.sock}"
+events="${XDG_RUNTIME_DIR}/hypr/${HYPRLAND_INSTANCE_SIGNATURE}/.socket2.sock"
+
+# Events look like `activewindow>>class,title`.
+socat -U - "UNIX-CONNECT:$events" \
+    | while IFS= read -r event; do
+        case "$event" in
+            activewindow\>\>*)
+                class="${event#activewindow>>}"
+                class="${class%%,*}"
+                printf 'set-context app=%s\n' "$(printf '%s' "$class" | tr -s '[:space:]' '_')"
+                ;;
+        esac
+    done \
+    | socat - "UNIX-CONNECT:$socket" > /dev/null
@@ -0,0 +1,18 @@
+#!/bin/sh
+# Tells keyswitch which application has focus in sway, by sending
+# `set-context app=<app_id>` to the control socket whenever focus changes.
+#
+# Needs `swaymsg`, `jq` and `socat`. Pass the control socket path from the
+# `[control]` section of your config as the first argument.
+
+set -eu
+
+socket="${1:-/run/keyswitch/keyswitch.sock}"
+
+swaymsg --type subscribe --monitor '["window"]' \
+    | jq --unbuffered --raw-output '
+        select(.change == "focus")
+        | .container
+        | "set-context app=\(.app_id // .window_properties.class // "" | gsub("\\s"; "_"))"
+    ' \
+    | socat - "UNIX-CONNECT:$socket" > /dev/null
@@ -0,0 +1,468 @@
+use crate::key_mapper::{self, MODIFIERS};
+use input_linux::{Key, KeyEvent, KeyState};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
+
+const FEATURES: &[(&str, Feature)] = &[
+    ("slow-keys", Feature::SlowKeys),
+    ("bounce-keys", Feature::BounceKeys),
+    ("sticky-keys", Feature::StickyKeys),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Keys have to be held for a while before they're pressed.
+    SlowKeys,
+    /// Pressing a key again soon after releasing it is ignored.
+    BounceKeys,
+    /// Modifiers that are tapped stay down until the next key, and lock when
+    /// they're tapped twice.
+    StickyKeys,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// How long keys have to be held with slow keys.
+    pub slow_keys: Duration,
+
+    /// How long after a key is released that it's ignored with bounce keys.
+    pub bounce_keys: Duration,
+
+    /// Features that start out enabled.
+    pub enabled: Vec<Feature>,
+
+    /// Keys that toggle a feature when they're pressed together.
+    pub toggles: Vec<(Feature, Vec<Key>)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Sticky {
+    /// Held down, and whether another key was pressed meanwhile.
+    Held { interrupted: bool },
+    /// Tapped, so it's released after the next key.
+    Latched,
+    /// Tapped twice, so it stays down until it's tapped again.
+    Locked,
+    /// Pressed again while locked, so it's released with the key.
+    Unlocking,
+}
+
+/// Accessibility features that work below the compositor, between the
+/// debouncer and the `KeyMapper`. Like the mapper, it's timed by event
+/// timestamps and `tick`.
+pub struct Accessibility {
+    settings: Settings,
+    enabled: HashSet<Feature>,
+
+    /// The latest time from an event or `tick`.
+    now: Duration,
+
+    /// Keys held on the device, to find toggles.
+    pressed: HashSet<Key>,
+
+    /// Keys whose events are dropped until they're released, like the key
+    /// that completed a toggle, or a press that bounced.
+    swallowed: HashSet<Key>,
+
+    /// Keys pressed with slow keys, and when they count as pressed.
+    slow: HashMap<Key, Duration>,
+
+    /// When each key was last released, for bounce keys.
+    released_at: HashMap<Key, Duration>,
+
+    sticky: HashMap<Key, Sticky>,
+
+    /// Features whose toggle was pressed since `take_toggled`.
+    toggled: Vec<Feature>,
+}
+
+impl Accessibility {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            enabled: settings.enabled.iter().copied().collect(),
+            settings,
+            now: Duration::ZERO,
+            pressed: HashSet::new(),
+            swallowed: HashSet::new(),
+            slow: HashMap::new(),
+            released_at: HashMap::new(),
+            sticky: HashMap::new(),
+            toggled: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.enabled.contains(&feature)
+    }
+
+    /// Returns events to send when turning a feature off, like releasing
+    /// modifiers that sticky keys held down.
+    pub fn set_enabled(&mut self, feature: Feature, enabled: bool) -> Vec<KeyEvent> {
+        if enabled {
+            self.enabled.insert(feature);
+            return vec![];
+        }
+
+        self.enabled.remove(&feature);
+
+        match feature {
+            Feature::SlowKeys => {
+                // These were never pressed, so they shouldn't be released.
+                let keys = self.slow.drain().map(|(key, _)| key).collect::<Vec<_>>();
+                self.swallowed.extend(keys);
+                vec![]
+            }
+            Feature::BounceKeys => {
+                self.released_at.clear();
+                vec![]
+            }
+            Feature::StickyKeys => {
+                let now = self.now;
+                self.sticky
+                    .drain()
+                    .filter(|(_, sticky)| matches!(sticky, Sticky::Latched | Sticky::Locked))
+                    .map(|(key, _)| key_mapper::event_at(now, key, KeyState::RELEASED))
+                    .collect()
+            }
+        }
+    }
+
+    /// Features whose toggle was pressed since the last call.
+    pub fn take_toggled(&mut self) -> Vec<Feature> {
+        self.toggled.drain(..).collect()
+    }
+
+    /// Returns the events that get through. Mouse buttons always do, as they
+    /// are.
+    pub fn filter(&mut self, event: &KeyEvent) -> Vec<KeyEvent> {
+        if key_mapper::is_mouse_button(event.key) {
+            return vec![*event];
+        }
+
+        let now = key_mapper::event_time(event);
+        self.now = self.now.max(now);
+
+        match event.value {
+            KeyState::PRESSED => self.pressed.insert(event.key),
+            KeyState::RELEASED => self.pressed.remove(&event.key),
+            _ => false,
+        };
+
+        if self.swallowed.contains(&event.key) {
+            if event.value == KeyState::RELEASED {
+                self.swallowed.remove(&event.key);
+            }
+            return vec![];
+        }
+
+        if event.value == KeyState::PRESSED {
+            let toggled = self.settings.toggles.iter().find(|(_, keys)| {
+                keys.contains(&event.key) && keys.iter().all(|key| self.pressed.contains(key))
+            });
+
+            if let Some((feature, _)) = toggled {
+                self.toggled.push(*feature);
+                self.swallowed.insert(event.key);
+                self.interrupt();
+                return vec![];
+            }
+        }
+
+        if self.is_enabled(Feature::BounceKeys) {
+            match event.value {
+                KeyState::PRESSED
+                    if self
+                        .released_at
+                        .get(&event.key)
+                        .is_some_and(|released| now < *released + self.settings.bounce_keys) =>
+                {
+                    self.swallowed.insert(event.key);
+                    return vec![];
+                }
+                KeyState::RELEASED => {
+                    self.released_at.insert(event.key, now);
+                }
+                _ => (),
+            }
+        }
+
+        if self.is_enabled(Feature::SlowKeys) {
+            match event.value {
+                KeyState::PRESSED => {
+                    self.slow.insert(event.key, now + self.settings.slow_keys);
+                    return vec![];
+                }
+                // Released too soon, so it never counted.
+                _ if self.slow.contains_key(&event.key) => {
+                    if event.value == KeyState::RELEASED {
+                        self.slow.remove(&event.key);
+                    }
+                    return vec![];
+                }
+                _ => (),
+            }
+        }
+
+        self.stick(*event)
+    }
+
+    /// Presses slow keys that have been held for long enough.
+    pub fn tick(&mut self, now: Duration) -> Vec<KeyEvent> {
+        self.now = self.now.max(now);
+
+        let mut accepted = self
+            .slow
+            .iter()
+            .filter(|(_, accepted)| now >= **accepted)
+            .map(|(key, accepted)| (*accepted, *key))
+            .collect::<Vec<_>>();
+        accepted.sort_by_key(|(accepted, _)| *accepted);
+
+        let mut events = vec![];
+        for (accepted, key) in accepted {
+            self.slow.remove(&key);
+            events.extend(self.stick(key_mapper::event_at(accepted, key, KeyState::PRESSED)));
+        }
+
+        events
+    }
+
+    /// When `tick` has something to do next.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.slow.values().copied().min()
+    }
+
+    fn stick(&mut self, event: KeyEvent) -> Vec<KeyEvent> {
+        if !self.is_enabled(Feature::StickyKeys) {
+            return vec![event];
+        }
+
+        if !MODIFIERS.contains(&event.key) {
+            if event.value == KeyState::PRESSED {
+                self.interrupt();
+            }
+
+            let mut events = vec![event];
+
+            if event.value == KeyState::RELEASED {
+                let latched = self
+                    .sticky
+                    .iter()
+                    .filter(|(_, sticky)| **sticky == Sticky::Latched)
+                    .map(|(key, _)| *key)
+                    .collect::<Vec<_>>();
+
+                for key in latched {
+                    self.sticky.remove(&key);
+                    events.push(KeyEvent::new(event.time, key, KeyState::RELEASED));
+                }
+            }
+
+            return events;
+        }
+
+        let sticky = self.sticky.get(&event.key).copied();
+        match (event.value, sticky) {
+            (KeyState::PRESSED, Some(Sticky::Latched)) => {
+                self.sticky.insert(event.key, Sticky::Locked);
+                vec![]
+            }
+            (KeyState::PRESSED, Some(Sticky::Locked)) => {
+                self.sticky.insert(event.key, Sticky::Unlocking);
+                vec![]
+            }
+            (KeyState::PRESSED, _) => {
+                self.sticky
+                    .insert(event.key, Sticky::Held { interrupted: false });
+                vec![event]
+            }
+            (KeyState::RELEASED, Some(Sticky::Held { interrupted: false })) => {
+                self.sticky.insert(event.key, Sticky::Latched);
+                vec![]
+            }
+            (KeyState::RELEASED, Some(Sticky::Held { interrupted: true }))
+            | (KeyState::RELEASED, Some(Sticky::Unlocking))
+            | (KeyState::RELEASED, None) => {
+                self.sticky.remove(&event.key);
+                vec![event]
+            }
+            (KeyState::AUTOREPEAT, Some(Sticky::Held { .. })) => vec![event],
+            _ => vec![],
+        }
+    }
+
+    /// Modifiers that are held while another key is pressed don't stick.
+    fn interrupt(&mut self) {
+        for sticky in self.sticky.values_mut() {
+            if let Sticky::Held { interrupted } = sticky {
+                *interrupted = true;
+            }
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            slow_keys: Duration::from_millis(300),
+            bounce_keys: Duration::from_millis(300),
+            enabled: vec![],
+            toggles: vec![],
+        }
+    }
+}
+
+impl Feature {
+    pub fn parse(name: &str) -> Option<Self> {
+        FEATURES
+            .iter()
+            .find(|(feature, _)| *feature == name)
+            .map(|(_, feature)| *feature)
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, _) = FEATURES
+            .iter()
+            .find(|(_, feature)| feature == self)
+            .expect("every feature should have a name");
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accessibility, Feature, Settings};
+    use input_linux::{EventTime, Key, KeyEvent, KeyState};
+    use std::time::Duration;
+
+    fn event(key: Key, value: KeyState, millis: i64) -> KeyEvent {
+        KeyEvent::new(EventTime::new(0, millis * 1000), key, value)
+    }
+
+    fn accessibility(feature: Feature) -> Accessibility {
+        Accessibility::new(Settings {
+            enabled: vec![feature],
+            toggles: vec![(Feature::SlowKeys, vec![Key::LeftShift, Key::RightShift])],
+            ..Settings::default()
+        })
+    }
+
+    #[test]
+    fn it_only_presses_slow_keys_held_long_enough() {
+        let mut accessibility = accessibility(Feature::SlowKeys);
+
+        assert_eq!(
+            accessibility.filter(&event(Key::J, KeyState::PRESSED, 0)),
+            vec![]
+        );
+        assert_eq!(
+            accessibility.filter(&event(Key::J, KeyState::RELEASED, 100)),
+            vec![]
+        );
+        assert_eq!(accessibility.next_deadline(), None);
+
+        accessibility.filter(&event(Key::K, KeyState::PRESSED, 200));
+        assert_eq!(
+            accessibility.next_deadline(),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            accessibility.tick(Duration::from_millis(510)),
+            vec![event(Key::K, KeyState::PRESSED, 500)]
+        );
+
+        let release = event(Key::K, KeyState::RELEASED, 600);
+        assert_eq!(accessibility.filter(&release), vec![release]);
+    }
+
+    #[test]
+    fn it_ignores_keys_pressed_again_too_soon_with_bounce_keys() {
+        let mut accessibility = accessibility(Feature::BounceKeys);
+
+        accessibility.filter(&event(Key::J, KeyState::PRESSED, 0));
+        accessibility.filter(&event(Key::J, KeyState::RELEASED, 50));
+        assert_eq!(
+            accessibility.filter(&event(Key::J, KeyState::PRESSED, 100)),
+            vec![]
+        );
+        assert_eq!(
+            accessibility.filter(&event(Key::J, KeyState::RELEASED, 150)),
+            vec![]
+        );
+
+        let press = event(Key::J, KeyState::PRESSED, 400);
+        assert_eq!(accessibility.filter(&press), vec![press]);
+    }
+
+    #[test]
+    fn it_keeps_tapped_modifiers_down_with_sticky_keys() {
+        let mut accessibility = accessibility(Feature::StickyKeys);
+
+        let shift = event(Key::LeftShift, KeyState::PRESSED, 0);
+        assert_eq!(accessibility.filter(&shift), vec![shift]);
+        assert_eq!(
+            accessibility.filter(&event(Key::LeftShift, KeyState::RELEASED, 10)),
+            vec![]
+        );
+
+        accessibility.filter(&event(Key::A, KeyState::PRESSED, 20));
+        assert_eq!(
+            accessibility.filter(&event(Key::A, KeyState::RELEASED, 30)),
+            vec![
+                event(Key::A, KeyState::RELEASED, 30),
+                event(Key::LeftShift, KeyState::RELEASED, 30)
+            ]
+        );
+
+        // Tapped twice, it stays down until it's turned off.
+        for millis in [40, 60].iter() {
+            accessibility.filter(&event(Key::LeftCtrl, KeyState::PRESSED, *millis));
+            accessibility.filter(&event(Key::LeftCtrl, KeyState::RELEASED, millis + 10));
+        }
+        let a = event(Key::A, KeyState::RELEASED, 90);
+        assert_eq!(accessibility.filter(&a), vec![a]);
+        assert_eq!(
+            accessibility.set_enabled(Feature::StickyKeys, false),
+            vec![event(Key::LeftCtrl, KeyState::RELEASED, 90)]
+        );
+    }
+
+    #[test]
+    fn it_lets_mouse_buttons_through() {
+        let mut slow = accessibility(Feature::SlowKeys);
+
+        let click = event(Key::ButtonLeft, KeyState::PRESSED, 0);
+        assert_eq!(slow.filter(&click), vec![click]);
+        assert_eq!(slow.next_deadline(), None);
+
+        let mut sticky = accessibility(Feature::StickyKeys);
+
+        sticky.filter(&event(Key::LeftShift, KeyState::PRESSED, 0));
+        sticky.filter(&event(Key::LeftShift, KeyState::RELEASED, 10));
+        let release = event(Key::ButtonLeft, KeyState::RELEASED, 30);
+        assert_eq!(sticky.filter(&release), vec![release]);
+    }
+
+    #[test]
+    fn it_swallows_the_keys_that_toggle_features() {
+        let mut accessibility = accessibility(Feature::StickyKeys);
+
+        accessibility.filter(&event(Key::LeftShift, KeyState::PRESSED, 0));
+        assert_eq!(
+            accessibility.filter(&event(Key::RightShift, KeyState::PRESSED, 10)),
+            vec![]
+        );
+        assert_eq!(
+            accessibility.filter(&event(Key::RightShift, KeyState::RELEASED, 20)),
+            vec![]
+        );
+        assert_eq!(accessibility.take_toggled(), vec![Feature::SlowKeys]);
+        assert_eq!(Feature::parse("slow-keys"), Some(Feature::SlowKeys));
+        assert_eq!(Feature::StickyKeys.to_string(), "sticky-keys");
+    }
+}
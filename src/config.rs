@@ -0,0 +1,3827 @@
+use crate::{
+    accessibility::{self, Accessibility, Feature},
+    control::Context,
+    debounce::{self, Debounce, Debouncer},
+    device::{Device, Selector},
+    exec::{self, User},
+    key_mapper::{
+        self, Action, AutoShift, CapsWord, Conditions, Decision, HeldModifiers, KeyMapper, Leader,
+        Modifier, MouseAction, MouseSettings, Repeat, TapDance, TapHold,
+    },
+    key_names,
+    layout::Layout,
+    privileges::{self, PrivilegesConfig},
+    text::{self, TextMethod},
+};
+use input_linux::{EventTime, Key, KeyEvent, KeyState};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Used when no configuration file is given.
+const DEFAULT_CONFIG: &str = r#"
+[[mapping]]
+keys = ["CapsLock"]
+output = "LeftCtrl"
+
+[[layer]]
+name = "arrows"
+hold = ["CapsLock"]
+
+[[layer.mapping]]
+keys = ["H"]
+output = "Left"
+
+[[layer.mapping]]
+keys = ["J"]
+output = "Down"
+
+[[layer.mapping]]
+keys = ["K"]
+output = "Up"
+
+[[layer.mapping]]
+keys = ["L"]
+output = "Right"
+"#;
+
+pub struct Config {
+    mappings: Vec<Mapping>,
+    combos: Vec<Combo>,
+    leader: Option<Leader>,
+
+    /// Keys tapped after the leader key, which are stored as mappings.
+    sequences: Vec<Mapping>,
+
+    /// Keys that do one thing when tapped and another when held.
+    tap_holds: Vec<(Key, TapHold)>,
+
+    /// Keys that send nothing, and pairs of keys that send each other, as
+    /// mappings. The mapper tries these before any other mapping, even one in
+    /// a layer.
+    shortcuts: Vec<Mapping>,
+    mouse: MouseSettings,
+    text_method: TextMethod,
+    layout: Layout,
+    caps_word: CapsWord,
+    auto_shift: Option<AutoShift>,
+
+    /// Mappings of layers that one-shot mappings and tap-hold keys enable,
+    /// without the keys that hold them.
+    modifier_mappings: Vec<Mapping>,
+    devices: Vec<DeviceConfig>,
+    context_rules: Vec<ContextRule>,
+    control: Option<ControlConfig>,
+    privileges: Option<PrivilegesConfig>,
+    repeat: Option<RepeatConfig>,
+
+    /// Used for keys that aren't in `key_debounces`.
+    debounce: Option<Debounce>,
+    key_debounces: HashMap<Key, Option<Debounce>>,
+    accessibility: accessibility::Settings,
+
+    /// Layers used by devices without a `layers` list, which are the ones
+    /// with `hold` keys that aren't enabled by context rules.
+    default_layers: HashSet<String>,
+}
+
+/// A mapping after layers have been flattened, in the same form that
+/// `KeyMapper::add_mapping` expects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub keys: Vec<Key>,
+    pub action: Action,
+    pub origin: Origin,
+
+    /// The layer that has to be used for this mapping to apply, if any.
+    pub layer: Option<String>,
+
+    /// Keys that have to be held, or can't be, besides the prefixes.
+    pub conditions: Conditions,
+    pub modifiers: HeldModifiers,
+}
+
+/// Keys that do something else when pressed together, in any order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Combo {
+    pub keys: Vec<Key>,
+    pub action: Action,
+    /// How long after the first key the rest have to be pressed.
+    pub timeout: Duration,
+    pub origin: Origin,
+}
+
+/// Used for combos without a `timeout`.
+const DEFAULT_COMBO_TIMEOUT: Duration = Duration::from_millis(30);
+
+/// Used for tap-hold keys without a `timeout`.
+const DEFAULT_TAP_HOLD_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Used when debouncing without a `time`.
+const DEFAULT_DEBOUNCE_TIME: u64 = 5;
+
+/// Used for auto-shift without a `timeout`.
+const DEFAULT_AUTO_SHIFT_TIMEOUT: Duration = Duration::from_millis(175);
+
+/// Used for tap-dances without a `term`.
+const DEFAULT_TAP_DANCE_TERM: Duration = Duration::from_millis(200);
+
+/// Used for the leader without a `timeout`.
+const DEFAULT_SEQUENCE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Settings for devices matching a selector, which each get their own
+/// `KeyMapper`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub selector: Selector,
+
+    /// Layers used for this device. Top level mappings always apply. When not
+    /// set, every layer with `hold` keys is used.
+    pub layers: Option<Vec<String>>,
+
+    /// Devices with the same output share a virtual device. Devices without
+    /// one share the default virtual device.
+    pub output: Option<String>,
+}
+
+/// Layers that are enabled on every device while the context matches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContextRule {
+    /// Context keys, and the values that each one may have for the rule to
+    /// match.
+    pub conditions: Vec<(String, Vec<String>)>,
+    pub layers: Vec<String>,
+}
+
+/// Where to listen for commands, like `set-context`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlConfig {
+    pub socket: PathBuf,
+    pub mode: u32,
+}
+
+/// How keys repeat on the virtual devices, instead of passing through the
+/// repeats of the keyboard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepeatConfig {
+    pub repeat: Repeat,
+
+    /// Whether keyswitch repeats keys itself, instead of the kernel.
+    pub software: bool,
+
+    /// Keys that keyswitch doesn't repeat, including the keys that hold
+    /// layers.
+    pub exclude: Vec<Key>,
+}
+
+/// Where a mapping was written in the configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub layer: Option<String>,
+    /// Position of the mapping within its layer, or the top level, starting at 1.
+    pub index: usize,
+    /// Each kind is counted separately.
+    pub kind: Kind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Mapping,
+    Combo,
+    Sequence,
+    TapHold,
+    /// A key in the `disable` list.
+    Disable,
+    /// A pair of keys in the `swap` list.
+    Swap,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    mapping: Vec<RawMapping>,
+    #[serde(default)]
+    combo: Vec<RawCombo>,
+    #[serde(rename = "tap-hold", default)]
+    tap_hold: Vec<RawTapHold>,
+    #[serde(default)]
+    disable: Vec<String>,
+    #[serde(default)]
+    swap: Vec<Vec<String>>,
+    #[serde(default)]
+    layer: Vec<RawLayer>,
+    #[serde(default)]
+    device: Vec<RawDevice>,
+    #[serde(default)]
+    context: Vec<RawContextRule>,
+    leader: Option<RawLeader>,
+    mouse: Option<RawMouse>,
+    text: Option<RawText>,
+    layout: Option<RawLayout>,
+    #[serde(rename = "caps-word")]
+    caps_word: Option<RawCapsWord>,
+    #[serde(rename = "auto-shift")]
+    auto_shift: Option<RawAutoShift>,
+    repeat: Option<RawRepeat>,
+    debounce: Option<RawDebounce>,
+    accessibility: Option<RawAccessibility>,
+    control: Option<RawControl>,
+    privileges: Option<RawPrivileges>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMapping {
+    keys: Vec<String>,
+    output: Option<String>,
+    exec: Option<RawExec>,
+    oneshot: Option<RawModifier>,
+    mouse: Option<String>,
+    text: Option<String>,
+    #[serde(rename = "tap-dance")]
+    tap_dance: Option<RawTapDance>,
+    #[serde(rename = "caps-word", default)]
+    caps_word: bool,
+
+    /// Groups of keys that at least one key of each has to be held, like
+    /// `["LeftShift", "RightShift"]` for either Shift.
+    #[serde(default)]
+    any: Vec<Vec<String>>,
+
+    /// Keys that can't be held for the mapping to apply.
+    #[serde(default)]
+    unless: Vec<String>,
+
+    /// Whether held modifiers that aren't prefixes are kept.
+    #[serde(default)]
+    modifiers: RawHeldModifiers,
+}
+
+/// The action of a mapping, out of the fields that each set one.
+#[derive(Clone, Copy)]
+enum RawAction<'a> {
+    Output(&'a str),
+    Exec(&'a RawExec),
+    OneShot(&'a RawModifier),
+    Mouse(&'a str),
+    Text(&'a str),
+    TapDance(&'a RawTapDance),
+    CapsWord,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RawHeldModifiers {
+    #[default]
+    Keep,
+    Strip,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTapDance {
+    taps: Vec<String>,
+    hold: Option<RawModifier>,
+
+    /// In milliseconds.
+    term: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCombo {
+    keys: Vec<String>,
+    output: Option<String>,
+    exec: Option<RawExec>,
+    text: Option<String>,
+    #[serde(rename = "caps-word", default)]
+    caps_word: bool,
+
+    /// In milliseconds.
+    timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTapHold {
+    key: String,
+
+    /// Defaults to the key itself.
+    tap: Option<String>,
+    hold: RawModifier,
+
+    /// In milliseconds.
+    timeout: Option<u64>,
+
+    /// In milliseconds. Quick taps are off by default.
+    #[serde(rename = "quick-tap", default)]
+    quick_tap: u64,
+    #[serde(default)]
+    decision: RawDecision,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RawDecision {
+    #[default]
+    Timeout,
+    PermissiveHold,
+    HoldOnOtherKeyPress,
+}
+
+/// Times are in milliseconds, and speeds in pixels per step.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RawMouse {
+    interval: Option<u64>,
+    speed: Option<f64>,
+    max_speed: Option<f64>,
+    acceleration: Option<u64>,
+    curve: Option<f64>,
+    scroll_interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawText {
+    #[serde(default)]
+    method: RawTextMethod,
+
+    /// Defaults to the Compose key.
+    compose: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawTextMethod {
+    #[default]
+    Hex,
+    Compose,
+    Layout,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCapsWord {
+    /// In milliseconds, and 0 or none never times out.
+    timeout: Option<u64>,
+    #[serde(default)]
+    underscore: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAutoShift {
+    /// Defaults to the keys that type letters and digits on the layout.
+    keys: Option<Vec<String>>,
+
+    /// In milliseconds.
+    timeout: Option<u64>,
+    #[serde(default)]
+    repeat: bool,
+}
+
+/// Times are in milliseconds.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRepeat {
+    delay: Option<u64>,
+    period: Option<u64>,
+    #[serde(default)]
+    software: bool,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+/// Times are in milliseconds, and 0 turns debouncing off.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDebounce {
+    #[serde(default)]
+    algorithm: RawAlgorithm,
+    time: Option<u64>,
+
+    /// Keys with their own settings, which default to the ones above.
+    #[serde(default)]
+    key: Vec<RawKeyDebounce>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RawAccessibility {
+    slow_keys: Option<RawFeature>,
+    bounce_keys: Option<RawFeature>,
+    sticky_keys: Option<RawFeature>,
+}
+
+/// A feature is enabled when its section is there, unless `enabled` is false.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFeature {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+
+    /// In milliseconds. Sticky keys don't have one.
+    delay: Option<u64>,
+
+    /// Keys that toggle the feature when they're pressed together.
+    #[serde(default)]
+    toggle: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawKeyDebounce {
+    keys: Vec<String>,
+    algorithm: Option<RawAlgorithm>,
+    time: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawAlgorithm {
+    #[default]
+    Eager,
+    Defer,
+}
+
+/// An XKB layout, like `us` with the `dvorak` variant.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLayout {
+    name: String,
+    variant: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLeader {
+    key: String,
+
+    /// In milliseconds, for each key of a sequence.
+    timeout: Option<u64>,
+    cancel: Option<String>,
+    #[serde(default)]
+    unmatched: RawUnmatched,
+    #[serde(default)]
+    sequence: Vec<RawMapping>,
+}
+
+/// What to do with sequences that don't match anything.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawUnmatched {
+    #[default]
+    Drop,
+    Replay,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum RawExec {
+    /// A command line that's run with `sh -c`.
+    Shell(String),
+    Command(RawCommand),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawModifier {
+    Key(String),
+    Layer { layer: String },
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCommand {
+    command: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    user: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLayer {
+    name: String,
+
+    /// Keys that activate the layer while held. They become prefixes of
+    /// every mapping in the layer.
+    #[serde(default)]
+    hold: Vec<String>,
+
+    /// Other layers whose mappings are also active in this layer.
+    #[serde(default)]
+    include: Vec<String>,
+
+    #[serde(default)]
+    mapping: Vec<RawMapping>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDevice {
+    name: Option<String>,
+    path: Option<PathBuf>,
+    layers: Option<Vec<String>>,
+    output: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawContextRule {
+    #[serde(rename = "match", default)]
+    conditions: HashMap<String, RawValues>,
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValues {
+    One(String),
+    Any(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawControl {
+    socket: PathBuf,
+
+    /// Permissions of the socket file.
+    #[serde(default = "default_socket_mode")]
+    mode: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPrivileges {
+    user: Option<String>,
+    group: Option<String>,
+}
+
+fn default_socket_mode() -> u32 {
+    0o600
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Config {
+    /// Loads a configuration file, failing if it has any errors. Problems that
+    /// are only warnings are ignored, use `check` to find those.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let (config, problems) = Self::check(path)?;
+        let errors = problems
+            .into_iter()
+            .filter(Problem::is_error)
+            .collect::<Vec<_>>();
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(Error::InvalidConfigError(errors))
+        }
+    }
+
+    pub fn check(path: &Path) -> Result<(Self, Vec<Problem>), Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<(Self, Vec<Problem>), Error> {
+        Self::parse_with(source, &Layout::load)
+    }
+
+    /// Parses a config, loading its layout with `load`.
+    fn parse_with(source: &str, load: &LoadLayout) -> Result<(Self, Vec<Problem>), Error> {
+        let mut raw: RawConfig = toml::from_str(source)?;
+        let (layout, mut problems) = match parse_layout(&raw, load) {
+            Ok(layout) => (layout, vec![]),
+            Err(name) => (
+                Layout::default(),
+                vec![Problem::UnknownKeyboardLayout(name)],
+            ),
+        };
+        resolve_characters(&mut raw, &layout);
+        problems.extend(check_names(&raw, &layout));
+        let mappings = flatten(&raw);
+        let combos = parse_combos(&raw);
+        let sequences = parse_sequences(&raw);
+        let tap_holds = parse_tap_holds(&raw);
+        let shortcuts = parse_shortcuts(&raw);
+        let mouse = parse_mouse(&raw);
+        let text_method = parse_text_method(&raw);
+        let caps_word = raw
+            .caps_word
+            .as_ref()
+            .map(|caps_word| CapsWord {
+                timeout: caps_word
+                    .timeout
+                    .filter(|timeout| *timeout > 0)
+                    .map(Duration::from_millis),
+                underscore: caps_word.underscore,
+            })
+            .unwrap_or_default();
+        let repeat = parse_repeat(&raw);
+        let auto_shift = parse_auto_shift(&raw, &layout);
+        let (debounce, key_debounces) = parse_debounce(&raw);
+        let accessibility = parse_accessibility(&raw);
+        let modifier_mappings = flatten_modifier_layers(&raw);
+        let context_rules = raw
+            .context
+            .into_iter()
+            .map(|rule| {
+                let mut conditions = rule
+                    .conditions
+                    .into_iter()
+                    .map(|(key, values)| match values {
+                        RawValues::One(value) => (key, vec![value]),
+                        RawValues::Any(values) => (key, values),
+                    })
+                    .collect::<Vec<_>>();
+                conditions.sort();
+
+                ContextRule {
+                    conditions,
+                    layers: rule.layers,
+                }
+            })
+            .collect::<Vec<_>>();
+        let config = Self {
+            mappings,
+            combos,
+            leader: raw.leader.as_ref().and_then(|leader| {
+                Some(Leader {
+                    key: key_names::parse(&leader.key)?,
+                    timeout: leader
+                        .timeout
+                        .map(Duration::from_millis)
+                        .unwrap_or(DEFAULT_SEQUENCE_TIMEOUT),
+                    cancel: match &leader.cancel {
+                        Some(name) => Some(key_names::parse(name)?),
+                        None => None,
+                    },
+                    replay_unmatched: matches!(leader.unmatched, RawUnmatched::Replay),
+                })
+            }),
+            sequences,
+            tap_holds,
+            shortcuts,
+            mouse,
+            text_method,
+            layout,
+            caps_word,
+            auto_shift,
+            modifier_mappings,
+            default_layers: raw
+                .layer
+                .iter()
+                .filter(|layer| {
+                    !layer.hold.is_empty()
+                        && !context_rules
+                            .iter()
+                            .any(|rule| rule.layers.contains(&layer.name))
+                })
+                .map(|layer| layer.name.clone())
+                .collect(),
+            context_rules,
+            control: raw.control.map(|control| ControlConfig {
+                socket: control.socket,
+                mode: control.mode,
+            }),
+            repeat,
+            debounce,
+            key_debounces,
+            accessibility,
+            privileges: raw.privileges.map(|privileges| PrivilegesConfig {
+                user: privileges.user,
+                group: privileges.group,
+            }),
+            devices: raw
+                .device
+                .into_iter()
+                .map(|device| DeviceConfig {
+                    selector: Selector {
+                        name: device.name,
+                        path: device.path,
+                    },
+                    layers: device.layers,
+                    output: device.output,
+                })
+                .collect(),
+        };
+
+        // Each device can have a different set of mappings, so they're all
+        // checked, skipping problems that were already found.
+        let device_configs = Some(None)
+            .into_iter()
+            .chain(config.devices.iter().map(Some));
+        for device_config in device_configs {
+            for problem in check_order(&config.mapper_mappings(device_config)) {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+
+        Ok((config, problems))
+    }
+
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    pub fn combos(&self) -> &[Combo] {
+        &self.combos
+    }
+
+    pub fn leader(&self) -> Option<&Leader> {
+        self.leader.as_ref()
+    }
+
+    pub fn sequences(&self) -> &[Mapping] {
+        &self.sequences
+    }
+
+    pub fn tap_holds(&self) -> &[(Key, TapHold)] {
+        &self.tap_holds
+    }
+
+    pub fn mouse(&self) -> &MouseSettings {
+        &self.mouse
+    }
+
+    pub fn text_method(&self) -> &TextMethod {
+        &self.text_method
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn repeat(&self) -> Option<&RepeatConfig> {
+        self.repeat.as_ref()
+    }
+
+    /// Filters chatter on a device, before its `KeyMapper`.
+    pub fn debouncer(&self) -> Debouncer {
+        Debouncer::new(self.debounce, self.key_debounces.clone())
+    }
+
+    /// Accessibility features for a device, between its debouncer and its
+    /// `KeyMapper`.
+    pub fn accessibility(&self) -> Accessibility {
+        Accessibility::new(self.accessibility.clone())
+    }
+
+    pub fn devices(&self) -> &[DeviceConfig] {
+        &self.devices
+    }
+
+    pub fn control(&self) -> Option<&ControlConfig> {
+        self.control.as_ref()
+    }
+
+    /// Every command that mappings, combos and sequences can run.
+    pub fn commands(&self) -> impl Iterator<Item = &exec::Command> {
+        self.mappings
+            .iter()
+            .chain(self.modifier_mappings.iter())
+            .chain(self.sequences.iter())
+            .map(|mapping| &mapping.action)
+            .chain(self.combos.iter().map(|combo| &combo.action))
+            .filter_map(|action| match action {
+                Action::Exec(command) => Some(command),
+                _ => None,
+            })
+    }
+
+    pub fn privileges(&self) -> Option<&PrivilegesConfig> {
+        self.privileges.as_ref()
+    }
+
+    pub fn context_rules(&self) -> &[ContextRule] {
+        &self.context_rules
+    }
+
+    pub fn context_layers(&self, context: &Context) -> HashSet<String> {
+        context_layers(&self.context_rules, context)
+    }
+
+    /// The settings for a device, taken from the first device section with a
+    /// matching selector.
+    pub fn device_config(&self, device: &Device) -> Option<&DeviceConfig> {
+        self.devices
+            .iter()
+            .find(|device_config| device_config.selector.matches(device))
+    }
+
+    /// The mappings that apply to a device with the given settings, or to a
+    /// device without any settings.
+    pub fn mappings_for(&self, device_config: Option<&DeviceConfig>) -> Vec<Mapping> {
+        let layers = device_config.and_then(|device_config| device_config.layers.as_ref());
+
+        self.mappings
+            .iter()
+            .filter(|mapping| match (&mapping.layer, layers) {
+                (None, _) => true,
+                (Some(layer), Some(layers)) => layers.contains(layer),
+                (Some(layer), None) => self.default_layers.contains(layer),
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn key_mapper(
+        &self,
+        device_config: Option<&DeviceConfig>,
+    ) -> Result<KeyMapper, key_mapper::Error> {
+        let mut mapper = KeyMapper::new();
+        mapper.set_mouse(self.mouse.clone());
+        mapper.set_text_method(self.text_method.clone());
+        mapper.set_layout(self.layout.clone());
+        mapper.set_caps_word(self.caps_word);
+
+        if let Some(auto_shift) = &self.auto_shift {
+            mapper.set_auto_shift(auto_shift.clone());
+        }
+
+        if let Some(repeat) = self.repeat.as_ref().filter(|repeat| repeat.software) {
+            mapper.set_repeat(repeat.repeat, &repeat.exclude);
+        }
+
+        for (mapping, layer) in self.mapper_mappings(device_config) {
+            mapping.add_to(&mut mapper, layer.as_deref())?;
+        }
+
+        for combo in self.combos.iter() {
+            mapper.add_combo(&combo.keys, combo.action.clone(), combo.timeout)?;
+        }
+
+        for (key, tap_hold) in self.tap_holds.iter() {
+            mapper.add_tap_hold(*key, tap_hold.clone());
+        }
+
+        if let Some(leader) = &self.leader {
+            mapper.set_leader(leader.clone());
+
+            for sequence in self.sequences.iter() {
+                mapper.add_sequence(&sequence.keys, sequence.action.clone())?;
+            }
+        }
+
+        Ok(mapper)
+    }
+
+    /// The mappings that are added to a device's mapper, along with the layer
+    /// that has to be enabled there for each to apply. Layers held by keys are
+    /// flattened into prefixes instead, so their mappings don't need one.
+    /// Disabled and swapped keys apply to every device.
+    fn mapper_mappings(
+        &self,
+        device_config: Option<&DeviceConfig>,
+    ) -> Vec<(Mapping, Option<String>)> {
+        let mut added = self
+            .shortcuts
+            .iter()
+            .cloned()
+            .chain(self.mappings_for(device_config))
+            .map(|mapping| (mapping, None))
+            .collect::<Vec<_>>();
+
+        // Layers that context rules enable are added too, but they only apply
+        // while the context matches.
+        for rule in self.context_rules.iter() {
+            for layer in rule.layers.iter() {
+                for mapping in self.mappings.iter() {
+                    if mapping.layer.as_ref() == Some(layer)
+                        && !added.iter().any(|(added, _)| added == mapping)
+                    {
+                        added.push((mapping.clone(), Some(layer.clone())));
+                    }
+                }
+            }
+        }
+
+        // So do layers that one-shot mappings and tap-hold keys enable.
+        for mapping in self.modifier_mappings.iter() {
+            if let Some(layer) = &mapping.layer {
+                if !added.iter().any(|(added, _)| added == mapping) {
+                    added.push((mapping.clone(), Some(layer.clone())));
+                }
+            }
+        }
+
+        added
+    }
+
+    /// Describes what happens when the given keys are pressed in order, and
+    /// then released in reverse order.
+    pub fn explain(
+        &self,
+        keys: &[Key],
+        device_config: Option<&DeviceConfig>,
+    ) -> Result<Explanation, key_mapper::Error> {
+        // No layers are enabled while explaining, so only mappings that don't
+        // need one can match.
+        let mappings = self.mapper_mappings(device_config);
+        let mapping = keys.split_last().and_then(|(old, prefixes)| {
+            in_mapper_order(&mappings)
+                .into_iter()
+                .find(|(mapping, layer)| layer.is_none() && mapping.matches(*old, prefixes))
+                .map(|(mapping, _)| mapping.clone())
+        });
+
+        let mut mapper = self.key_mapper(device_config)?;
+        let inputs = keys
+            .iter()
+            .map(|key| (*key, KeyState::PRESSED))
+            .chain(keys.iter().rev().map(|key| (*key, KeyState::RELEASED)));
+        let steps = inputs
+            .map(|(key, state)| Step {
+                input: (key, state),
+                output: mapper.handle_key_event(&KeyEvent::new(EventTime::new(0, 0), key, state)),
+                commands: mapper.take_commands(),
+            })
+            .collect();
+
+        Ok(Explanation { mapping, steps })
+    }
+}
+
+impl Mapping {
+    /// Adds this to a mapper, only applying while `layer` is enabled if given.
+    fn add_to(&self, mapper: &mut KeyMapper, layer: Option<&str>) -> Result<(), key_mapper::Error> {
+        mapper.add_conditional_action(
+            layer,
+            &self.keys,
+            self.conditions.clone(),
+            self.modifiers,
+            self.action.clone(),
+        )
+    }
+
+    /// Whether this applies when `old` is pressed while `held` are.
+    fn matches(&self, old: Key, held: &[Key]) -> bool {
+        self.keys.split_last().is_some_and(|(key, prefixes)| {
+            *key == old
+                && prefixes.iter().all(|key| held.contains(key))
+                && self
+                    .conditions
+                    .any
+                    .iter()
+                    .all(|group| group.iter().any(|key| held.contains(key)))
+                && !self.conditions.unless.iter().any(|key| held.contains(key))
+        })
+    }
+
+    /// Whether this always applies before `later` could, assuming it's tried
+    /// first. `later` could be held with any one key of each of its groups.
+    fn shadows(&self, later: &Mapping) -> bool {
+        let ((old, prefixes), (later_old, later_prefixes)) =
+            match (self.keys.split_last(), later.keys.split_last()) {
+                (Some(split), Some(later_split)) => (split, later_split),
+                _ => return false,
+            };
+        let later_any = &later.conditions.any;
+        let unless = &self.conditions.unless;
+
+        old == later_old
+            && prefixes.iter().all(|key| later_prefixes.contains(key))
+            && self.conditions.any.iter().all(|group| {
+                later_prefixes.iter().any(|key| group.contains(key))
+                    || later_any
+                        .iter()
+                        .any(|later_group| later_group.iter().all(|key| group.contains(key)))
+            })
+            && !later_prefixes
+                .iter()
+                .chain(later_any.iter().flatten())
+                .any(|key| unless.contains(key))
+    }
+}
+
+impl ContextRule {
+    pub fn matches(&self, context: &Context) -> bool {
+        self.conditions
+            .iter()
+            .all(|(key, values)| context.get(key).is_some_and(|value| values.contains(value)))
+    }
+}
+
+/// Layers enabled by every context rule that matches.
+pub fn context_layers(rules: &[ContextRule], context: &Context) -> HashSet<String> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(context))
+        .flat_map(|rule| rule.layers.iter().cloned())
+        .collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let (config, _) = Self::parse(DEFAULT_CONFIG).expect("default config should be valid");
+        config
+    }
+}
+
+/// Reports any names in the configuration that don't refer to anything.
+fn check_names(raw: &RawConfig, layout: &Layout) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    for (index, mapping) in raw.mapping.iter().enumerate() {
+        let origin = Origin {
+            layer: None,
+            index: index + 1,
+            kind: Kind::Mapping,
+        };
+        check_mapping(raw, layout, origin, mapping, &mut problems);
+    }
+
+    for (index, combo) in raw.combo.iter().enumerate() {
+        let origin = Origin {
+            layer: None,
+            index: index + 1,
+            kind: Kind::Combo,
+        };
+
+        let unique = combo.keys.iter().filter_map(|name| key_names::parse(name));
+        if unique.collect::<HashSet<_>>().len() < 2 {
+            problems.push(Problem::BadCombo(origin.clone()));
+        }
+
+        check_mapping(raw, layout, origin, &combo.mapping(), &mut problems);
+    }
+
+    for (index, tap_hold) in raw.tap_hold.iter().enumerate() {
+        let origin = Origin {
+            layer: None,
+            index: index + 1,
+            kind: Kind::TapHold,
+        };
+
+        let hold_key = match &tap_hold.hold {
+            RawModifier::Key(name) => Some(name),
+            RawModifier::Layer { layer } => {
+                if find_layer(raw, layer).is_none() {
+                    problems.push(Problem::UnknownModifierLayer(origin.clone(), layer.clone()));
+                }
+                None
+            }
+        };
+
+        let names = Some(&tap_hold.key).into_iter().chain(&tap_hold.tap);
+        for name in names.chain(hold_key) {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownKey(Some(origin.clone()), name.clone()));
+            }
+        }
+
+        let first = raw.tap_hold[..index]
+            .iter()
+            .position(|first| first.key == tap_hold.key);
+        if let Some(first) = first {
+            let first = Origin {
+                index: first + 1,
+                ..origin.clone()
+            };
+            problems.push(Problem::DuplicateTapHold(first, origin));
+        }
+    }
+
+    let mut layer_names = HashSet::new();
+
+    for layer in raw.layer.iter() {
+        if !layer_names.insert(&layer.name) {
+            problems.push(Problem::DuplicateLayer(layer.name.clone()));
+        }
+
+        for name in layer.hold.iter() {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownKey(None, name.clone()));
+            }
+        }
+
+        for name in layer.include.iter() {
+            if find_layer(raw, name).is_none() {
+                problems.push(Problem::UnknownLayer(layer.name.clone(), name.clone()));
+            }
+        }
+
+        for (index, mapping) in layer.mapping.iter().enumerate() {
+            let origin = Origin {
+                layer: Some(layer.name.clone()),
+                index: index + 1,
+                kind: Kind::Mapping,
+            };
+            check_mapping(raw, layout, origin, mapping, &mut problems);
+        }
+    }
+
+    for (index, device) in raw.device.iter().enumerate() {
+        if device.name.is_none() && device.path.is_none() {
+            problems.push(Problem::EmptySelector(index + 1));
+        }
+
+        for name in device.layers.iter().flatten() {
+            if find_layer(raw, name).is_none() {
+                problems.push(Problem::UnknownDeviceLayer(index + 1, name.clone()));
+            }
+        }
+    }
+
+    for (index, rule) in raw.context.iter().enumerate() {
+        for name in rule.layers.iter() {
+            if find_layer(raw, name).is_none() {
+                problems.push(Problem::UnknownContextLayer(index + 1, name.clone()));
+            }
+        }
+    }
+
+    if let Some(leader) = &raw.leader {
+        for name in Some(&leader.key).into_iter().chain(leader.cancel.as_ref()) {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownLeaderKey(name.clone()));
+            }
+        }
+
+        for (index, mapping) in leader.sequence.iter().enumerate() {
+            let origin = Origin {
+                layer: None,
+                index: index + 1,
+                kind: Kind::Sequence,
+            };
+            // There's no key to hold after a sequence.
+            if matches!(
+                mapping.action(),
+                Some(RawAction::OneShot(_))
+                    | Some(RawAction::Mouse(_))
+                    | Some(RawAction::TapDance(_))
+            ) {
+                problems.push(Problem::BadAction(origin.clone()));
+            }
+
+            // Sequences are typed after the leader, so nothing is held.
+            if !mapping.any.is_empty() || !mapping.unless.is_empty() {
+                problems.push(Problem::SequenceConditions(origin.clone()));
+            }
+
+            check_mapping(raw, layout, origin, mapping, &mut problems);
+        }
+
+        let sequences = parse_sequences(raw);
+        for (index, sequence) in sequences.iter().enumerate() {
+            if let Some(first) = sequences[..index]
+                .iter()
+                .find(|first| first.keys == sequence.keys)
+            {
+                problems.push(Problem::DuplicateSequence(
+                    first.origin.clone(),
+                    sequence.origin.clone(),
+                ));
+            }
+        }
+    }
+
+    if let Some(mouse) = &raw.mouse {
+        // Not a number isn't positive either.
+        let not_positive =
+            |value: Option<f64>| value.is_some_and(|value| value.is_nan() || value <= 0.0);
+        let settings = [
+            (
+                "interval",
+                mouse.interval.is_some_and(|interval| interval == 0),
+            ),
+            ("speed", not_positive(mouse.speed)),
+            ("max-speed", not_positive(mouse.max_speed)),
+            ("curve", not_positive(mouse.curve)),
+            (
+                "scroll-interval",
+                mouse.scroll_interval.is_some_and(|interval| interval == 0),
+            ),
+        ];
+
+        for (name, _) in settings.iter().filter(|(_, bad)| *bad) {
+            problems.push(Problem::BadMouseSetting(String::from(*name)));
+        }
+    }
+
+    if let Some(debounce) = &raw.debounce {
+        for name in debounce.key.iter().flat_map(|key| key.keys.iter()) {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownDebounceKey(name.clone()));
+            }
+        }
+    }
+
+    for (feature, raw_feature) in raw_features(raw) {
+        for name in raw_feature.toggle.iter() {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownToggleKey(feature, name.clone()));
+            }
+        }
+
+        if feature == Feature::StickyKeys && raw_feature.delay.is_some() {
+            problems.push(Problem::StickyKeysDelay);
+        }
+    }
+
+    if let Some(auto_shift) = &raw.auto_shift {
+        for name in auto_shift.keys.iter().flatten() {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownAutoShiftKey(name.clone()));
+            }
+        }
+    }
+
+    for name in raw.disable.iter() {
+        if key_names::parse(name).is_none() {
+            problems.push(Problem::UnknownDisabledKey(name.clone()));
+        }
+    }
+
+    for names in raw.swap.iter() {
+        for name in names.iter() {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownSwappedKey(name.clone()));
+            }
+        }
+
+        if names.len() != 2 {
+            problems.push(Problem::BadSwap(names.clone()));
+        }
+    }
+
+    // A key can only be disabled or swapped once, or all but the first are
+    // ignored.
+    let mut shortcut_keys = HashSet::new();
+    for key in raw
+        .disable
+        .iter()
+        .chain(raw.swap.iter().flatten())
+        .filter_map(|name| key_names::parse(name))
+    {
+        if !shortcut_keys.insert(key) {
+            problems.push(Problem::RepeatedShortcutKey(key));
+        }
+    }
+
+    if let Some(repeat) = &raw.repeat {
+        for name in repeat.exclude.iter() {
+            if key_names::parse(name).is_none() {
+                problems.push(Problem::UnknownRepeatKey(name.clone()));
+            }
+        }
+
+        let settings = [("delay", repeat.delay), ("period", repeat.period)];
+        for (name, _) in settings.iter().filter(|(_, value)| *value == Some(0)) {
+            problems.push(Problem::BadRepeatSetting(String::from(*name)));
+        }
+    }
+
+    if let Some(RawText {
+        compose: Some(name),
+        ..
+    }) = &raw.text
+    {
+        if key_names::parse(name).is_none() {
+            problems.push(Problem::UnknownComposeKey(name.clone()));
+        }
+    }
+
+    if let Some(privileges) = &raw.privileges {
+        if let Some(user) = &privileges.user {
+            if User::find(user).is_none() {
+                problems.push(Problem::UnknownPrivilegesUser(user.clone()));
+            }
+        }
+
+        if let Some(group) = &privileges.group {
+            if privileges::find_group(group).is_none() {
+                problems.push(Problem::UnknownPrivilegesGroup(group.clone()));
+            }
+        }
+    }
+
+    let mut cycles = HashSet::new();
+
+    for layer in raw.layer.iter() {
+        find_cycles(raw, layer, &mut vec![], &mut cycles);
+    }
+
+    let mut cycles = cycles.into_iter().collect::<Vec<_>>();
+    cycles.sort();
+    problems.extend(cycles.into_iter().map(Problem::LayerCycle));
+
+    problems
+}
+
+fn check_mapping(
+    raw: &RawConfig,
+    layout: &Layout,
+    origin: Origin,
+    mapping: &RawMapping,
+    problems: &mut Vec<Problem>,
+) {
+    if mapping.keys.is_empty() {
+        problems.push(Problem::EmptyMapping(origin.clone()));
+    }
+
+    let one_shot_key = match &mapping.oneshot {
+        Some(RawModifier::Key(name)) => Some(name),
+        _ => None,
+    };
+    let (taps, tap_dance_hold) = match &mapping.tap_dance {
+        Some(tap_dance) => (
+            &tap_dance.taps[..],
+            match &tap_dance.hold {
+                Some(RawModifier::Key(name)) => Some(name),
+                _ => None,
+            },
+        ),
+        None => (&[][..], None),
+    };
+
+    for name in mapping
+        .keys
+        .iter()
+        .chain(mapping.output.iter().filter(|output| !is_noop(output)))
+        .chain(one_shot_key)
+        .chain(taps)
+        .chain(tap_dance_hold)
+        .chain(mapping.any.iter().flatten())
+        .chain(&mapping.unless)
+    {
+        if key_names::parse(name).is_none() {
+            problems.push(Problem::UnknownKey(Some(origin.clone()), name.clone()));
+        }
+    }
+
+    if mapping.any.iter().any(|group| group.is_empty()) {
+        problems.push(Problem::EmptyAnyGroup(origin.clone()));
+    }
+
+    match mapping.action() {
+        Some(RawAction::Output(_))
+        | Some(RawAction::Exec(RawExec::Shell(_)))
+        | Some(RawAction::OneShot(RawModifier::Key(_)))
+        | Some(RawAction::CapsWord) => (),
+        Some(RawAction::OneShot(RawModifier::Layer { layer })) => {
+            if find_layer(raw, layer).is_none() {
+                problems.push(Problem::UnknownModifierLayer(origin, layer.clone()));
+            }
+        }
+        Some(RawAction::Mouse(name)) => {
+            if MouseAction::parse(name).is_none() {
+                problems.push(Problem::UnknownMouseAction(origin, String::from(name)));
+            }
+        }
+        Some(RawAction::Text(text)) => {
+            let method = parse_text_method(raw);
+            if let Err(c) = text::keys(text, &method, layout) {
+                problems.push(Problem::UntypeableText(origin, c, method));
+            }
+        }
+        Some(RawAction::TapDance(tap_dance)) => {
+            if tap_dance.taps.is_empty() {
+                problems.push(Problem::EmptyTapDance(origin.clone()));
+            }
+
+            if let Some(RawModifier::Layer { layer }) = &tap_dance.hold {
+                if find_layer(raw, layer).is_none() {
+                    problems.push(Problem::UnknownModifierLayer(origin, layer.clone()));
+                }
+            }
+        }
+        Some(RawAction::Exec(RawExec::Command(command))) => {
+            if command.command.is_empty() {
+                problems.push(Problem::EmptyCommand(origin.clone()));
+            }
+
+            if let Some(user) = &command.user {
+                let uid = |name: &str| User::find(name).map(|user| user.uid);
+                let target = raw
+                    .privileges
+                    .as_ref()
+                    .and_then(|privileges| privileges.user.as_ref());
+
+                if uid(user).is_none() {
+                    problems.push(Problem::UnknownUser(origin, user.clone()));
+                } else if let Some(target) = target.filter(|target| uid(target) != uid(user)) {
+                    problems.push(Problem::CommandUserAfterDrop(
+                        origin,
+                        user.clone(),
+                        target.clone(),
+                    ));
+                }
+            }
+        }
+        None => problems.push(Problem::BadAction(origin)),
+    }
+}
+
+fn find_cycles<'a>(
+    raw: &'a RawConfig,
+    layer: &'a RawLayer,
+    path: &mut Vec<&'a str>,
+    cycles: &mut HashSet<Vec<String>>,
+) {
+    if let Some(start) = path.iter().position(|name| *name == layer.name) {
+        let mut cycle = path[start..]
+            .iter()
+            .map(|name| String::from(*name))
+            .collect::<Vec<_>>();
+
+        // Rotate so the same cycle is only reported once, no matter which
+        // layer it was found from.
+        let first = (0..cycle.len()).min_by_key(|i| &cycle[*i]).unwrap_or(0);
+        cycle.rotate_left(first);
+        cycles.insert(cycle);
+        return;
+    }
+
+    path.push(&layer.name);
+    for included in layer
+        .include
+        .iter()
+        .filter_map(|name| find_layer(raw, name))
+    {
+        find_cycles(raw, included, path, cycles);
+    }
+    path.pop();
+}
+
+fn find_layer<'a>(raw: &'a RawConfig, name: &str) -> Option<&'a RawLayer> {
+    raw.layer.iter().find(|layer| layer.name == name)
+}
+
+/// Turns layers into plain mappings by prefixing each of their mappings with
+/// the keys that activate the layer. Anything with a bad name is skipped, as
+/// `check_names` will already have reported it.
+fn flatten(raw: &RawConfig) -> Vec<Mapping> {
+    let mut mappings = vec![];
+
+    for (index, mapping) in raw.mapping.iter().enumerate() {
+        let origin = Origin {
+            layer: None,
+            index: index + 1,
+            kind: Kind::Mapping,
+        };
+        mappings.extend(flatten_mapping(&[], mapping, origin, None));
+    }
+
+    for layer in raw.layer.iter() {
+        let hold = match parse_keys(&layer.hold) {
+            Some(hold) => hold,
+            None => continue,
+        };
+
+        let mut members = vec![];
+        collect_layers(raw, layer, &mut members);
+
+        for member in members {
+            for (index, mapping) in member.mapping.iter().enumerate() {
+                let origin = Origin {
+                    layer: Some(member.name.clone()),
+                    index: index + 1,
+                    kind: Kind::Mapping,
+                };
+                mappings.extend(flatten_mapping(&hold, mapping, origin, Some(&layer.name)));
+            }
+        }
+    }
+
+    mappings
+}
+
+/// Flattens each layer that a one-shot mapping or tap-hold key enables, like
+/// `flatten` does, but without the keys that hold it, since it's enabled by
+/// the mapper.
+fn flatten_modifier_layers(raw: &RawConfig) -> Vec<Mapping> {
+    let all_mappings = raw
+        .mapping
+        .iter()
+        .chain(raw.layer.iter().flat_map(|layer| layer.mapping.iter()));
+    let modifiers = all_mappings
+        .flat_map(|mapping| {
+            let tap_dance_hold = mapping
+                .tap_dance
+                .as_ref()
+                .and_then(|tap_dance| tap_dance.hold.as_ref());
+            mapping.oneshot.as_ref().into_iter().chain(tap_dance_hold)
+        })
+        .chain(raw.tap_hold.iter().map(|tap_hold| &tap_hold.hold));
+    let mut names = modifiers
+        .filter_map(|modifier| match modifier {
+            RawModifier::Layer { layer } => Some(layer),
+            RawModifier::Key(_) => None,
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    let mut mappings = vec![];
+
+    for layer in names.into_iter().filter_map(|name| find_layer(raw, name)) {
+        let mut members = vec![];
+        collect_layers(raw, layer, &mut members);
+
+        for member in members {
+            for (index, mapping) in member.mapping.iter().enumerate() {
+                let origin = Origin {
+                    layer: Some(member.name.clone()),
+                    index: index + 1,
+                    kind: Kind::Mapping,
+                };
+                mappings.extend(flatten_mapping(&[], mapping, origin, Some(&layer.name)));
+            }
+        }
+    }
+
+    mappings
+}
+
+/// Turns the `disable` and `swap` lists into mappings, skipping keys with bad
+/// names, swaps without two keys, and all but the first use of each key.
+fn parse_shortcuts(raw: &RawConfig) -> Vec<Mapping> {
+    let shortcut = |key: Key, action, kind, index: usize| Mapping {
+        keys: vec![key],
+        action,
+        origin: Origin {
+            layer: None,
+            index: index + 1,
+            kind,
+        },
+        layer: None,
+        conditions: Conditions::default(),
+        modifiers: HeldModifiers::default(),
+    };
+    let mut shortcuts = vec![];
+    let mut used = HashSet::new();
+
+    for (index, name) in raw.disable.iter().enumerate() {
+        if let Some(key) = key_names::parse(name).filter(|key| used.insert(*key)) {
+            shortcuts.push(shortcut(key, Action::Disable, Kind::Disable, index));
+        }
+    }
+
+    for (index, names) in raw.swap.iter().enumerate() {
+        if let Some([a, b]) = parse_keys(names).as_deref() {
+            if a != b && !used.contains(a) && !used.contains(b) {
+                used.extend([*a, *b]);
+                shortcuts.push(shortcut(*a, Action::Swap(*b), Kind::Swap, index));
+                shortcuts.push(shortcut(*b, Action::Swap(*a), Kind::Swap, index));
+            }
+        }
+    }
+
+    shortcuts
+}
+
+/// Skips tap-hold keys with bad names, and all but the first of each key.
+fn parse_tap_holds(raw: &RawConfig) -> Vec<(Key, TapHold)> {
+    let mut tap_holds: Vec<(Key, TapHold)> = vec![];
+
+    for tap_hold in raw.tap_hold.iter() {
+        let key = match key_names::parse(&tap_hold.key) {
+            Some(key) => key,
+            None => continue,
+        };
+        let tap = match &tap_hold.tap {
+            Some(name) => key_names::parse(name),
+            None => Some(key),
+        };
+        let hold = parse_modifier(&tap_hold.hold);
+
+        if let (Some(tap), Some(hold), false) =
+            (tap, hold, tap_holds.iter().any(|(first, _)| *first == key))
+        {
+            tap_holds.push((
+                key,
+                TapHold {
+                    tap,
+                    hold,
+                    timeout: tap_hold
+                        .timeout
+                        .map(Duration::from_millis)
+                        .unwrap_or(DEFAULT_TAP_HOLD_TIMEOUT),
+                    quick_tap: Duration::from_millis(tap_hold.quick_tap),
+                    decision: match tap_hold.decision {
+                        RawDecision::Timeout => Decision::Timeout,
+                        RawDecision::PermissiveHold => Decision::PermissiveHold,
+                        RawDecision::HoldOnOtherKeyPress => Decision::HoldOnOtherKeyPress,
+                    },
+                },
+            ));
+        }
+    }
+
+    tap_holds
+}
+
+/// Settings that aren't given, or are invalid, keep their defaults.
+fn parse_mouse(raw: &RawConfig) -> MouseSettings {
+    let mut settings = MouseSettings::default();
+    let mouse = match &raw.mouse {
+        Some(mouse) => mouse,
+        None => return settings,
+    };
+
+    let positive = |value: &f64| *value > 0.0;
+
+    if let Some(interval) = mouse.interval.filter(|interval| *interval > 0) {
+        settings.interval = Duration::from_millis(interval);
+    }
+    if let Some(speed) = mouse.speed.filter(positive) {
+        settings.speed = speed;
+    }
+    if let Some(max_speed) = mouse.max_speed.filter(positive) {
+        settings.max_speed = max_speed;
+    }
+    if let Some(acceleration) = mouse.acceleration {
+        settings.acceleration = Duration::from_millis(acceleration);
+    }
+    if let Some(curve) = mouse.curve.filter(positive) {
+        settings.curve = curve;
+    }
+    if let Some(interval) = mouse.scroll_interval.filter(|interval| *interval > 0) {
+        settings.scroll_interval = Duration::from_millis(interval);
+    }
+
+    settings
+}
+
+fn parse_auto_shift(raw: &RawConfig, layout: &Layout) -> Option<AutoShift> {
+    let auto_shift = raw.auto_shift.as_ref()?;
+
+    Some(AutoShift {
+        keys: match &auto_shift.keys {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| key_names::parse(name))
+                .collect(),
+            None => layout.alphanumeric_keys().into_iter().collect(),
+        },
+        timeout: auto_shift
+            .timeout
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_AUTO_SHIFT_TIMEOUT),
+        repeat: auto_shift.repeat,
+    })
+}
+
+/// Settings that aren't given, or are 0, keep their defaults.
+fn parse_repeat(raw: &RawConfig) -> Option<RepeatConfig> {
+    let repeat = raw.repeat.as_ref()?;
+    let defaults = Repeat::default();
+    let millis = |value: Option<u64>, default| {
+        value
+            .filter(|value| *value > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(default)
+    };
+
+    // Keys that hold layers would otherwise repeat while they're held.
+    let hold = raw.layer.iter().flat_map(|layer| layer.hold.iter());
+    let mut exclude = repeat
+        .exclude
+        .iter()
+        .chain(hold)
+        .filter_map(|name| key_names::parse(name))
+        .collect::<Vec<_>>();
+    exclude.sort();
+    exclude.dedup();
+
+    Some(RepeatConfig {
+        repeat: Repeat {
+            delay: millis(repeat.delay, defaults.delay),
+            period: millis(repeat.period, defaults.period),
+        },
+        software: repeat.software,
+        exclude,
+    })
+}
+
+/// Later key sections win over earlier ones.
+fn parse_debounce(raw: &RawConfig) -> (Option<Debounce>, HashMap<Key, Option<Debounce>>) {
+    let debounce = match &raw.debounce {
+        Some(debounce) => debounce,
+        None => return (None, HashMap::new()),
+    };
+
+    let parse = |algorithm, time: u64| {
+        Some(Debounce {
+            algorithm: match algorithm {
+                RawAlgorithm::Eager => debounce::Algorithm::Eager,
+                RawAlgorithm::Defer => debounce::Algorithm::Defer,
+            },
+            time: Duration::from_millis(time),
+        })
+        .filter(|_| time > 0)
+    };
+    let time = debounce.time.unwrap_or(DEFAULT_DEBOUNCE_TIME);
+
+    let mut keys = HashMap::new();
+    for key in debounce.key.iter() {
+        let settings = parse(
+            key.algorithm.unwrap_or(debounce.algorithm),
+            key.time.unwrap_or(time),
+        );
+
+        for key in key.keys.iter().filter_map(|name| key_names::parse(name)) {
+            keys.insert(key, settings);
+        }
+    }
+
+    (parse(debounce.algorithm, time), keys)
+}
+
+/// Features without a `delay` keep the default one.
+fn parse_accessibility(raw: &RawConfig) -> accessibility::Settings {
+    let mut settings = accessibility::Settings::default();
+
+    for (feature, raw_feature) in raw_features(raw) {
+        if raw_feature.enabled {
+            settings.enabled.push(feature);
+        }
+
+        let delay = raw_feature.delay.map(Duration::from_millis);
+        match feature {
+            Feature::SlowKeys => settings.slow_keys = delay.unwrap_or(settings.slow_keys),
+            Feature::BounceKeys => settings.bounce_keys = delay.unwrap_or(settings.bounce_keys),
+            Feature::StickyKeys => (),
+        }
+
+        let keys = raw_feature
+            .toggle
+            .iter()
+            .filter_map(|name| key_names::parse(name))
+            .collect::<Vec<_>>();
+        if !keys.is_empty() {
+            settings.toggles.push((feature, keys));
+        }
+    }
+
+    settings
+}
+
+/// The accessibility features that have a section.
+fn raw_features(raw: &RawConfig) -> Vec<(Feature, &RawFeature)> {
+    let accessibility = match &raw.accessibility {
+        Some(accessibility) => accessibility,
+        None => return vec![],
+    };
+
+    [
+        (Feature::SlowKeys, &accessibility.slow_keys),
+        (Feature::BounceKeys, &accessibility.bounce_keys),
+        (Feature::StickyKeys, &accessibility.sticky_keys),
+    ]
+    .iter()
+    .filter_map(|(feature, raw_feature)| Some((*feature, raw_feature.as_ref()?)))
+    .collect()
+}
+
+/// Fails with the name of a layout that can't be loaded.
+/// Finds a layout by name and variant, like `Layout::load`.
+type LoadLayout = dyn Fn(&str, Option<&str>) -> Option<Layout>;
+
+fn parse_layout(raw: &RawConfig, load: &LoadLayout) -> Result<Layout, String> {
+    let layout = match &raw.layout {
+        Some(layout) => layout,
+        None => return Ok(Layout::default()),
+    };
+
+    load(&layout.name, layout.variant.as_deref()).ok_or_else(|| match &layout.variant {
+        Some(variant) => format!("{}({})", layout.name, variant),
+        None => layout.name.clone(),
+    })
+}
+
+/// Replaces characters like `'j'` with the keys that type them on the layout,
+/// so the rest of the config only deals with keys. Characters that need Shift
+/// add it to the keys of a mapping, and are typed as text when they're the
+/// output. Anything else is left for `check_names` to report.
+fn resolve_characters(raw: &mut RawConfig, layout: &Layout) {
+    let mappings = raw
+        .mapping
+        .iter_mut()
+        .chain(
+            raw.layer
+                .iter_mut()
+                .flat_map(|layer| layer.mapping.iter_mut()),
+        )
+        .chain(
+            raw.leader
+                .iter_mut()
+                .flat_map(|leader| leader.sequence.iter_mut()),
+        );
+
+    for mapping in mappings {
+        resolve_keys(&mut mapping.keys, layout);
+        resolve_keys(&mut mapping.unless, layout);
+        resolve_output(&mut mapping.output, &mut mapping.text, layout);
+
+        for group in mapping.any.iter_mut() {
+            resolve_keys(group, layout);
+        }
+
+        if let Some(RawModifier::Key(name)) = &mut mapping.oneshot {
+            resolve_key(name, layout);
+        }
+
+        if let Some(tap_dance) = &mut mapping.tap_dance {
+            for name in tap_dance.taps.iter_mut() {
+                resolve_key(name, layout);
+            }
+            if let Some(RawModifier::Key(name)) = &mut tap_dance.hold {
+                resolve_key(name, layout);
+            }
+        }
+    }
+
+    resolve_keys(&mut raw.disable, layout);
+    for names in raw.swap.iter_mut() {
+        resolve_keys(names, layout);
+    }
+
+    for combo in raw.combo.iter_mut() {
+        resolve_keys(&mut combo.keys, layout);
+        resolve_output(&mut combo.output, &mut combo.text, layout);
+    }
+
+    for name in raw.layer.iter_mut().flat_map(|layer| layer.hold.iter_mut()) {
+        resolve_key(name, layout);
+    }
+
+    for tap_hold in raw.tap_hold.iter_mut() {
+        resolve_key(&mut tap_hold.key, layout);
+
+        for name in tap_hold.tap.iter_mut() {
+            resolve_key(name, layout);
+        }
+        if let RawModifier::Key(name) = &mut tap_hold.hold {
+            resolve_key(name, layout);
+        }
+    }
+
+    if let Some(auto_shift) = &mut raw.auto_shift {
+        for name in auto_shift.keys.iter_mut().flatten() {
+            resolve_key(name, layout);
+        }
+    }
+
+    if let Some(leader) = &mut raw.leader {
+        resolve_key(&mut leader.key, layout);
+
+        for name in leader.cancel.iter_mut() {
+            resolve_key(name, layout);
+        }
+    }
+}
+
+/// The character in a name like `'j'`.
+fn character(name: &str) -> Option<char> {
+    let mut chars = name.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+/// Only characters without Shift can be resolved to a single key.
+fn resolve_key(name: &mut String, layout: &Layout) {
+    if let Some((key, false)) = character(name).and_then(|c| layout.find(c)) {
+        *name = key_names::name(key);
+    }
+}
+
+fn resolve_keys(names: &mut Vec<String>, layout: &Layout) {
+    let mut resolved: Vec<String> = vec![];
+
+    for name in names.drain(..) {
+        match character(&name).and_then(|c| layout.find(c)) {
+            Some((key, shift)) => {
+                if shift
+                    && !resolved
+                        .iter()
+                        .any(|name| key_names::parse(name) == Some(Key::LeftShift))
+                {
+                    resolved.push(key_names::name(Key::LeftShift));
+                }
+
+                resolved.push(key_names::name(key));
+            }
+            None => resolved.push(name),
+        }
+    }
+
+    *names = resolved;
+}
+
+fn resolve_output(output: &mut Option<String>, text: &mut Option<String>, layout: &Layout) {
+    let c = match output.as_deref().and_then(character) {
+        Some(c) => c,
+        None => return,
+    };
+
+    match layout.find(c) {
+        Some((key, false)) => *output = Some(key_names::name(key)),
+        // There's no single key to hold for these.
+        Some((_, true)) if text.is_none() => {
+            *output = None;
+            *text = Some(c.to_string());
+        }
+        _ => (),
+    }
+}
+
+fn parse_text_method(raw: &RawConfig) -> TextMethod {
+    let text = match &raw.text {
+        Some(text) => text,
+        None => return TextMethod::default(),
+    };
+
+    match text.method {
+        RawTextMethod::Hex => TextMethod::Hex,
+        RawTextMethod::Compose => TextMethod::Compose(
+            text.compose
+                .as_ref()
+                .and_then(|name| key_names::parse(name))
+                .unwrap_or(Key::Compose),
+        ),
+        RawTextMethod::Layout => TextMethod::Layout,
+    }
+}
+
+fn parse_sequences(raw: &RawConfig) -> Vec<Mapping> {
+    let sequences = raw.leader.iter().flat_map(|leader| leader.sequence.iter());
+
+    sequences
+        .enumerate()
+        .filter_map(|(index, mapping)| {
+            let origin = Origin {
+                layer: None,
+                index: index + 1,
+                kind: Kind::Sequence,
+            };
+            flatten_mapping(&[], mapping, origin, None)
+        })
+        .collect()
+}
+
+/// Combos can't be in layers, so they only need the same parsing as a
+/// mapping.
+fn parse_combos(raw: &RawConfig) -> Vec<Combo> {
+    raw.combo
+        .iter()
+        .enumerate()
+        .filter_map(|(index, combo)| {
+            let origin = Origin {
+                layer: None,
+                index: index + 1,
+                kind: Kind::Combo,
+            };
+            let mapping = flatten_mapping(&[], &combo.mapping(), origin, None)?;
+
+            Some(Combo {
+                keys: mapping.keys,
+                action: mapping.action,
+                timeout: combo
+                    .timeout
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_COMBO_TIMEOUT),
+                origin: mapping.origin,
+            })
+        })
+        .collect()
+}
+
+impl RawMapping {
+    /// The action of the mapping, or `None` when it has none, or more than
+    /// one.
+    fn action(&self) -> Option<RawAction<'_>> {
+        let actions = [
+            self.output.as_deref().map(RawAction::Output),
+            self.exec.as_ref().map(RawAction::Exec),
+            self.oneshot.as_ref().map(RawAction::OneShot),
+            self.mouse.as_deref().map(RawAction::Mouse),
+            self.text.as_deref().map(RawAction::Text),
+            self.tap_dance.as_ref().map(RawAction::TapDance),
+            Some(RawAction::CapsWord).filter(|_| self.caps_word),
+        ];
+        let mut set = actions.iter().flatten();
+
+        match (set.next(), set.next()) {
+            (Some(action), None) => Some(*action),
+            _ => None,
+        }
+    }
+}
+
+impl RawCombo {
+    fn mapping(&self) -> RawMapping {
+        RawMapping {
+            keys: self.keys.clone(),
+            output: self.output.clone(),
+            exec: self.exec.clone(),
+            oneshot: None,
+            mouse: None,
+            text: self.text.clone(),
+            tap_dance: None,
+            caps_word: self.caps_word,
+            any: vec![],
+            unless: vec![],
+            modifiers: RawHeldModifiers::Keep,
+        }
+    }
+}
+
+fn flatten_mapping(
+    hold: &[Key],
+    mapping: &RawMapping,
+    origin: Origin,
+    layer: Option<&String>,
+) -> Option<Mapping> {
+    let keys = parse_keys(&mapping.keys).filter(|keys| !keys.is_empty())?;
+    let action = match mapping.action()? {
+        RawAction::Output(output) if is_noop(output) => Action::Noop,
+        RawAction::Output(output) => Action::Key(key_names::parse(output)?),
+        RawAction::Exec(exec) => Action::Exec(parse_command(exec)?),
+        RawAction::OneShot(modifier) => Action::OneShot(parse_modifier(modifier)?),
+        RawAction::Mouse(name) => Action::Mouse(MouseAction::parse(name)?),
+        RawAction::Text(text) => Action::Text(String::from(text)),
+        RawAction::TapDance(tap_dance) => Action::TapDance(TapDance {
+            taps: parse_keys(&tap_dance.taps).filter(|taps| !taps.is_empty())?,
+            hold: match &tap_dance.hold {
+                Some(modifier) => Some(parse_modifier(modifier)?),
+                None => None,
+            },
+            term: tap_dance
+                .term
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_TAP_DANCE_TERM),
+        }),
+        RawAction::CapsWord => Action::CapsWord,
+    };
+
+    let any = mapping
+        .any
+        .iter()
+        .map(|group| parse_keys(group).filter(|keys| !keys.is_empty()))
+        .collect::<Option<_>>()?;
+
+    Some(Mapping {
+        keys: hold.iter().chain(keys.iter()).copied().collect(),
+        action,
+        origin,
+        layer: layer.cloned(),
+        conditions: Conditions {
+            any,
+            unless: parse_keys(&mapping.unless)?,
+        },
+        modifiers: match mapping.modifiers {
+            RawHeldModifiers::Keep => HeldModifiers::Keep,
+            RawHeldModifiers::Strip => HeldModifiers::Strip,
+        },
+    })
+}
+
+/// Whether an output is `noop`, which sends nothing instead of a key.
+fn is_noop(output: &str) -> bool {
+    output.trim().eq_ignore_ascii_case("noop")
+}
+
+fn parse_modifier(modifier: &RawModifier) -> Option<Modifier> {
+    match modifier {
+        RawModifier::Key(name) => key_names::parse(name).map(Modifier::Key),
+        RawModifier::Layer { layer } => Some(Modifier::Layer(layer.clone())),
+    }
+}
+
+fn parse_command(exec: &RawExec) -> Option<exec::Command> {
+    match exec {
+        RawExec::Shell(command_line) => Some(exec::Command {
+            args: vec![String::from("sh"), String::from("-c"), command_line.clone()],
+            env: vec![],
+            user: None,
+        }),
+        RawExec::Command(command) if !command.command.is_empty() => Some(exec::Command {
+            args: command.command.clone(),
+            env: command
+                .env
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            user: match &command.user {
+                Some(name) => Some(User::find(name)?),
+                None => None,
+            },
+        }),
+        RawExec::Command(_) => None,
+    }
+}
+
+/// Collects a layer and everything it includes, skipping layers that were
+/// already visited so that cycles terminate.
+fn collect_layers<'a>(raw: &'a RawConfig, layer: &'a RawLayer, members: &mut Vec<&'a RawLayer>) {
+    if members.iter().any(|member| member.name == layer.name) {
+        return;
+    }
+
+    members.push(layer);
+    for included in layer
+        .include
+        .iter()
+        .filter_map(|name| find_layer(raw, name))
+    {
+        collect_layers(raw, included, members);
+    }
+}
+
+fn parse_keys(names: &[String]) -> Option<Vec<Key>> {
+    names.iter().map(|name| key_names::parse(name)).collect()
+}
+
+/// Mappings in the order `KeyMapper` tries them, given the layers they're
+/// added with.
+fn in_mapper_order(mappings: &[(Mapping, Option<String>)]) -> Vec<&(Mapping, Option<String>)> {
+    let mut sorted = mappings.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(mapping, layer)| {
+        key_mapper::mapping_order(
+            mapping.keys.len().saturating_sub(1),
+            &mapping.conditions,
+            layer.as_deref(),
+            &mapping.action,
+        )
+    });
+    sorted
+}
+
+/// Reports mappings that can never be reached, because a mapping tried before
+/// them always matches first. A mapping that needs a layer only shadows
+/// mappings that need the same one.
+fn check_order(mappings: &[(Mapping, Option<String>)]) -> Vec<Problem> {
+    let sorted = in_mapper_order(mappings);
+    let mut problems = vec![];
+
+    for (position, (later, later_layer)) in sorted.iter().enumerate() {
+        let earlier = sorted[..position]
+            .iter()
+            .find(|(earlier, layer)| {
+                (layer.is_none() || layer == later_layer) && earlier.shadows(later)
+            })
+            .map(|(earlier, _)| earlier);
+
+        if let Some(earlier) = earlier {
+            let same_prefixes = later.keys.iter().all(|key| earlier.keys.contains(key))
+                && earlier.conditions == later.conditions;
+            let (first, second) = (earlier.clone(), later.clone());
+
+            problems.push(if !same_prefixes {
+                Problem::ShadowedMapping(second, first)
+            } else if earlier.action == later.action && earlier.modifiers == later.modifiers {
+                Problem::DuplicateMapping(first, second)
+            } else {
+                Problem::ConflictingMapping(first, second)
+            });
+        }
+    }
+
+    problems
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// A key name that isn't recognized, and the mapping it was found in. Keys
+    /// used to hold a layer have no mapping.
+    UnknownKey(Option<Origin>, String),
+    /// A layer that includes another layer that doesn't exist.
+    UnknownLayer(String, String),
+    DuplicateLayer(String),
+    /// A device section, by position, that uses a layer that doesn't exist.
+    UnknownDeviceLayer(usize, String),
+    /// A context rule, by position, that enables a layer that doesn't exist.
+    UnknownContextLayer(usize, String),
+    /// A device section, by position, that has nothing to match devices with.
+    EmptySelector(usize),
+    LayerCycle(Vec<String>),
+    EmptyMapping(Origin),
+    /// A combo without at least two different keys.
+    BadCombo(Origin),
+    /// The leader or cancel key isn't a key.
+    UnknownLeaderKey(String),
+    DuplicateSequence(Origin, Origin),
+    /// A mapping without exactly one of `output`, `exec`, `oneshot`, `mouse`,
+    /// `text`, `tap-dance` or `caps-word`, or a sequence with one that holds a
+    /// key.
+    BadAction(Origin),
+    /// A tap-dance without any `taps`.
+    EmptyTapDance(Origin),
+    /// A mapping with an `any` group that has no keys, so it never applies.
+    EmptyAnyGroup(Origin),
+    /// A sequence with `any` or `unless` keys, which only work for mappings.
+    SequenceConditions(Origin),
+    /// A one-shot mapping or tap-hold key for a layer that doesn't exist.
+    UnknownModifierLayer(Origin, String),
+    UnknownMouseAction(Origin, String),
+    /// A setting in the mouse section that's out of range, by name.
+    BadMouseSetting(String),
+    /// A character that the text method can't type.
+    UntypeableText(Origin, char, TextMethod),
+    UnknownComposeKey(String),
+    UnknownRepeatKey(String),
+    UnknownAutoShiftKey(String),
+    UnknownDisabledKey(String),
+    UnknownSwappedKey(String),
+    /// A swap without exactly two keys.
+    BadSwap(Vec<String>),
+    /// A key that's disabled or swapped more than once.
+    RepeatedShortcutKey(Key),
+    UnknownDebounceKey(String),
+    UnknownToggleKey(Feature, String),
+    /// A `delay` in the sticky keys section, which doesn't use one.
+    StickyKeysDelay,
+    /// A time in the repeat section that's 0, by name.
+    BadRepeatSetting(String),
+    /// An XKB layout that isn't installed or bundled, with its variant.
+    UnknownKeyboardLayout(String),
+    /// A tap-hold key that's also used by an earlier one.
+    DuplicateTapHold(Origin, Origin),
+    EmptyCommand(Origin),
+    /// A user to run a command as that doesn't exist.
+    UnknownUser(Origin, String),
+    /// A user to run a command as that isn't the one keyswitch drops to, which
+    /// it can't switch to anymore.
+    CommandUserAfterDrop(Origin, String, String),
+    /// A user to drop privileges to that doesn't exist.
+    UnknownPrivilegesUser(String),
+    UnknownPrivilegesGroup(String),
+    /// Two mappings with the same keys and output.
+    DuplicateMapping(Mapping, Mapping),
+    /// Two mappings with the same keys, but different outputs.
+    ConflictingMapping(Mapping, Mapping),
+    /// A mapping that never matches, because the second mapping always matches
+    /// first.
+    ShadowedMapping(Mapping, Mapping),
+}
+
+impl Problem {
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            Problem::DuplicateMapping(_, _) | Problem::ShadowedMapping(_, _)
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UnknownKey(Some(origin), name) => {
+                write!(f, "Unknown key `{}` in {}.", name, origin)
+            }
+            Problem::UnknownKey(None, name) => write!(f, "Unknown key `{}` in layer hold.", name),
+            Problem::UnknownLayer(layer, name) => {
+                write!(f, "Layer `{}` includes unknown layer `{}`.", layer, name)
+            }
+            Problem::DuplicateLayer(name) => write!(f, "Layer `{}` is defined twice.", name),
+            Problem::UnknownDeviceLayer(index, name) => {
+                write!(f, "Device {} uses unknown layer `{}`.", index, name)
+            }
+            Problem::UnknownContextLayer(index, name) => {
+                write!(
+                    f,
+                    "Context rule {} enables unknown layer `{}`.",
+                    index, name
+                )
+            }
+            Problem::EmptySelector(index) => {
+                write!(
+                    f,
+                    "Device {} needs a `name` or `path` to match with.",
+                    index
+                )
+            }
+            Problem::LayerCycle(names) => {
+                write!(f, "Layers include each other in a cycle: ")?;
+                for name in names.iter() {
+                    write!(f, "{} -> ", name)?;
+                }
+                write!(f, "{}.", names.first().map(String::as_str).unwrap_or(""))
+            }
+            Problem::EmptyMapping(origin) => write!(f, "No keys given for {}.", origin),
+            Problem::UnknownLeaderKey(name) => write!(f, "Unknown key `{}` in leader.", name),
+            Problem::DuplicateSequence(first, second) => {
+                write!(f, "{} has the same keys as {}.", second, first)
+            }
+            Problem::UnknownMouseAction(origin, name) => {
+                write!(f, "Unknown mouse action `{}` in {}.", name, origin)
+            }
+            Problem::BadMouseSetting(name) => {
+                write!(f, "Expected `{}` of mouse to be more than 0.", name)
+            }
+            Problem::UntypeableText(origin, c, method) => write!(
+                f,
+                "Can't type `{}` in {} with the {} method.",
+                c, origin, method
+            ),
+            Problem::UnknownComposeKey(name) => {
+                write!(f, "Unknown key `{}` in text compose.", name)
+            }
+            Problem::UnknownKeyboardLayout(name) => write!(f, "Unknown layout `{}`.", name),
+            Problem::UnknownRepeatKey(name) => write!(f, "Unknown key `{}` in repeat.", name),
+            Problem::UnknownAutoShiftKey(name) => {
+                write!(f, "Unknown key `{}` in auto-shift.", name)
+            }
+            Problem::UnknownDisabledKey(name) => write!(f, "Unknown key `{}` in disable.", name),
+            Problem::UnknownSwappedKey(name) => write!(f, "Unknown key `{}` in swap.", name),
+            Problem::BadSwap(names) => write!(
+                f,
+                "Expected two keys to swap, found `{}`.",
+                names.join(", ")
+            ),
+            Problem::RepeatedShortcutKey(key) => write!(
+                f,
+                "`{}` is disabled or swapped more than once.",
+                key_names::name(*key)
+            ),
+            Problem::UnknownDebounceKey(name) => {
+                write!(f, "Unknown key `{}` in debounce.", name)
+            }
+            Problem::UnknownToggleKey(feature, name) => {
+                write!(f, "Unknown key `{}` in toggle of {}.", name, feature)
+            }
+            Problem::StickyKeysDelay => write!(f, "Expected no `delay` for sticky-keys."),
+            Problem::BadRepeatSetting(name) => {
+                write!(f, "Expected `{}` of repeat to be more than 0.", name)
+            }
+            Problem::DuplicateTapHold(first, second) => {
+                write!(f, "{} has the same key as {}.", second, first)
+            }
+            Problem::BadCombo(origin) => {
+                write!(f, "Expected at least two different keys for {}.", origin)
+            }
+            Problem::BadAction(origin) if origin.kind == Kind::Mapping => write!(
+                f,
+                "Expected one of `output`, `exec`, `oneshot`, `mouse`, `text`, `tap-dance` or \
+                 `caps-word` for {}.",
+                origin
+            ),
+            Problem::BadAction(origin) => write!(
+                f,
+                "Expected one of `output`, `exec`, `text` or `caps-word` for {}.",
+                origin
+            ),
+            Problem::EmptyTapDance(origin) => {
+                write!(f, "Expected at least one key in `taps` for {}.", origin)
+            }
+            Problem::EmptyAnyGroup(origin) => write!(
+                f,
+                "Expected at least one key in each `any` group for {}.",
+                origin
+            ),
+            Problem::SequenceConditions(origin) => {
+                write!(f, "Expected no `any` or `unless` keys for {}.", origin)
+            }
+            Problem::UnknownModifierLayer(origin, name) => {
+                write!(f, "Unknown layer `{}` in {}.", name, origin)
+            }
+            Problem::EmptyCommand(origin) => write!(f, "No command given for {}.", origin),
+            Problem::UnknownUser(origin, name) => {
+                write!(f, "Unknown user `{}` in {}.", name, origin)
+            }
+            Problem::CommandUserAfterDrop(origin, name, target) => write!(
+                f,
+                "{} can't run as `{}`, since keyswitch runs as `{}` after opening devices.",
+                origin, name, target
+            ),
+            Problem::UnknownPrivilegesUser(name) => {
+                write!(f, "Unknown user `{}` in privileges.", name)
+            }
+            Problem::UnknownPrivilegesGroup(name) => {
+                write!(f, "Unknown group `{}` in privileges.", name)
+            }
+            Problem::DuplicateMapping(first, second) => {
+                write!(f, "{} is a duplicate of {}.", second, first)
+            }
+            Problem::ConflictingMapping(first, second) => {
+                write!(f, "{} conflicts with {}.", second, first)
+            }
+            Problem::ShadowedMapping(shadowed, by) => write!(
+                f,
+                "{} is unreachable, because {} always matches first.",
+                shadowed, by
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Mapping => "mapping",
+            Kind::Combo => "combo",
+            Kind::Sequence => "sequence",
+            Kind::TapHold => "tap-hold",
+            Kind::Disable => "disable",
+            Kind::Swap => "swap",
+        };
+
+        match &self.layer {
+            Some(layer) => write!(f, "{} {} of layer `{}`", kind, self.index, layer),
+            None => write!(f, "{} {}", kind, self.index),
+        }
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}", self.origin, key_names::join(&self.keys))?;
+
+        for group in self.conditions.any.iter() {
+            let names = group.iter().map(|key| key_names::name(*key));
+            write!(f, " with {}", names.collect::<Vec<_>>().join(" or "))?;
+        }
+
+        if !self.conditions.unless.is_empty() {
+            let names = self
+                .conditions
+                .unless
+                .iter()
+                .map(|key| key_names::name(*key));
+            write!(f, " unless {}", names.collect::<Vec<_>>().join(" or "))?;
+        }
+
+        write!(f, " -> {})", self.action)
+    }
+}
+
+pub struct Explanation {
+    /// The mapping that fires once all keys are pressed, if any.
+    pub mapping: Option<Mapping>,
+    pub steps: Vec<Step>,
+}
+
+/// An input event, and the events sent to the virtual device and commands run
+/// in response.
+pub struct Step {
+    pub input: (Key, KeyState),
+    pub output: Vec<(Key, KeyState)>,
+    pub commands: Vec<exec::Command>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.mapping {
+            Some(mapping) => writeln!(f, "Matches {}.\n", mapping)?,
+            None => writeln!(f, "No mapping matches.\n")?,
+        }
+
+        let inputs = self
+            .steps
+            .iter()
+            .map(|step| describe_event(step.input))
+            .collect::<Vec<_>>();
+        let input_width = inputs.iter().map(String::len).max().unwrap_or(0);
+
+        for (input, step) in inputs.iter().zip(self.steps.iter()) {
+            let output = step
+                .output
+                .iter()
+                .map(|event| describe_event(*event))
+                .chain(
+                    step.commands
+                        .iter()
+                        .map(|command| format!("runs `{}`", command)),
+                )
+                .collect::<Vec<_>>();
+
+            writeln!(
+                f,
+                "{input:input_width$}  ->  {output}",
+                input = input,
+                input_width = input_width,
+                output = if output.is_empty() {
+                    String::from("(nothing)")
+                } else {
+                    output.join(", ")
+                }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn describe_event((key, state): (Key, KeyState)) -> String {
+    let state = match state {
+        KeyState::PRESSED => "pressed",
+        KeyState::RELEASED => "released",
+        KeyState::AUTOREPEAT => "repeated",
+        _ => "unknown",
+    };
+
+    format!("{} {}", key_names::name(key), state)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    ParseError(toml::de::Error),
+    InvalidConfigError(Vec<Problem>),
+}
+
+impl From<io::Error> for Error {
+    fn from(io_error: io::Error) -> Self {
+        Error::IOError(io_error)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(toml_error: toml::de::Error) -> Self {
+        Error::ParseError(toml_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_mapper_order, Config, Kind, Origin, Problem, RepeatConfig};
+    use crate::{
+        accessibility::{self, Feature},
+        control::Context,
+        exec::{self, User},
+        key_mapper::{
+            Action, AutoShift, CapsWord, Decision, HeldModifiers, Modifier, MouseSettings, Repeat,
+            TapDance, TapHold,
+        },
+        layout::Layout,
+        text::TextMethod,
+    };
+    use input_linux::{EventTime, Key, KeyEvent, KeyState};
+    use std::{collections::HashSet, time::Duration};
+
+    fn problems(source: &str) -> Vec<Problem> {
+        Config::parse(source).unwrap().1
+    }
+
+    #[test]
+    fn the_default_config_has_no_problems() {
+        assert_eq!(problems(super::DEFAULT_CONFIG), vec![]);
+    }
+
+    #[test]
+    fn it_flattens_layers_into_prefixes() {
+        let (config, _) = Config::parse(
+            r#"
+            [[layer]]
+            name = "nav"
+            hold = ["caps"]
+            include = ["extra"]
+
+            [[layer.mapping]]
+            keys = ["j"]
+            output = "Down"
+
+            [[layer]]
+            name = "extra"
+
+            [[layer.mapping]]
+            keys = ["k"]
+            output = "Up"
+            "#,
+        )
+        .unwrap();
+
+        let keys = config
+            .mappings_for(None)
+            .iter()
+            .map(|mapping| (mapping.keys.clone(), mapping.action.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            keys,
+            vec![
+                (vec![Key::CapsLock, Key::J], Action::Key(Key::Down)),
+                (vec![Key::CapsLock, Key::K], Action::Key(Key::Up)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_uses_the_layers_of_each_device() {
+        let (config, found) = Config::parse(
+            r#"
+            [[layer]]
+            name = "caps-ctrl"
+
+            [[layer.mapping]]
+            keys = ["caps"]
+            output = "LeftCtrl"
+
+            [[layer]]
+            name = "arrows"
+            hold = ["caps"]
+
+            [[layer.mapping]]
+            keys = ["j"]
+            output = "Down"
+
+            [[device]]
+            name = "laptop"
+            layers = ["caps-ctrl", "arrows"]
+
+            [[device]]
+            name = "split"
+            layers = ["arrows"]
+            output = "split"
+            "#,
+        )
+        .unwrap();
+
+        let outputs = |device_config| {
+            config
+                .mappings_for(device_config)
+                .iter()
+                .map(|mapping| mapping.action.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(found, vec![]);
+        assert_eq!(outputs(None), vec![Action::Key(Key::Down)]);
+        assert_eq!(
+            outputs(Some(&config.devices()[0])),
+            vec![Action::Key(Key::LeftCtrl), Action::Key(Key::Down)]
+        );
+        assert_eq!(
+            outputs(Some(&config.devices()[1])),
+            vec![Action::Key(Key::Down)]
+        );
+        assert_eq!(config.devices()[1].output, Some(String::from("split")));
+    }
+
+    #[test]
+    fn it_maps_mouse_buttons_like_keys() {
+        let (config, found) = Config::parse(
+            r#"
+            [[mapping]]
+            keys = ["ButtonSide"]
+            output = "Back"
+
+            [[layer]]
+            name = "mouse-arrows"
+            hold = ["ButtonExtra"]
+
+            [[layer.mapping]]
+            keys = ["ButtonLeft"]
+            output = "Left"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(found, vec![]);
+
+        let explanation = config
+            .explain(&[Key::ButtonExtra, Key::ButtonLeft], None)
+            .unwrap();
+        assert_eq!(explanation.mapping.unwrap().action, Action::Key(Key::Left));
+        assert_eq!(
+            config.explain(&[Key::ButtonSide], None).unwrap().steps[0].output,
+            vec![(Key::Back, KeyState::PRESSED)]
+        );
+    }
+
+    #[test]
+    fn it_orders_mappings_like_the_mapper() {
+        let (config, found) = Config::parse(
+            r#"
+            [[mapping]]
+            keys = ["caps", "j"]
+            output = "Down"
+
+            [[layer]]
+            name = "browser"
+
+            [[layer.mapping]]
+            keys = ["caps", "j"]
+            output = "PageDown"
+
+            [[context]]
+            match = { app = "firefox" }
+            layers = ["browser"]
+            "#,
+        )
+        .unwrap();
+
+        // The layer's mapping is added last, but tried first.
+        assert_eq!(found, vec![]);
+        let mappings = config.mapper_mappings(None);
+        let sorted = in_mapper_order(&mappings);
+        assert_eq!(sorted[0].0.action, Action::Key(Key::PageDown));
+        assert_eq!(sorted[0].1, Some(String::from("browser")));
+
+        let mut mapper = config.key_mapper(None).unwrap();
+        mapper.set_enabled_layers(HashSet::from([String::from("browser")]));
+        let outputs = [Key::CapsLock, Key::J].map(|key| {
+            mapper.handle_key_event(&KeyEvent::new(EventTime::new(0, 0), key, KeyState::PRESSED))
+        });
+        assert_eq!(outputs[1].last(), Some(&(Key::PageDown, KeyState::PRESSED)));
+
+        // Without the layer, the other mapping matches.
+        let explanation = config.explain(&[Key::CapsLock, Key::J], None).unwrap();
+        assert_eq!(explanation.mapping.unwrap().action, Action::Key(Key::Down));
+    }
+
+    #[test]
+    fn it_enables_layers_matching_the_context() {
+        let (config, found) = Config::parse(
+            r#"
+            [[layer]]
+            name = "arrows"
+            hold = ["caps"]
+
+            [[layer.mapping]]
+            keys = ["j"]
+            output = "Down"
+
+            [[layer]]
+            name = "browser"
+            hold = ["caps"]
+
+            [[layer.mapping]]
+            keys = ["j"]
+            output = "PageDown"
+
+            [[context]]
+            match = { app = ["firefox", "chromium"] }
+            layers = ["browser"]
+            "#,
+        )
+        .unwrap();
+
+        let context = |app: &str| Context::from([(String::from("app"), String::from(app))]);
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.context_layers(&context("chromium")),
+            HashSet::from([String::from("browser")])
+        );
+        assert_eq!(config.context_layers(&context("foot")), HashSet::new());
+        assert_eq!(
+            config
+                .mappings_for(None)
+                .iter()
+                .map(|mapping| mapping.action.clone())
+                .collect::<Vec<_>>(),
+            vec![Action::Key(Key::Down)]
+        );
+    }
+
+    #[test]
+    fn it_parses_exec_mappings() {
+        let (config, found) = Config::parse(
+            r#"
+            [[mapping]]
+            keys = ["caps", "enter"]
+            exec = "foot"
+
+            [[mapping]]
+            keys = ["caps", "volumeup"]
+            exec = { command = ["pactl", "set-sink-volume", "@DEFAULT_SINK@", "+5%"], env = { PULSE_SERVER = "unix:/run/user/1000/pulse/native" } }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.mappings()[0].action,
+            Action::Exec(exec::Command {
+                args: vec![String::from("sh"), String::from("-c"), String::from("foot")],
+                env: vec![],
+                user: None,
+            })
+        );
+        match &config.mappings()[1].action {
+            Action::Exec(command) => {
+                assert_eq!(command.args[0], "pactl");
+                assert_eq!(command.env[0].0, "PULSE_SERVER");
+            }
+            action => panic!("Expected a command, found {:?}", action),
+        }
+    }
+
+    #[test]
+    fn it_disables_and_swaps_keys() {
+        let (config, found) = Config::parse(
+            r#"
+            disable = ["Insert"]
+            swap = [["Esc", "caps"]]
+
+            [[mapping]]
+            keys = ["caps", "q"]
+            output = "noop"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(config.mappings()[0].action, Action::Noop);
+
+        let mut mapper = config.key_mapper(None).unwrap();
+        let mut press = |key| {
+            mapper.handle_key_event(&KeyEvent::new(EventTime::new(0, 0), key, KeyState::PRESSED))
+        };
+        assert_eq!(press(Key::Insert), vec![]);
+        assert_eq!(press(Key::Esc), vec![(Key::CapsLock, KeyState::PRESSED)]);
+        assert_eq!(press(Key::CapsLock), vec![(Key::Esc, KeyState::PRESSED)]);
+        assert_eq!(press(Key::Q), vec![]);
+
+        let found = problems(
+            r#"
+            disable = ["Insert", "Nope"]
+            swap = [["Esc"], ["LeftAlt", "Insert"]]
+            "#,
+        );
+        assert_eq!(
+            found,
+            vec![
+                Problem::UnknownDisabledKey(String::from("Nope")),
+                Problem::BadSwap(vec![String::from("Esc")]),
+                Problem::RepeatedShortcutKey(Key::Insert),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_mappings_that_disabled_and_swapped_keys_shadow() {
+        let (config, found) = Config::parse(
+            r#"
+            disable = ["Insert"]
+            swap = [["Esc", "caps"]]
+
+            [[mapping]]
+            keys = ["Insert"]
+            output = "Home"
+
+            [[layer]]
+            name = "browser"
+
+            [[layer.mapping]]
+            keys = ["LeftCtrl", "Esc"]
+            output = "Stop"
+
+            [[context]]
+            match = { app = "firefox" }
+            layers = ["browser"]
+            "#,
+        )
+        .unwrap();
+
+        // Both are tried before any other mapping, even one in a layer.
+        match found.as_slice() {
+            [Problem::ConflictingMapping(first, second), Problem::ShadowedMapping(shadowed, by)] => {
+                assert_eq!(first.action, Action::Disable);
+                assert_eq!(first.origin.kind, Kind::Disable);
+                assert_eq!(second.action, Action::Key(Key::Home));
+                assert_eq!(shadowed.action, Action::Key(Key::Stop));
+                assert_eq!(by.action, Action::Swap(Key::CapsLock));
+                assert_eq!(by.to_string(), "swap 1 (Esc -> swap with CapsLock)");
+            }
+            _ => panic!("Expected shadowed mappings, found {:?}", found),
+        }
+
+        let explanation = config.explain(&[Key::Insert], None).unwrap();
+        assert_eq!(explanation.mapping.unwrap().action, Action::Disable);
+        assert_eq!(explanation.steps[0].output, vec![]);
+    }
+
+    #[test]
+    fn it_disables_tap_hold_keys() {
+        let (config, found) = Config::parse(
+            r#"
+            disable = ["CapsLock"]
+
+            [[tap-hold]]
+            key = "CapsLock"
+            tap = "Esc"
+            hold = "LeftCtrl"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        let explanation = config.explain(&[Key::CapsLock], None).unwrap();
+        assert_eq!(explanation.mapping.unwrap().action, Action::Disable);
+        assert!(explanation.steps.iter().all(|step| step.output.is_empty()));
+    }
+
+    #[test]
+    fn it_parses_held_modifier_policies() {
+        let (config, found) = Config::parse(
+            r#"
+            [[mapping]]
+            keys = ["caps", "h"]
+            output = "left"
+
+            [[mapping]]
+            keys = ["caps", "l"]
+            output = "right"
+            modifiers = "strip"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(config.mappings()[0].modifiers, HeldModifiers::Keep);
+        assert_eq!(config.mappings()[1].modifiers, HeldModifiers::Strip);
+    }
+
+    #[test]
+    fn it_reports_bad_exec_mappings() {
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["caps", "enter"]
+            exec = { command = [], user = "no-such-user-here" }
+
+            [[mapping]]
+            keys = ["caps", "space"]
+            output = "Enter"
+            exec = "foot"
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [
+                Problem::EmptyCommand(_),
+                Problem::UnknownUser(_, _),
+                Problem::BadAction(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn it_reports_commands_for_users_other_than_the_one_dropped_to() {
+        // The user running the tests, and any other account.
+        let me = User::find_by_uid(unsafe { libc::getuid() }).unwrap();
+        let other = (0..65536)
+            .filter(|uid| *uid != me.uid)
+            .find_map(User::find_by_uid)
+            .expect("another account");
+
+        let found = problems(&format!(
+            r#"
+            [privileges]
+            user = "{me}"
+
+            [[mapping]]
+            keys = ["caps", "enter"]
+            exec = {{ command = ["foot"], user = "{me}" }}
+
+            [[mapping]]
+            keys = ["caps", "space"]
+            exec = {{ command = ["foot"], user = "{other}" }}
+            "#,
+            me = me.name,
+            other = other.name
+        ));
+
+        assert_eq!(
+            found,
+            vec![Problem::CommandUserAfterDrop(
+                Origin {
+                    layer: None,
+                    index: 2,
+                    kind: Kind::Mapping,
+                },
+                other.name,
+                me.name
+            )]
+        );
+    }
+
+    #[test]
+    fn it_parses_combos() {
+        let (config, found) = Config::parse(
+            r#"
+            [[combo]]
+            keys = ["j", "k"]
+            output = "Esc"
+
+            [[combo]]
+            keys = ["d", "f"]
+            exec = "foot"
+            timeout = 50
+
+            [[combo]]
+            keys = ["j", "J"]
+            output = "Enter"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.combos().len(), 3);
+        assert_eq!(config.combos()[0].keys, vec![Key::J, Key::K]);
+        assert_eq!(config.combos()[0].action, Action::Key(Key::Esc));
+        assert_eq!(config.combos()[0].timeout, Duration::from_millis(30));
+        assert_eq!(config.combos()[1].timeout, Duration::from_millis(50));
+        assert_eq!(
+            found,
+            vec![Problem::BadCombo(Origin {
+                layer: None,
+                index: 3,
+                kind: Kind::Combo,
+            })]
+        );
+        assert_eq!(
+            found[0].to_string(),
+            "Expected at least two different keys for combo 3."
+        );
+    }
+
+    #[test]
+    fn it_parses_leader_sequences() {
+        let (config, found) = Config::parse(
+            r#"
+            [leader]
+            key = "ralt"
+            cancel = "esc"
+            unmatched = "replay"
+
+            [[leader.sequence]]
+            keys = ["g", "h"]
+            exec = "foot"
+
+            [[leader.sequence]]
+            keys = ["g", "H"]
+            output = "F5"
+            "#,
+        )
+        .unwrap();
+
+        let leader = config.leader().unwrap();
+        assert_eq!(leader.key, Key::RightAlt);
+        assert_eq!(leader.cancel, Some(Key::Esc));
+        assert_eq!(leader.timeout, Duration::from_millis(1000));
+        assert!(leader.replay_unmatched);
+        assert_eq!(config.sequences()[1].keys, vec![Key::G, Key::H]);
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec!["sequence 2 has the same keys as sequence 1."]
+        );
+
+        assert_eq!(
+            problems("[leader]\nkey = \"lader\""),
+            vec![Problem::UnknownLeaderKey(String::from("lader"))]
+        );
+    }
+
+    #[test]
+    fn it_parses_one_shot_mappings() {
+        let (config, found) = Config::parse(
+            r#"
+            [[mapping]]
+            keys = ["LeftShift"]
+            oneshot = "LeftShift"
+
+            [[mapping]]
+            keys = ["RightAlt"]
+            oneshot = { layer = "nav" }
+
+            [[layer]]
+            name = "nav"
+            hold = ["CapsLock"]
+
+            [[layer.mapping]]
+            keys = ["H"]
+            output = "Left"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.mappings()[1].action,
+            Action::OneShot(Modifier::Layer(String::from("nav")))
+        );
+        assert_eq!(config.modifier_mappings[0].keys, vec![Key::H]);
+
+        let mut mapper = config.key_mapper(None).unwrap();
+        for state in [KeyState::PRESSED, KeyState::RELEASED].iter() {
+            mapper.handle_key_event(&KeyEvent::new(EventTime::new(0, 0), Key::RightAlt, *state));
+        }
+        assert_eq!(
+            mapper.handle_key_event(&KeyEvent::new(
+                EventTime::new(0, 0),
+                Key::H,
+                KeyState::PRESSED
+            )),
+            vec![(Key::Left, KeyState::PRESSED)]
+        );
+
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["RightAlt"]
+            oneshot = { layer = "nope" }
+
+            [leader]
+            key = "ralt"
+
+            [[leader.sequence]]
+            keys = ["s"]
+            oneshot = "LeftShift"
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Unknown layer `nope` in mapping 1.",
+                "Expected one of `output`, `exec`, `text` or `caps-word` for sequence 1."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_tap_dance_mappings() {
+        let (config, found) = Config::parse(
+            r#"
+            [[mapping]]
+            keys = ["LeftShift"]
+            tap-dance = { taps = ["LeftShift", "CapsLock"], hold = "LeftShift" }
+
+            [[mapping]]
+            keys = ["D"]
+            tap-dance = { taps = ["D"], hold = { layer = "nav" }, term = 150 }
+
+            [[layer]]
+            name = "nav"
+
+            [[layer.mapping]]
+            keys = ["H"]
+            output = "Left"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.mappings()[0].action,
+            Action::TapDance(TapDance {
+                taps: vec![Key::LeftShift, Key::CapsLock],
+                hold: Some(Modifier::Key(Key::LeftShift)),
+                term: Duration::from_millis(200),
+            })
+        );
+        assert_eq!(config.modifier_mappings[0].keys, vec![Key::H]);
+
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["D"]
+            tap-dance = { taps = [], hold = { layer = "nope" } }
+
+            [[mapping]]
+            keys = ["F"]
+            output = "G"
+            tap-dance = { taps = ["Gee"] }
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Expected at least one key in `taps` for mapping 1.",
+                "Unknown layer `nope` in mapping 1.",
+                "Unknown key `Gee` in mapping 2.",
+                "Expected one of `output`, `exec`, `oneshot`, `mouse`, `text`, `tap-dance` or \
+                 `caps-word` for mapping 2."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_caps_word() {
+        let (config, found) = Config::parse(
+            r#"
+            [caps-word]
+            timeout = 3000
+            underscore = true
+
+            [[combo]]
+            keys = ["LeftShift", "RightShift"]
+            caps-word = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(config.combos()[0].action, Action::CapsWord);
+        assert_eq!(
+            config.caps_word,
+            CapsWord {
+                timeout: Some(Duration::from_millis(3000)),
+                underscore: true,
+            }
+        );
+
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["CapsLock"]
+            output = "Escape"
+            caps-word = true
+            "#,
+        );
+        assert_eq!(
+            found,
+            vec![Problem::BadAction(Origin {
+                layer: None,
+                index: 1,
+                kind: Kind::Mapping,
+            })]
+        );
+    }
+
+    #[test]
+    fn it_parses_auto_shift_settings() {
+        let (config, found) = Config::parse(
+            r#"
+            [auto-shift]
+            keys = ["A", "'z'"]
+            repeat = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.auto_shift,
+            Some(AutoShift {
+                keys: [Key::A, Key::Z].iter().copied().collect(),
+                timeout: Duration::from_millis(175),
+                repeat: true,
+            })
+        );
+
+        let (config, _) = Config::parse("[auto-shift]\ntimeout = 200").unwrap();
+        let keys = config.auto_shift.map(|auto_shift| auto_shift.keys);
+        assert!(keys.is_some_and(|keys| keys.contains(&Key::Num1) && !keys.contains(&Key::Minus)));
+
+        assert_eq!(
+            problems("[auto-shift]\nkeys = [\"Ay\"]")
+                .iter()
+                .map(Problem::to_string)
+                .collect::<Vec<_>>(),
+            vec!["Unknown key `Ay` in auto-shift."]
+        );
+    }
+
+    #[test]
+    fn it_parses_tap_hold_keys() {
+        let (config, found) = Config::parse(
+            r#"
+            [[tap-hold]]
+            key = "a"
+            hold = "LeftMeta"
+            decision = "permissive-hold"
+
+            [[tap-hold]]
+            key = "f"
+            tap = "Esc"
+            hold = { layer = "nav" }
+            timeout = 150
+            quick-tap = 120
+            decision = "hold-on-other-key-press"
+
+            [[layer]]
+            name = "nav"
+
+            [[layer.mapping]]
+            keys = ["H"]
+            output = "Left"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.tap_holds(),
+            &[
+                (
+                    Key::A,
+                    TapHold {
+                        tap: Key::A,
+                        hold: Modifier::Key(Key::LeftMeta),
+                        timeout: Duration::from_millis(200),
+                        quick_tap: Duration::ZERO,
+                        decision: Decision::PermissiveHold,
+                    }
+                ),
+                (
+                    Key::F,
+                    TapHold {
+                        tap: Key::Esc,
+                        hold: Modifier::Layer(String::from("nav")),
+                        timeout: Duration::from_millis(150),
+                        quick_tap: Duration::from_millis(120),
+                        decision: Decision::HoldOnOtherKeyPress,
+                    }
+                )
+            ]
+        );
+        assert_eq!(config.modifier_mappings[0].keys, vec![Key::H]);
+
+        let found = problems(
+            r#"
+            [[tap-hold]]
+            key = "a"
+            hold = "LeftMta"
+
+            [[tap-hold]]
+            key = "a"
+            hold = { layer = "nope" }
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Unknown key `LeftMta` in tap-hold 1.",
+                "Unknown layer `nope` in tap-hold 2.",
+                "tap-hold 2 has the same key as tap-hold 1."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_text_mappings() {
+        let (config, found) = Config::parse(
+            r#"
+            [text]
+            method = "compose"
+            compose = "RightAlt"
+
+            [[mapping]]
+            keys = ["CapsLock", "E"]
+            text = "é"
+
+            [[combo]]
+            keys = ["A", "S"]
+            text = "→"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(config.text_method(), &TextMethod::Compose(Key::RightAlt));
+        assert_eq!(config.mappings()[0].action, Action::Text(String::from("é")));
+        assert_eq!(config.combos()[0].action.to_string(), "text `→`");
+
+        let found = problems(
+            r#"
+            [text]
+            method = "compose"
+            compose = "Kompose"
+
+            [[mapping]]
+            keys = ["L"]
+            text = "λ"
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Can't type `λ` in mapping 1 with the compose method.",
+                "Unknown key `Kompose` in text compose."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_resolves_characters_on_the_layout() {
+        // The bundled layouts, so the test doesn't depend on the XKB files.
+        let parse = |source| Config::parse_with(source, &Layout::bundled).unwrap();
+        let (config, found) = parse(
+            r#"
+            [layout]
+            name = "us"
+            variant = "dvorak"
+
+            [[mapping]]
+            keys = ["CapsLock", "'j'"]
+            output = "'/'"
+
+            [[mapping]]
+            keys = ["'?'"]
+            output = "'!'"
+            "#,
+        );
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config
+                .mappings()
+                .iter()
+                .map(|mapping| (mapping.keys.clone(), mapping.action.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (vec![Key::CapsLock, Key::C], Action::Key(Key::LeftBrace)),
+                (
+                    vec![Key::LeftShift, Key::LeftBrace],
+                    Action::Text(String::from("!"))
+                )
+            ]
+        );
+
+        let (_, found) = parse(
+            r#"
+            [layout]
+            name = "us"
+            variant = "qwertz"
+
+            [[mapping]]
+            keys = ["'λ'"]
+            output = "Esc"
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Unknown layout `us(qwertz)`.",
+                "Unknown key `'λ'` in mapping 1."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_repeat_settings() {
+        let (config, found) = Config::parse(
+            r#"
+            [repeat]
+            delay = 200
+            software = true
+            exclude = ["Backspace"]
+
+            [[layer]]
+            name = "arrows"
+            hold = ["CapsLock"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.repeat(),
+            Some(&RepeatConfig {
+                repeat: Repeat {
+                    delay: Duration::from_millis(200),
+                    period: Duration::from_millis(33),
+                },
+                software: true,
+                exclude: vec![Key::Backspace, Key::CapsLock],
+            })
+        );
+        assert_eq!(Config::default().repeat(), None);
+
+        let found = problems(
+            r#"
+            [repeat]
+            period = 0
+            exclude = ["Bakspace"]
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Unknown key `Bakspace` in repeat.",
+                "Expected `period` of repeat to be more than 0."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_debounce_settings() {
+        let (config, found) = Config::parse(
+            r#"
+            [debounce]
+            algorithm = "defer"
+            time = 10
+
+            [[debounce.key]]
+            keys = ["J"]
+            algorithm = "eager"
+
+            [[debounce.key]]
+            keys = ["Space"]
+            time = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(found, vec![]);
+
+        let mut debouncer = config.debouncer();
+        let event = |key| KeyEvent::new(EventTime::new(0, 0), key, KeyState::PRESSED);
+
+        assert_eq!(debouncer.filter(&event(Key::K)), vec![]);
+        assert_eq!(debouncer.next_deadline(), Some(Duration::from_millis(10)));
+        assert_eq!(debouncer.filter(&event(Key::J)), vec![event(Key::J)]);
+        assert_eq!(
+            debouncer.filter(&event(Key::Space)),
+            vec![event(Key::Space)]
+        );
+
+        let found = problems(
+            r#"
+            [[debounce.key]]
+            keys = ["Jay"]
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec!["Unknown key `Jay` in debounce."]
+        );
+    }
+
+    #[test]
+    fn it_parses_accessibility_settings() {
+        let (config, found) = Config::parse(
+            r#"
+            [accessibility.slow-keys]
+            enabled = false
+            delay = 500
+            toggle = ["LeftShift", "RightShift"]
+
+            [accessibility.sticky-keys]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config.accessibility,
+            accessibility::Settings {
+                slow_keys: Duration::from_millis(500),
+                enabled: vec![Feature::StickyKeys],
+                toggles: vec![(Feature::SlowKeys, vec![Key::LeftShift, Key::RightShift])],
+                ..accessibility::Settings::default()
+            }
+        );
+
+        let found = problems(
+            r#"
+            [accessibility.sticky-keys]
+            delay = 100
+            toggle = ["Shfit"]
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Unknown key `Shfit` in toggle of sticky-keys.",
+                "Expected no `delay` for sticky-keys."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_mouse_keys() {
+        let (config, found) = Config::parse(
+            r#"
+            [mouse]
+            interval = 10
+            max-speed = 30
+            curve = 1
+
+            [[layer]]
+            name = "mouse"
+            hold = ["CapsLock"]
+
+            [[layer.mapping]]
+            keys = ["H"]
+            mouse = "left"
+
+            [[layer.mapping]]
+            keys = ["J"]
+            mouse = "Scroll-Down"
+
+            [[layer.mapping]]
+            keys = ["Space"]
+            output = "ButtonLeft"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(found, vec![]);
+        assert_eq!(
+            config
+                .mappings()
+                .iter()
+                .map(|mapping| mapping.action.to_string())
+                .collect::<Vec<_>>(),
+            vec!["mouse left", "mouse scroll-down", "ButtonLeft"]
+        );
+        assert_eq!(
+            config.mouse(),
+            &MouseSettings {
+                interval: Duration::from_millis(10),
+                max_speed: 30.0,
+                curve: 1.0,
+                ..MouseSettings::default()
+            }
+        );
+
+        let found = problems(
+            r#"
+            [mouse]
+            interval = 0
+            speed = -1
+
+            [[mapping]]
+            keys = ["H"]
+            mouse = "sideways"
+            "#,
+        );
+        assert_eq!(
+            found.iter().map(Problem::to_string).collect::<Vec<_>>(),
+            vec![
+                "Unknown mouse action `sideways` in mapping 1.",
+                "Expected `interval` of mouse to be more than 0.",
+                "Expected `speed` of mouse to be more than 0."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_unknown_privileges() {
+        let found = problems(
+            r#"
+            [privileges]
+            user = "no-such-user-here"
+            group = "no-such-group-here"
+            "#,
+        );
+
+        assert_eq!(
+            found,
+            vec![
+                Problem::UnknownPrivilegesUser(String::from("no-such-user-here")),
+                Problem::UnknownPrivilegesGroup(String::from("no-such-group-here")),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_unknown_keys() {
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["Capslok"]
+            output = "LeftCtrl"
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [Problem::UnknownKey(Some(_), name)] if name == "Capslok"
+        ));
+    }
+
+    #[test]
+    fn it_reports_duplicate_and_conflicting_mappings() {
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["CapsLock", "LeftShift", "J"]
+            output = "Down"
+
+            [[mapping]]
+            keys = ["LeftShift", "CapsLock", "J"]
+            output = "Down"
+
+            [[mapping]]
+            keys = ["CapsLock", "LeftShift", "J"]
+            output = "Up"
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [
+                Problem::DuplicateMapping(_, _),
+                Problem::ConflictingMapping(_, _)
+            ]
+        ));
+        assert!(!found[0].is_error());
+        assert!(found[1].is_error());
+    }
+
+    #[test]
+    fn it_reports_mappings_shadowed_by_shorter_prefixes() {
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["CapsLock", "LeftShift", "J"]
+            output = "PageDown"
+
+            [[mapping]]
+            keys = ["CapsLock", "J"]
+            output = "Down"
+            "#,
+        );
+
+        match found.as_slice() {
+            [Problem::ShadowedMapping(shadowed, by)] => {
+                assert_eq!(shadowed.action, Action::Key(Key::PageDown));
+                assert_eq!(by.action, Action::Key(Key::Down));
+            }
+            _ => panic!("Expected a shadowed mapping, found {:?}", found),
+        }
+    }
+
+    #[test]
+    fn it_only_reports_mappings_that_conditions_always_shadow() {
+        let found = problems(
+            r#"
+            [[mapping]]
+            keys = ["CapsLock", "J"]
+            output = "Down"
+            unless = ["LeftShift", "RightShift"]
+
+            [[mapping]]
+            keys = ["CapsLock", "J"]
+            output = "PageDown"
+            any = [["LeftShift", "RightShift"]]
+
+            [[mapping]]
+            keys = ["H"]
+            output = "Left"
+            any = [["LeftAlt", "RightAlt"]]
+
+            [[mapping]]
+            keys = ["LeftAlt", "H"]
+            output = "Home"
+
+            [[mapping]]
+            keys = ["J"]
+            output = "Down"
+            any = [[]]
+            "#,
+        );
+
+        match found.as_slice() {
+            [Problem::EmptyAnyGroup(_), Problem::ShadowedMapping(shadowed, by)] => {
+                assert_eq!(shadowed.action, Action::Key(Key::Home));
+                assert_eq!(by.action, Action::Key(Key::Left));
+                assert_eq!(
+                    by.to_string(),
+                    "mapping 3 (H with LeftAlt or RightAlt -> Left)"
+                );
+            }
+            _ => panic!("Expected a shadowed mapping, found {:?}", found),
+        }
+    }
+
+    #[test]
+    fn it_reports_layer_cycles_once() {
+        let found = problems(
+            r#"
+            [[layer]]
+            name = "b"
+            hold = ["caps"]
+            include = ["a"]
+
+            [[layer]]
+            name = "a"
+            include = ["b"]
+            "#,
+        );
+
+        assert_eq!(
+            found,
+            vec![Problem::LayerCycle(vec![
+                String::from("a"),
+                String::from("b")
+            ])]
+        );
+    }
+
+    #[test]
+    fn it_explains_which_mapping_fires() {
+        let explanation = Config::default()
+            .explain(&[Key::CapsLock, Key::J], None)
+            .unwrap();
+
+        assert_eq!(explanation.mapping.unwrap().action, Action::Key(Key::Down));
+        assert_eq!(
+            explanation.steps[1].output,
+            vec![
+                (Key::LeftCtrl, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED)
+            ]
+        );
+    }
+}
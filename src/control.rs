@@ -0,0 +1,352 @@
+use crate::accessibility::Feature;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    os::unix::{
+        self,
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Values describing what the user is doing, like the focused application.
+/// keyswitch can't find these out itself, so they're pushed by other programs.
+pub type Context = HashMap<String, String>;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Replaces the current context, like `set-context app=firefox`.
+    SetContext(Context),
+    /// Turns an accessibility feature on or off, like `enable slow-keys`, or
+    /// flips it when there's no value, like `toggle slow-keys`.
+    SetFeature(Feature, Option<bool>),
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+
+    match words.next() {
+        Some("set-context") => words
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+                _ => Err(format!("Expected `key=value`, found `{}`.", pair)),
+            })
+            .collect::<Result<Context, _>>()
+            .map(Command::SetContext),
+        Some(command @ ("enable" | "disable" | "toggle")) => {
+            let enabled = match command {
+                "enable" => Some(true),
+                "disable" => Some(false),
+                _ => None,
+            };
+
+            match (words.next(), words.next()) {
+                (Some(name), None) => Feature::parse(name)
+                    .map(|feature| Command::SetFeature(feature, enabled))
+                    .ok_or_else(|| format!("Unknown feature `{}`.", name)),
+                _ => Err(format!("Expected one feature for `{}`.", command)),
+            }
+        }
+        Some(command) => Err(format!("Unknown command `{}`.", command)),
+        None => Err(String::from("Empty command.")),
+    }
+}
+
+/// The longest line a client can send, so one that never ends can't use up
+/// memory. Clients that go over are dropped.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// A Unix socket that accepts one command per line. Each command is answered
+/// with `ok`, or `error: ` followed by a reason.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    stream: UnixStream,
+
+    /// Bytes read so far that don't make up a whole line yet.
+    buffer: Vec<u8>,
+}
+
+impl ControlSocket {
+    /// Fails if another keyswitch is already listening on `path`.
+    pub fn bind(path: &Path, mode: u32) -> io::Result<Self> {
+        // A socket left behind by an earlier run is replaced, but one that's
+        // still listening belongs to another instance, and files that aren't
+        // sockets are left alone.
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse));
+            }
+
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::from(io::ErrorKind::AddrInUse)),
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                    fs::remove_file(path)?;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        // The socket is created in a directory only keyswitch can enter, and
+        // only moved into place once it has `mode`, so it's never open to
+        // anyone in the meantime.
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let created = dir.join("socket");
+        let listener = UnixListener::bind(&created).and_then(|listener| {
+            fs::set_permissions(&created, fs::Permissions::from_mode(mode))?;
+            fs::rename(&created, path)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_dir_all(&dir);
+
+        let listener = listener?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path: PathBuf::from(path),
+            clients: Vec::new(),
+        })
+    }
+
+    /// Hands the socket file to another user, so it can still be removed
+    /// after keyswitch drops privileges.
+    pub fn set_owner(&self, uid: u32, gid: u32) -> io::Result<()> {
+        unix::fs::chown(&self.path, Some(uid), Some(gid))
+    }
+
+    /// File descriptors to wait on before calling `receive`.
+    pub fn fds(&self) -> Vec<RawFd> {
+        Some(self.listener.as_raw_fd())
+            .into_iter()
+            .chain(self.clients.iter().map(|client| client.stream.as_raw_fd()))
+            .collect()
+    }
+
+    /// Accepts waiting clients, and returns any commands they've sent. Never
+    /// blocks, and clients that hang up or misbehave are dropped.
+    pub fn receive(&mut self) -> Vec<Command> {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.clients.push(Client {
+                    stream,
+                    buffer: Vec::new(),
+                });
+            }
+        }
+
+        let mut commands = Vec::new();
+        self.clients
+            .retain_mut(|client| client.receive(&mut commands).is_ok());
+        commands
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Client {
+    fn receive(&mut self, commands: &mut Vec<Command>) -> io::Result<()> {
+        let mut chunk = [0; 1024];
+        let mut closed = false;
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(len) => {
+                    self.buffer.extend_from_slice(&chunk[..len]);
+
+                    // The rest is read after the lines so far are handled.
+                    if self.buffer.len() > MAX_LINE_LENGTH {
+                        break;
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match parse_command(&line) {
+                Ok(command) => {
+                    commands.push(command);
+                    self.stream.write_all(b"ok\n")?;
+                }
+                Err(reason) => writeln!(self.stream, "error: {}", reason)?,
+            }
+        }
+
+        if self.buffer.len() > MAX_LINE_LENGTH {
+            writeln!(
+                self.stream,
+                "error: Lines can't be longer than {} bytes.",
+                MAX_LINE_LENGTH
+            )?;
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        if closed {
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_command, Command, Context, ControlSocket, MAX_LINE_LENGTH};
+    use crate::accessibility::Feature;
+    use std::{
+        env, fs,
+        io::{self, BufRead, BufReader, Write},
+        net::Shutdown,
+        os::unix::{
+            fs::PermissionsExt,
+            net::{UnixListener, UnixStream},
+        },
+        process,
+    };
+
+    #[test]
+    fn it_parses_set_context() {
+        let command = parse_command("set-context app=firefox title=\n").unwrap();
+
+        match command {
+            Command::SetContext(context) => {
+                assert_eq!(context.get("app").map(String::as_str), Some("firefox"));
+                assert_eq!(context.get("title").map(String::as_str), Some(""));
+            }
+            command => panic!("expected set-context, found {:?}", command),
+        }
+    }
+
+    #[test]
+    fn it_parses_feature_commands() {
+        assert_eq!(
+            parse_command("enable slow-keys"),
+            Ok(Command::SetFeature(Feature::SlowKeys, Some(true)))
+        );
+        assert_eq!(
+            parse_command("toggle sticky-keys\n"),
+            Ok(Command::SetFeature(Feature::StickyKeys, None))
+        );
+        assert_eq!(
+            parse_command("disable mouse-keys"),
+            Err(String::from("Unknown feature `mouse-keys`."))
+        );
+        assert!(parse_command("disable").is_err());
+    }
+
+    #[test]
+    fn it_rejects_bad_commands() {
+        assert!(parse_command("set-context firefox").is_err());
+        assert!(parse_command("launch-missiles").is_err());
+    }
+
+    #[test]
+    fn it_receives_commands_from_clients() {
+        let path = env::temp_dir().join(format!("keyswitch-test-{}.sock", process::id()));
+        let mut socket = ControlSocket::bind(&path, 0o600).unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"set-context app=foot\nbogus\nset-context app=firefox\n")
+            .unwrap();
+
+        assert_eq!(socket.receive().len(), 2);
+
+        let responses = BufReader::new(client)
+            .lines()
+            .take(3)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(responses[0], "ok");
+        assert!(responses[1].starts_with("error: "));
+        assert_eq!(responses[2], "ok");
+
+        // Clients can also send a command and hang up right away.
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"set-context\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        assert_eq!(socket.receive(), vec![Command::SetContext(Context::new())]);
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_only_replaces_sockets_that_nothing_listens_on() {
+        let path = env::temp_dir().join(format!("keyswitch-test-{}-bind.sock", process::id()));
+        let socket = ControlSocket::bind(&path, 0o600).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let error = ControlSocket::bind(&path, 0o600).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        // Left behind, like after a crash.
+        drop(socket);
+        drop(UnixListener::bind(&path).unwrap());
+        let socket = ControlSocket::bind(&path, 0o660).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+        drop(socket);
+
+        fs::write(&path, "").unwrap();
+        let error = ControlSocket::bind(&path, 0o600).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_drops_clients_that_send_lines_that_are_too_long() {
+        let path = env::temp_dir().join(format!("keyswitch-test-{}-long.sock", process::id()));
+        let mut socket = ControlSocket::bind(&path, 0o600).unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"set-context app=foot\n").unwrap();
+        client.write_all(&[b'a'; MAX_LINE_LENGTH + 1]).unwrap();
+
+        assert_eq!(socket.receive().len(), 1);
+        assert_eq!(socket.fds().len(), 1);
+
+        let responses = BufReader::new(client)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(responses[0], "ok");
+        assert!(responses[1].starts_with("error: "));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::key_mapper;
+use input_linux::{Key, KeyEvent, KeyState};
+use std::{collections::HashMap, time::Duration};
+
+/// How chatter is filtered, named after QMK's algorithms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// Sends a change right away, and ignores the key until the time passes.
+    /// Quick to respond, but a worn switch can still chatter after the time.
+    Eager,
+    /// Sends a change once the key has stayed the same for the time. Slower,
+    /// but it also filters out noise that isn't a real press at all.
+    Defer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Debounce {
+    pub algorithm: Algorithm,
+    pub time: Duration,
+}
+
+/// Filters chatter from worn switches before events reach a `KeyMapper`.
+/// Like the mapper, it's timed by event timestamps and `tick`.
+#[derive(Default)]
+pub struct Debouncer {
+    /// Used for keys that aren't in `keys`. Keys aren't debounced without
+    /// one.
+    default: Option<Debounce>,
+    keys: HashMap<Key, Option<Debounce>>,
+
+    /// The state of each key on the device, and the state that was sent.
+    raw: HashMap<Key, KeyState>,
+    sent: HashMap<Key, KeyState>,
+
+    /// When eager keys can change again.
+    ignored_until: HashMap<Key, Duration>,
+
+    /// When deferred keys have been stable for long enough to send.
+    deferred_until: HashMap<Key, Duration>,
+
+    /// How often chatter was filtered for each key.
+    filtered: HashMap<Key, usize>,
+
+    /// Keys whose chatter was filtered since `take_filtered`.
+    newly_filtered: Vec<Key>,
+}
+
+impl Debouncer {
+    pub fn new(default: Option<Debounce>, keys: HashMap<Key, Option<Debounce>>) -> Self {
+        Self {
+            default,
+            keys,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the events that get through, which is none while a key is
+    /// chattering or a change is deferred.
+    pub fn filter(&mut self, event: &KeyEvent) -> Vec<KeyEvent> {
+        let debounce = match self.debounce(event.key) {
+            Some(debounce) => debounce,
+            None => return vec![*event],
+        };
+        let now = key_mapper::event_time(event);
+        let sent = self.sent(event.key);
+
+        if event.value == KeyState::AUTOREPEAT {
+            // Repeats only make sense for a key that's down, and stays down.
+            let settled = !self.deferred_until.contains_key(&event.key);
+            return if sent == KeyState::PRESSED && settled {
+                vec![*event]
+            } else {
+                vec![]
+            };
+        }
+
+        self.raw.insert(event.key, event.value);
+
+        match debounce.algorithm {
+            Algorithm::Eager => {
+                if self
+                    .ignored_until
+                    .get(&event.key)
+                    .is_some_and(|until| now < *until)
+                {
+                    self.count(event.key);
+                    return vec![];
+                }
+
+                if event.value == sent {
+                    return vec![];
+                }
+
+                self.sent.insert(event.key, event.value);
+                self.ignored_until.insert(event.key, now + debounce.time);
+                vec![*event]
+            }
+            Algorithm::Defer => {
+                if event.value == sent {
+                    // The change that was waiting didn't last.
+                    if self.deferred_until.remove(&event.key).is_some() {
+                        self.count(event.key);
+                    }
+                } else {
+                    self.deferred_until.insert(event.key, now + debounce.time);
+                }
+
+                vec![]
+            }
+        }
+    }
+
+    /// Sends deferred changes that lasted, and catches up with eager keys
+    /// that changed while they were ignored.
+    pub fn tick(&mut self, now: Duration) -> Vec<KeyEvent> {
+        let mut events = vec![];
+
+        let deferred = self
+            .deferred_until
+            .iter()
+            .filter(|(_, until)| now >= **until)
+            .map(|(key, until)| (*key, *until))
+            .collect::<Vec<_>>();
+
+        for (key, until) in deferred {
+            self.deferred_until.remove(&key);
+            events.push(self.send(key, until));
+        }
+
+        let ignored = self
+            .ignored_until
+            .iter()
+            .filter(|(_, until)| now >= **until)
+            .map(|(key, until)| (*key, *until))
+            .collect::<Vec<_>>();
+
+        for (key, until) in ignored {
+            self.ignored_until.remove(&key);
+
+            // A change that was missed while the key was ignored, like a
+            // release that came too quickly after the press.
+            if self.raw.get(&key) != self.sent.get(&key) {
+                events.push(self.send(key, until));
+
+                if let Some(debounce) = self.debounce(key) {
+                    self.ignored_until.insert(key, until + debounce.time);
+                }
+            }
+        }
+
+        events.sort_by_key(key_mapper::event_time);
+        events
+    }
+
+    /// When `tick` has something to do next.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let missed = self
+            .ignored_until
+            .iter()
+            .filter(|(key, _)| self.raw.get(key) != self.sent.get(key))
+            .map(|(_, until)| *until);
+
+        self.deferred_until.values().copied().chain(missed).min()
+    }
+
+    /// Keys whose chatter was filtered since the last call, along with how
+    /// often it's been filtered in total.
+    pub fn take_filtered(&mut self) -> Vec<(Key, usize)> {
+        let filtered = &self.filtered;
+
+        self.newly_filtered
+            .drain(..)
+            .map(|key| (key, filtered.get(&key).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    fn debounce(&self, key: Key) -> Option<Debounce> {
+        match self.keys.get(&key) {
+            Some(debounce) => *debounce,
+            None => self.default,
+        }
+    }
+
+    fn sent(&self, key: Key) -> KeyState {
+        self.sent.get(&key).copied().unwrap_or(KeyState::RELEASED)
+    }
+
+    fn send(&mut self, key: Key, time: Duration) -> KeyEvent {
+        let value = self.raw.get(&key).copied().unwrap_or(KeyState::RELEASED);
+        self.sent.insert(key, value);
+
+        key_mapper::event_at(time, key, value)
+    }
+
+    fn count(&mut self, key: Key) {
+        *self.filtered.entry(key).or_insert(0) += 1;
+
+        if !self.newly_filtered.contains(&key) {
+            self.newly_filtered.push(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Algorithm, Debounce, Debouncer};
+    use input_linux::{EventTime, Key, KeyEvent, KeyState};
+    use std::{collections::HashMap, time::Duration};
+
+    fn event(key: Key, value: KeyState, millis: i64) -> KeyEvent {
+        KeyEvent::new(EventTime::new(0, millis * 1000), key, value)
+    }
+
+    fn debouncer(algorithm: Algorithm) -> Debouncer {
+        let mut keys = HashMap::new();
+        keys.insert(Key::Space, None);
+
+        Debouncer::new(
+            Some(Debounce {
+                algorithm,
+                time: Duration::from_millis(5),
+            }),
+            keys,
+        )
+    }
+
+    #[test]
+    fn it_ignores_chatter_after_eager_changes() {
+        let mut debouncer = debouncer(Algorithm::Eager);
+
+        let press = event(Key::J, KeyState::PRESSED, 0);
+        assert_eq!(debouncer.filter(&press), vec![press]);
+        assert_eq!(
+            debouncer.filter(&event(Key::J, KeyState::RELEASED, 1)),
+            vec![]
+        );
+        assert_eq!(
+            debouncer.filter(&event(Key::J, KeyState::PRESSED, 2)),
+            vec![]
+        );
+        assert_eq!(debouncer.next_deadline(), None);
+        assert_eq!(debouncer.take_filtered(), vec![(Key::J, 2)]);
+        assert_eq!(debouncer.take_filtered(), vec![]);
+
+        // Keys without debouncing get through as they are.
+        let space = event(Key::Space, KeyState::PRESSED, 3);
+        assert_eq!(debouncer.filter(&space), vec![space]);
+
+        let release = event(Key::J, KeyState::RELEASED, 50);
+        assert_eq!(debouncer.filter(&release), vec![release]);
+    }
+
+    #[test]
+    fn it_catches_up_with_eager_changes_that_were_ignored() {
+        let mut debouncer = debouncer(Algorithm::Eager);
+
+        debouncer.filter(&event(Key::J, KeyState::PRESSED, 0));
+        debouncer.filter(&event(Key::J, KeyState::RELEASED, 2));
+        assert_eq!(debouncer.next_deadline(), Some(Duration::from_millis(5)));
+        assert_eq!(
+            debouncer.tick(Duration::from_millis(6)),
+            vec![event(Key::J, KeyState::RELEASED, 5)]
+        );
+    }
+
+    #[test]
+    fn it_sends_deferred_changes_once_they_last() {
+        let mut debouncer = debouncer(Algorithm::Defer);
+
+        assert_eq!(
+            debouncer.filter(&event(Key::J, KeyState::PRESSED, 0)),
+            vec![]
+        );
+        debouncer.filter(&event(Key::J, KeyState::RELEASED, 1));
+        debouncer.filter(&event(Key::J, KeyState::PRESSED, 2));
+        assert_eq!(debouncer.take_filtered(), vec![(Key::J, 1)]);
+        assert_eq!(debouncer.next_deadline(), Some(Duration::from_millis(7)));
+        assert_eq!(debouncer.tick(Duration::from_millis(6)), vec![]);
+        assert_eq!(
+            debouncer.tick(Duration::from_millis(7)),
+            vec![event(Key::J, KeyState::PRESSED, 7)]
+        );
+
+        // A release that doesn't last is dropped.
+        debouncer.filter(&event(Key::J, KeyState::RELEASED, 20));
+        debouncer.filter(&event(Key::J, KeyState::PRESSED, 22));
+        assert_eq!(debouncer.tick(Duration::from_millis(30)), vec![]);
+        assert_eq!(debouncer.take_filtered(), vec![(Key::J, 2)]);
+    }
+}
@@ -1,9 +1,10 @@
 use input_linux::{EvdevHandle, EventKind};
 use std::{
+    fmt,
     fs::{self, File},
     io,
     iter::Iterator,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str,
 };
 
@@ -27,6 +28,10 @@ impl Device {
             .filter_map(|entry| Self::open(entry.path()).ok()))
     }
 
+    pub fn matching(selector: &Selector) -> Result<impl Iterator<Item = Device> + '_, Error> {
+        Ok(Self::available()?.filter(move |device| selector.matches(device)))
+    }
+
     pub fn print_available() -> Result<(), Error> {
         println!("Available devices: \n");
 
@@ -63,7 +68,7 @@ impl Device {
 
         Ok(Device {
             handle,
-            dev_path: dev_path,
+            dev_path,
             name: String::from(name),
         })
     }
@@ -71,6 +76,65 @@ impl Device {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn path(&self) -> &Path {
+        &self.dev_path
+    }
+}
+
+/// Picks out devices by their name or path. Every field that is set has to
+/// match.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selector {
+    pub name: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+impl Selector {
+    /// Parses `key=value` pairs joined by commas, like
+    /// `name=daskeyboard,path=/dev/input/event2`.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut selector = Self::default();
+
+        for pair in source.split(',') {
+            match pair.split_once('=') {
+                Some(("name", name)) => selector.name = Some(String::from(name)),
+                Some(("path", path)) => selector.path = Some(PathBuf::from(path)),
+                _ => {
+                    return Err(format!(
+                        "Expected `name=...` or `path=...`, found `{}`.",
+                        pair
+                    ))
+                }
+            }
+        }
+
+        Ok(selector)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.path.is_none()
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        !self.is_empty()
+            && self.name.as_ref().is_none_or(|name| *name == device.name)
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| *path == device.dev_path)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.name, &self.path) {
+            (Some(name), Some(path)) => write!(f, "`{}` at {}", name, path.display()),
+            (Some(name), None) => write!(f, "`{}`", name),
+            (None, Some(path)) => write!(f, "{}", path.display()),
+            (None, None) => write!(f, "any device"),
+        }
+    }
 }
 
 impl From<Device> for EvdevHandle<File> {
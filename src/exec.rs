@@ -0,0 +1,143 @@
+use std::{
+    ffi::{CStr, CString},
+    fmt, io, mem,
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{self, Child, Stdio},
+    ptr,
+};
+
+/// A program to run from a key binding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    /// The program, followed by its arguments.
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+
+    /// Who to run the program as, instead of the user running keyswitch.
+    pub user: Option<User>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+impl User {
+    /// Looks up a user in the password database.
+    pub fn find(name: &str) -> Option<Self> {
+        let c_name = CString::new(name).ok()?;
+
+        Self::lookup(|passwd, buffer, result| unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                result,
+            )
+        })
+    }
+
+    pub fn find_by_uid(uid: u32) -> Option<Self> {
+        Self::lookup(|passwd, buffer, result| unsafe {
+            libc::getpwuid_r(uid, passwd, buffer.as_mut_ptr(), buffer.len(), result)
+        })
+    }
+
+    /// Calls one of the `getpw*_r` functions, growing the buffer until the
+    /// entry fits.
+    fn lookup<F>(mut get: F) -> Option<Self>
+    where
+        F: FnMut(&mut libc::passwd, &mut [libc::c_char], &mut *mut libc::passwd) -> libc::c_int,
+    {
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let mut buffer = vec![0 as libc::c_char; 4096];
+
+        loop {
+            let error = get(&mut passwd, &mut buffer, &mut result);
+
+            match error {
+                0 => break,
+                libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+                _ => return None,
+            }
+        }
+
+        if result.is_null() {
+            return None;
+        }
+
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+
+        Some(Self {
+            name: name.to_string_lossy().into_owned(),
+            uid: passwd.pw_uid,
+            gid: passwd.pw_gid,
+            home: PathBuf::from(home.to_string_lossy().into_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.args.join(" "))
+    }
+}
+
+/// Spawned commands that haven't been waited on yet.
+#[derive(Default)]
+pub struct Children {
+    children: Vec<Child>,
+}
+
+impl Children {
+    /// Starts a command without waiting for it. It gets its own process group,
+    /// so signals meant for keyswitch, like Ctrl-C in a terminal, don't reach
+    /// it.
+    pub fn spawn(&mut self, command: &Command) -> io::Result<()> {
+        let (program, args) = command
+            .args
+            .split_first()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let mut process = process::Command::new(program);
+        process
+            .args(args)
+            .stdin(Stdio::null())
+            .process_group(0)
+            .envs(command.env.iter().cloned());
+
+        if let Some(user) = &command.user {
+            process
+                .env("HOME", &user.home)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name)
+                .current_dir(&user.home);
+
+            // Switching users only works as root, so there's nothing to do if
+            // we're already running as them.
+            if unsafe { libc::getuid() } != user.uid {
+                process.uid(user.uid).gid(user.gid);
+            }
+        }
+
+        self.children.push(process.spawn()?);
+        Ok(())
+    }
+
+    /// Waits on children that have exited, without blocking.
+    pub fn reap(&mut self) {
+        self.children
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+}
@@ -0,0 +1,212 @@
+use crate::device::Selector;
+use std::{fmt::Write, path::Path};
+
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/90-keyswitch.rules";
+
+/// The templated unit started by the udev rules, once for each matching
+/// device. The instance is the device's name in `/dev/input`, like `event3`.
+pub const UNIT_PATH: &str = "/etc/systemd/system/keyswitch@.service";
+
+/// Builds a udev rule for each selector, which tags matching devices for
+/// systemd and starts the unit for them. The rules match the same devices as
+/// `Device::matching`.
+pub fn udev_rules(selectors: &[Selector]) -> Result<String, Error> {
+    let mut rules = format!(
+        "# Save as {}, then run `udevadm control --reload` and\n# `udevadm trigger`.\n",
+        UDEV_RULES_PATH
+    );
+
+    for selector in selectors {
+        if selector.is_empty() {
+            return Err(Error::EmptySelectorError);
+        }
+
+        let mut conditions = vec![
+            String::from(r#"ACTION=="add""#),
+            String::from(r#"SUBSYSTEM=="input""#),
+            String::from(r#"KERNEL=="event*""#),
+        ];
+
+        if let Some(path) = &selector.path {
+            let path = path.to_str().ok_or(Error::UnsupportedValueError)?;
+            conditions.push(format!(r#"DEVNAME=="{}""#, escape(path)?));
+        }
+
+        // The name belongs to the input device that owns the event device.
+        if let Some(name) = &selector.name {
+            conditions.push(format!(r#"ATTRS{{name}}=="{}""#, escape(name)?));
+        }
+
+        write!(
+            rules,
+            "\n# {}\n{}, TAG+=\"systemd\", ENV{{SYSTEMD_WANTS}}+=\"keyswitch@%k.service\"\n",
+            selector,
+            conditions.join(", ")
+        )
+        .unwrap();
+    }
+
+    Ok(rules)
+}
+
+/// Builds the unit started by the udev rules. It runs as root so it can open
+/// the device, but keyswitch drops privileges once it has, and systemd takes
+/// away everything else it doesn't need.
+pub fn systemd_unit(executable: &Path, config: Option<&Path>) -> Result<String, Error> {
+    let mut command = vec![
+        quote(executable)?,
+        String::from("-d"),
+        String::from("/dev/input/%I"),
+    ];
+
+    if let Some(config) = config {
+        command.push(String::from("-c"));
+        command.push(quote(config)?);
+    }
+
+    let command = command.join(" ");
+
+    Ok(format!(
+        r#"# Save as {path}, then run `systemctl daemon-reload`.
+[Unit]
+Description=Keyswitch for /dev/input/%I
+BindsTo=dev-input-%i.device
+After=dev-input-%i.device
+
+[Service]
+Type=notify
+ExecStart={command}
+WatchdogSec=30
+Restart=on-failure
+# Other instances may still be using the control socket.
+RuntimeDirectory=keyswitch
+RuntimeDirectoryPreserve=yes
+
+DevicePolicy=closed
+DeviceAllow=char-input rw
+DeviceAllow=/dev/uinput rw
+CapabilityBoundingSet=CAP_SETUID CAP_SETGID CAP_CHOWN
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=read-only
+PrivateTmp=yes
+PrivateNetwork=yes
+ProtectHostname=yes
+ProtectClock=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_UNIX
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+SystemCallFilter=@system-service
+"#,
+        path = UNIT_PATH,
+        command = command,
+    ))
+}
+
+/// Writes a path as a single argument of `ExecStart`, so that systemd doesn't
+/// split it or expand anything in it.
+fn quote(path: &Path) -> Result<String, Error> {
+    let path = path.to_str().ok_or(Error::UnsupportedValueError)?;
+
+    if path.contains(['"', '\\', '\n']) {
+        return Err(Error::UnsupportedValueError);
+    }
+
+    let path = path.replace('%', "%%").replace('$', "$$");
+
+    if path.contains(' ') {
+        Ok(format!("\"{}\"", path))
+    } else {
+        Ok(path)
+    }
+}
+
+/// Quotes the characters udev would otherwise treat as a pattern. Double
+/// quotes can't be matched at all.
+fn escape(value: &str) -> Result<String, Error> {
+    let mut escaped = String::new();
+
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '\n' => return Err(Error::UnsupportedValueError),
+            '*' | '?' | '[' | '|' => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            _ => escaped.push(c),
+        }
+    }
+
+    Ok(escaped)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// A selector without a name or path, which would match every device.
+    EmptySelectorError,
+    /// A name or path with characters that can't be written in the output.
+    UnsupportedValueError,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{systemd_unit, udev_rules, Error};
+    use crate::device::Selector;
+    use std::path::Path;
+
+    #[test]
+    fn it_builds_udev_rules_from_selectors() {
+        let selectors = [
+            Selector::parse("name=daskeyboard").unwrap(),
+            Selector::parse("name=Weird*Keyboard,path=/dev/input/event3").unwrap(),
+        ];
+        let rules = udev_rules(&selectors).unwrap();
+        let lines = rules
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                r#"ACTION=="add", SUBSYSTEM=="input", KERNEL=="event*", ATTRS{name}=="daskeyboard", TAG+="systemd", ENV{SYSTEMD_WANTS}+="keyswitch@%k.service""#,
+                r#"ACTION=="add", SUBSYSTEM=="input", KERNEL=="event*", DEVNAME=="/dev/input/event3", ATTRS{name}=="Weird[*]Keyboard", TAG+="systemd", ENV{SYSTEMD_WANTS}+="keyswitch@%k.service""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_selectors_it_cannot_write() {
+        assert!(Selector::parse("vendor=1234").is_err());
+        assert_eq!(
+            udev_rules(&[Selector::default()]),
+            Err(Error::EmptySelectorError)
+        );
+        assert_eq!(
+            udev_rules(&[Selector::parse("name=\"quoted\"").unwrap()]),
+            Err(Error::UnsupportedValueError)
+        );
+    }
+
+    #[test]
+    fn it_passes_the_device_to_the_unit() {
+        let unit = systemd_unit(
+            Path::new("/usr/bin/keyswitch"),
+            Some(Path::new("/etc/keyswitch/my config.toml")),
+        )
+        .unwrap();
+
+        assert!(unit.contains(
+            "\nExecStart=/usr/bin/keyswitch -d /dev/input/%I -c \"/etc/keyswitch/my config.toml\"\n"
+        ));
+    }
+}
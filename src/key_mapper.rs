@@ -1,7 +1,14 @@
+use crate::{
+    exec, key_names,
+    layout::Layout,
+    text::{self, TextMethod},
+};
 use input_linux::{EventTime, Key, KeyEvent, KeyState};
 use linked_hash_set::LinkedHashSet;
 use std::{
     collections::{HashMap, HashSet},
+    fmt,
+    time::Duration,
     vec::Vec,
 };
 
@@ -9,18 +16,401 @@ pub struct KeyMapper {
     mappings: Vec<Mapping>,
     pressed_keys: HashSet<Key>,
 
+    /// Layers whose mappings currently apply, in addition to mappings that
+    /// aren't part of a layer.
+    enabled_layers: HashSet<String>,
+
     /// Keys that were already released to isolate a mapping.
     already_released: LinkedHashSet<Key>,
 
     /// Keys that were previously mapped, which we'll need to be able to identify
     /// again if their prefixes are no longer held.
     mapped_keys: HashMap<Key, Key>,
+
+    /// Keys that ran a command, whose repeats and release shouldn't be sent.
+    swallowed_keys: HashSet<Key>,
+
+    /// Commands from mappings that haven't been run yet.
+    commands: Vec<exec::Command>,
+
+    combos: Vec<Combo>,
+
+    /// Presses of combo keys, held back until it's clear whether they make up
+    /// a combo.
+    pending_presses: Vec<KeyEvent>,
+
+    /// Combos that fired and whose keys are still held, along with the key
+    /// they sent. It's released as soon as any of the combo's keys are.
+    held_combos: Vec<(Vec<Key>, Key)>,
+
+    leader: Option<Leader>,
+    sequences: SequenceNode,
+
+    /// Keys typed since the leader key was tapped, and when the sequence
+    /// times out. Set while a sequence is being typed.
+    typed_sequence: Option<(Vec<Key>, Duration)>,
+
+    /// One-shot modifiers that are held, or were tapped and still apply.
+    one_shots: Vec<OneShot>,
+
+    /// Layers enabled by one-shot modifiers.
+    one_shot_layers: HashSet<String>,
+
+    tap_holds: HashMap<Key, TapHold>,
+    auto_shift: Option<AutoShift>,
+
+    /// A tap-hold key that's pressed, but isn't known to be tapped or held
+    /// yet, and when it was pressed.
+    undecided: Option<(Key, Duration)>,
+
+    /// Events that came after the undecided key, held back until it's known
+    /// what it does, so they're sent after it.
+    held_back: Vec<KeyEvent>,
+
+    /// Tap-hold keys that are still pressed, and whether they were tapped or
+    /// held.
+    decided: HashMap<Key, Decided>,
+
+    /// When each tap-hold key was last tapped.
+    last_taps: HashMap<Key, Duration>,
+
+    /// Layers enabled by held tap-hold and tap-dance keys.
+    held_layers: HashSet<String>,
+
+    /// A tap-dance key that's being tapped, until it's known how often.
+    dance: Option<Dance>,
+
+    /// Tap-dance keys that are still held after their dance ended, and what
+    /// they hold.
+    danced: HashMap<Key, Decided>,
+
+    mouse: MouseSettings,
+
+    /// Keys with a mouse action that are held.
+    mouse_keys: Vec<(Key, MouseAction)>,
+
+    /// When the pointer started moving, and when it moves next, while it's
+    /// moving.
+    pointer: Option<(Duration, Duration)>,
+
+    /// When to scroll next, while scrolling.
+    scrolling: Option<Duration>,
+
+    /// Mouse movement that hasn't been sent yet.
+    movements: Vec<Movement>,
+
+    /// The latest time from an event or `tick`.
+    now: Duration,
+
+    text_method: TextMethod,
+    layout: Layout,
+
+    /// Keys that typed text, whose prefixes are pressed again when they're
+    /// released.
+    text_keys: HashSet<Key>,
+
+    /// Set when the mapper repeats keys itself, instead of passing through the
+    /// repeats of the keyboard.
+    repeat: Option<Repeat>,
+    no_repeat: HashSet<Key>,
+
+    /// The key that's repeating, and when it repeats next.
+    repeating: Option<(Key, Duration)>,
+
+    caps_word: CapsWord,
+
+    /// Set while Caps Word is on, along with when it times out, if it does.
+    caps_word_on: bool,
+    caps_word_deadline: Option<Duration>,
+
+    /// Whether `LeftShift` was last sent pressed, by Caps Word or otherwise.
+    shifted: bool,
+
+    /// Keys that type `-`, and the key sent instead to type `_`.
+    underscores: HashMap<Key, Key>,
 }
 
 struct Mapping {
     prefixes: Vec<Key>,
     old: Key,
-    new: Key,
+    action: Action,
+    layer: Option<String>,
+    conditions: Conditions,
+    modifiers: HeldModifiers,
+}
+
+/// Other keys that decide whether a mapping applies, besides its prefixes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Conditions {
+    /// At least one key of each group has to be held, like either Shift.
+    /// Held keys are released like prefixes when the mapping applies.
+    pub any: Vec<Vec<Key>>,
+
+    /// None of these can be held.
+    pub unless: Vec<Key>,
+}
+
+/// What happens to modifiers that are held, but aren't prefixes, when a
+/// mapping applies. Prefixes are always released.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeldModifiers {
+    /// Keeps them held, so Shift with Caps Lock and H can select to the left.
+    #[default]
+    Keep,
+    /// Releases them too, until the mapped key is released.
+    Strip,
+}
+
+/// Keys that do something else when pressed together, in any order.
+struct Combo {
+    keys: Vec<Key>,
+    action: Action,
+
+    /// How long after the first key the rest have to be pressed.
+    timeout: Duration,
+}
+
+/// A key that starts a sequence of other keys, which are tapped in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leader {
+    pub key: Key,
+
+    /// How long to wait for each key of the sequence.
+    pub timeout: Duration,
+
+    /// Stops typing a sequence, without sending anything.
+    pub cancel: Option<Key>,
+
+    /// Whether to send the keys of a sequence that doesn't match anything, or
+    /// drop them.
+    pub replay_unmatched: bool,
+}
+
+/// What a one-shot action modifies the next key with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Modifier {
+    Key(Key),
+    Layer(String),
+}
+
+/// A key with a one-shot action that's in use.
+struct OneShot {
+    /// The key that was pressed.
+    key: Key,
+    modifier: Modifier,
+    held: bool,
+
+    /// Whether another key was pressed while it was held, which makes it work
+    /// like a normal modifier.
+    interrupted: bool,
+
+    /// Once for a modifier that applies to the next key, twice for one that's
+    /// locked.
+    taps: u8,
+}
+
+/// A key that does one thing when tapped, and another when held.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TapHold {
+    pub tap: Key,
+    pub hold: Modifier,
+
+    /// How long the key has to be held to be held, rather than tapped, unless
+    /// the decision is made sooner.
+    pub timeout: Duration,
+
+    /// Pressing the key this soon after tapping it taps it again right away,
+    /// so holding it repeats the tapped key.
+    pub quick_tap: Duration,
+    pub decision: Decision,
+}
+
+/// How a tap-hold key decides that it's held before its timeout, when other
+/// keys are pressed while it's down. Keys pressed after it are held back until
+/// it's decided either way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// Only holding it until the timeout holds it.
+    Timeout,
+    /// Pressing and releasing another key while it's down holds it.
+    PermissiveHold,
+    /// Pressing another key while it's down holds it.
+    HoldOnOtherKeyPress,
+}
+
+enum Decided {
+    Tap(Key),
+    Hold(Modifier),
+    /// An auto-shift key that was held, and whether it's still shifted and
+    /// pressed.
+    Shifted {
+        held: bool,
+    },
+}
+
+/// A key that taps a different key depending on how often it's tapped in a
+/// row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TapDance {
+    /// The key tapped for each number of taps, starting with one. The dance
+    /// ends as soon as the key is tapped as often as there are keys.
+    pub taps: Vec<Key>,
+
+    /// Held instead when the key is still held as the dance ends, which
+    /// otherwise holds the key for the number of taps.
+    pub hold: Option<Modifier>,
+
+    /// How long after each press or release the dance ends, unless the key is
+    /// pressed or released again. Pressing another key ends it right away.
+    pub term: Duration,
+}
+
+/// A tap-dance key that's being tapped.
+struct Dance {
+    key: Key,
+
+    /// The prefixes of the mapping, which are released when the dance ends.
+    prefixes: Vec<Key>,
+    tap_dance: TapDance,
+    taps: usize,
+    held: bool,
+
+    /// When the dance ends, unless the key is pressed or released before.
+    deadline: Duration,
+}
+
+/// Moves the pointer or scrolls while the key is held. `y` is positive going
+/// down, for scrolling too.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MouseAction {
+    Move { x: i32, y: i32 },
+    Scroll { x: i32, y: i32 },
+}
+
+const MOUSE_ACTIONS: &[(&str, MouseAction)] = &[
+    ("up", MouseAction::Move { x: 0, y: -1 }),
+    ("down", MouseAction::Move { x: 0, y: 1 }),
+    ("left", MouseAction::Move { x: -1, y: 0 }),
+    ("right", MouseAction::Move { x: 1, y: 0 }),
+    ("scroll-up", MouseAction::Scroll { x: 0, y: -1 }),
+    ("scroll-down", MouseAction::Scroll { x: 0, y: 1 }),
+    ("scroll-left", MouseAction::Scroll { x: -1, y: 0 }),
+    ("scroll-right", MouseAction::Scroll { x: 1, y: 0 }),
+];
+
+/// How mouse actions move the pointer and scroll.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MouseSettings {
+    /// Time between each step of moving the pointer.
+    pub interval: Duration,
+
+    /// Pixels moved in each step, when a key is first pressed.
+    pub speed: f64,
+
+    /// Pixels moved in each step, once the pointer is done accelerating.
+    pub max_speed: f64,
+
+    /// How long it takes to go from `speed` to `max_speed`.
+    pub acceleration: Duration,
+
+    /// The shape of the acceleration, which is linear at 1. Higher curves start
+    /// slower, for finer control.
+    pub curve: f64,
+
+    /// Time between each notch of scrolling.
+    pub scroll_interval: Duration,
+}
+
+/// How long a key is held before it starts repeating, and how often it repeats
+/// after that.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Repeat {
+    pub delay: Duration,
+    pub period: Duration,
+}
+
+/// Keys that type their shifted character when they're held for a while, which
+/// are decided like tap-hold keys with the `Timeout` decision.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoShift {
+    pub keys: HashSet<Key>,
+
+    /// How long a key has to be held to be shifted.
+    pub timeout: Duration,
+
+    /// Whether a shifted key stays held until it's released, so it repeats.
+    /// Otherwise it's tapped as soon as it's shifted.
+    pub repeat: bool,
+}
+
+/// How Caps Word shifts the letters of one word, like a constant's name.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CapsWord {
+    /// How long Caps Word stays on without a key being typed, if it times out.
+    pub timeout: Option<Duration>,
+
+    /// Whether `-` types `_` instead.
+    pub underscore: bool,
+}
+
+/// Keys that only change what other keys do, so they never repeat. Sticky keys
+/// apply to these too.
+pub const MODIFIERS: &[Key] = &[
+    Key::LeftCtrl,
+    Key::RightCtrl,
+    Key::LeftShift,
+    Key::RightShift,
+    Key::LeftAlt,
+    Key::RightAlt,
+    Key::LeftMeta,
+    Key::RightMeta,
+];
+
+/// Keys that never repeat, besides modifiers.
+const LOCKS: &[Key] = &[Key::CapsLock, Key::NumLock, Key::ScrollLock];
+
+/// A step of mouse movement, which is sent as a single report.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Movement {
+    pub x: i32,
+    pub y: i32,
+    pub wheel: i32,
+    pub horizontal_wheel: i32,
+}
+
+/// Sequences that start with the same keys share nodes.
+#[derive(Default)]
+struct SequenceNode {
+    action: Option<Action>,
+    next: HashMap<Key, SequenceNode>,
+}
+
+/// What happens when a mapping matches.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Sends a different key.
+    Key(Key),
+    /// Runs a command when the key is pressed, and sends nothing.
+    Exec(exec::Command),
+    /// Works like a normal modifier when held. When tapped, it only modifies
+    /// the next key, and tapping it twice locks it until it's tapped again.
+    OneShot(Modifier),
+    /// Moves the pointer or scrolls while the key is held, and sends nothing.
+    Mouse(MouseAction),
+    /// Types text when the key is pressed, which can include characters that
+    /// aren't on the keyboard.
+    Text(String),
+    /// Taps a different key depending on how often the key is tapped.
+    TapDance(TapDance),
+    /// Turns Caps Word on when the key is pressed, or off if it's on.
+    CapsWord,
+    /// Sends nothing, for the press or the release.
+    Noop,
+    /// Sends nothing, like `Noop`, for a key that's turned off entirely.
+    Disable,
+    /// Sends a different key, like `Key`, for one of two keys that send each
+    /// other.
+    Swap(Key),
 }
 
 impl KeyMapper {
@@ -28,285 +418,3088 @@ impl KeyMapper {
         Self {
             mappings: Vec::new(),
             pressed_keys: HashSet::new(),
+            enabled_layers: HashSet::new(),
             already_released: LinkedHashSet::new(),
             mapped_keys: HashMap::new(),
+            swallowed_keys: HashSet::new(),
+            commands: Vec::new(),
+            combos: Vec::new(),
+            pending_presses: Vec::new(),
+            held_combos: Vec::new(),
+            leader: None,
+            sequences: SequenceNode::default(),
+            typed_sequence: None,
+            one_shots: Vec::new(),
+            one_shot_layers: HashSet::new(),
+            tap_holds: HashMap::new(),
+            auto_shift: None,
+            undecided: None,
+            held_back: Vec::new(),
+            decided: HashMap::new(),
+            last_taps: HashMap::new(),
+            held_layers: HashSet::new(),
+            dance: None,
+            danced: HashMap::new(),
+            mouse: MouseSettings::default(),
+            mouse_keys: Vec::new(),
+            pointer: None,
+            scrolling: None,
+            movements: Vec::new(),
+            now: Duration::ZERO,
+            text_method: TextMethod::default(),
+            layout: Layout::default(),
+            text_keys: HashSet::new(),
+            repeat: None,
+            no_repeat: HashSet::new(),
+            repeating: None,
+            caps_word: CapsWord::default(),
+            caps_word_on: false,
+            caps_word_deadline: None,
+            shifted: false,
+            underscores: HashMap::new(),
         }
     }
 
     pub fn add_mapping(&mut self, keys: &[Key], new: &Key) -> Result<(), Error> {
+        self.add_action(keys, Action::Key(*new))
+    }
+
+    pub fn add_action(&mut self, keys: &[Key], action: Action) -> Result<(), Error> {
+        self.insert_mapping(
+            keys,
+            action,
+            None,
+            Conditions::default(),
+            HeldModifiers::Keep,
+        )
+    }
+
+    /// Adds a mapping that only applies while its layer is enabled. These are
+    /// tried before other mappings with the same number of prefixes.
+    pub fn add_layer_action(
+        &mut self,
+        layer: &str,
+        keys: &[Key],
+        action: Action,
+    ) -> Result<(), Error> {
+        self.insert_mapping(
+            keys,
+            action,
+            Some(String::from(layer)),
+            Conditions::default(),
+            HeldModifiers::Keep,
+        )
+    }
+
+    /// Adds a mapping that only applies when its conditions hold, and its
+    /// layer is enabled, if it has one. Each `any` group counts as a prefix
+    /// when mappings are ordered.
+    pub fn add_conditional_action(
+        &mut self,
+        layer: Option<&str>,
+        keys: &[Key],
+        conditions: Conditions,
+        modifiers: HeldModifiers,
+        action: Action,
+    ) -> Result<(), Error> {
+        self.insert_mapping(keys, action, layer.map(String::from), conditions, modifiers)
+    }
+
+    /// Makes a key send nothing. This wins over anything else on the key, like
+    /// other mappings, tap-hold, combos or the leader, but the key still works
+    /// as a prefix of other mappings.
+    pub fn disable(&mut self, key: Key) -> Result<(), Error> {
+        self.add_action(&[key], Action::Disable)
+    }
+
+    /// Makes two keys send each other. Like disabled keys, these win over
+    /// anything else on the keys.
+    pub fn swap(&mut self, a: Key, b: Key) -> Result<(), Error> {
+        self.add_action(&[a], Action::Swap(b))?;
+        self.add_action(&[b], Action::Swap(a))
+    }
+
+    pub fn add_combo(
+        &mut self,
+        keys: &[Key],
+        action: Action,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let unique = keys.iter().collect::<HashSet<_>>();
+        if unique.len() < 2 || unique.len() != keys.len() {
+            return Err(Error::BadComboError);
+        }
+
+        self.combos.push(Combo {
+            keys: Vec::from(keys),
+            action,
+            timeout,
+        });
+
+        Ok(())
+    }
+
+    /// Makes a key tap one key and hold another, or a layer. This replaces
+    /// anything else the key was mapped to.
+    pub fn add_tap_hold(&mut self, key: Key, tap_hold: TapHold) {
+        self.tap_holds.insert(key, tap_hold);
+    }
+
+    /// Keys that are tap-hold keys too, and mouse buttons, aren't auto-shifted.
+    pub fn set_auto_shift(&mut self, auto_shift: AutoShift) {
+        self.auto_shift = Some(auto_shift);
+    }
+
+    pub fn set_text_method(&mut self, method: TextMethod) {
+        self.text_method = method;
+    }
+
+    /// Repeats the last key sent while it's held, unless it's a modifier, a
+    /// mouse button, or one of the `no_repeat` keys. Repeats from the keyboard
+    /// should be dropped before they get here.
+    pub fn set_repeat(&mut self, repeat: Repeat, no_repeat: &[Key]) {
+        self.repeat = Some(repeat);
+        self.no_repeat = MODIFIERS
+            .iter()
+            .chain(LOCKS)
+            .chain(no_repeat)
+            .copied()
+            .collect();
+    }
+
+    pub fn set_caps_word(&mut self, caps_word: CapsWord) {
+        self.caps_word = caps_word;
+    }
+
+    /// The layout text is typed on, and that Caps Word finds letters on.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn set_mouse(&mut self, mouse: MouseSettings) {
+        self.mouse = mouse;
+    }
+
+    pub fn set_leader(&mut self, leader: Leader) {
+        self.leader = Some(leader);
+    }
+
+    /// Adds a sequence of keys to tap after the leader key.
+    pub fn add_sequence(&mut self, keys: &[Key], action: Action) -> Result<(), Error> {
+        if keys.is_empty() {
+            return Err(Error::EmptyMappingError);
+        }
+
+        let node = keys.iter().fold(&mut self.sequences, |node, key| {
+            node.next.entry(*key).or_default()
+        });
+        node.action = Some(action);
+
+        Ok(())
+    }
+
+    pub fn set_enabled_layers(&mut self, layers: HashSet<String>) {
+        self.enabled_layers = layers;
+    }
+
+    fn insert_mapping(
+        &mut self,
+        keys: &[Key],
+        action: Action,
+        layer: Option<String>,
+        conditions: Conditions,
+        modifiers: HeldModifiers,
+    ) -> Result<(), Error> {
         match keys.split_last() {
             None => Err(Error::EmptyMappingError),
             Some((old, prefixes)) => {
                 self.mappings.push(Mapping {
                     prefixes: Vec::from(prefixes),
                     old: *old,
-                    new: *new,
+                    action,
+                    layer,
+                    conditions,
+                    modifiers,
+                });
+                self.mappings.sort_by_key(|mapping| {
+                    mapping_order(
+                        mapping.prefixes.len(),
+                        &mapping.conditions,
+                        mapping.layer.as_deref(),
+                        &mapping.action,
+                    )
                 });
-                self.mappings.sort_by_key(|mapping| mapping.prefixes.len());
 
                 Ok(())
             }
         }
     }
 
+    /// Commands that mappings asked to run since this was last called.
+    pub fn take_commands(&mut self) -> Vec<exec::Command> {
+        std::mem::take(&mut self.commands)
+    }
+
+    /// Mouse movement since this was last called, in the order it happened.
+    pub fn take_movements(&mut self) -> Vec<Movement> {
+        std::mem::take(&mut self.movements)
+    }
+
+    /// Whether any mapping moves the mouse or sends a mouse button, which
+    /// needs a pointer to send them on.
+    pub fn uses_pointer(&self) -> bool {
+        self.mappings
+            .iter()
+            .map(|mapping| &mapping.action)
+            .chain(self.combos.iter().map(|combo| &combo.action))
+            .any(Action::uses_pointer)
+            || self.sequences.uses_pointer()
+            || self
+                .tap_holds
+                .values()
+                .any(|tap_hold| is_mouse_button(tap_hold.tap))
+    }
+
+    /// When `tick` needs to be called next, in the same clock as event
+    /// timestamps. This is set while keys are held back for a combo or a
+    /// tap-hold key, a sequence or tap-dance is being typed, or mouse keys are
+    /// held.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.combo_deadline()
+            .into_iter()
+            .chain(self.typed_sequence.as_ref().map(|(_, deadline)| *deadline))
+            .chain(self.tap_hold_deadline())
+            .chain(self.dance.as_ref().map(|dance| dance.deadline))
+            .chain(self.pointer.map(|(_, next)| next))
+            .chain(self.scrolling)
+            .chain(self.repeating.map(|(_, next)| next))
+            .chain(self.caps_word_deadline)
+            .min()
+    }
+
+    /// Lets time pass without a key event. Tap-hold keys are held once they
+    /// time out, keys held back for a combo are sent once the combo can't be
+    /// completed anymore, sequences and tap-dances that time out end, held mouse
+    /// keys move the mouse, held keys repeat, and Caps Word times out.
+    pub fn tick(&mut self, now: Duration) -> Vec<(Key, KeyState)> {
+        self.now = self.now.max(now);
+        self.move_mouse();
+
+        let mut final_keys = self.repeat_key();
+
+        final_keys.append(&mut match self.tap_hold_deadline() {
+            Some(deadline) if now >= deadline => self.decide(true),
+            _ => vec![],
+        });
+
+        if self
+            .dance
+            .as_ref()
+            .is_some_and(|dance| now >= dance.deadline)
+        {
+            final_keys.append(&mut self.end_dance());
+        }
+
+        final_keys.append(&mut match self.combo_deadline() {
+            Some(deadline) if now >= deadline => match self.complete_combo(true) {
+                Some(index) => self.fire_combo(index),
+                None => self.flush_pending_presses(),
+            },
+            _ => vec![],
+        });
+
+        if let Some((keys, deadline)) = self.typed_sequence.take() {
+            if now >= deadline {
+                final_keys.append(&mut self.end_sequence(&keys));
+            } else {
+                self.typed_sequence = Some((keys, deadline));
+            }
+        }
+
+        let mut final_keys = self.apply_caps_word(final_keys);
+        if self
+            .caps_word_deadline
+            .is_some_and(|deadline| now >= deadline)
+        {
+            final_keys.append(&mut self.set_caps_word_on(false));
+        }
+
+        self.track_repeat(&final_keys);
+        final_keys
+    }
+
     pub fn handle_key_event(&mut self, event: &KeyEvent) -> Vec<(Key, KeyState)> {
-        let matched_mapping = match event.value {
-            KeyState::PRESSED | KeyState::AUTOREPEAT => {
-                self.mappings.iter().find(|Mapping { prefixes, old, .. }| {
-                    *old == event.key && self.all_pressed(prefixes)
-                })
+        // In case `tick` wasn't called in time.
+        let mut final_keys = self.tick(event_time(event));
+        let keys = self.feed_key_event(event);
+        let keys = self.apply_caps_word(keys);
+        self.track_repeat(&keys);
+
+        final_keys.extend(keys);
+        final_keys
+    }
+
+    /// Repeats the repeating key, if it's time to.
+    fn repeat_key(&mut self) -> Vec<(Key, KeyState)> {
+        match (self.repeat, self.repeating) {
+            (Some(repeat), Some((key, next))) if self.now >= next => {
+                // Skip repeats that were missed, instead of sending them all
+                // at once.
+                let next = (next + repeat.period).max(self.now + repeat.period);
+                self.repeating = Some((key, next));
+                vec![(key, KeyState::AUTOREPEAT)]
             }
-            _ => None,
+            _ => vec![],
+        }
+    }
+
+    /// Starts repeating the last key pressed in `keys`, or stops when the
+    /// repeating key is released.
+    fn track_repeat(&mut self, keys: &[(Key, KeyState)]) {
+        let repeat = match self.repeat {
+            Some(repeat) => repeat,
+            None => return,
         };
 
-        update_pressed_keys(&mut self.pressed_keys, event);
+        for (key, state) in keys.iter() {
+            match *state {
+                KeyState::PRESSED if self.repeats(*key) => {
+                    self.repeating = Some((*key, self.now + repeat.delay));
+                }
+                KeyState::RELEASED if self.repeating.map(|(key, _)| key) == Some(*key) => {
+                    self.repeating = None;
+                }
+                _ => (),
+            }
+        }
+    }
 
-        if let Some(mapping) = matched_mapping {
-            self.mapped_keys.insert(event.key, mapping.new);
+    fn repeats(&self, key: Key) -> bool {
+        !self.no_repeat.contains(&key) && !key_names::name(key).starts_with("Button")
+    }
 
-            let keys = mapping
-                .prefixes
-                .iter()
-                .filter(|key| !self.already_released.contains(key))
-                .collect::<Vec<_>>();
+    /// Shifts the letters among keys that are about to be sent while Caps Word
+    /// is on, and turns it off at the first key that isn't part of a word.
+    /// Digits, Backspace and `-` are, but they aren't shifted, unless `-` types
+    /// `_`. Modifiers and mouse buttons are sent as they are.
+    fn apply_caps_word(&mut self, keys: Vec<(Key, KeyState)>) -> Vec<(Key, KeyState)> {
+        let mut final_keys = vec![];
 
-            // Ignore release events later for these keys, as they'll already
-            // have been reported.
-            for key in keys.iter() {
-                self.already_released.insert(**key);
+        for (key, state) in keys {
+            if let Some(underscore) = self.underscores.get(&key).copied() {
+                if state == KeyState::RELEASED {
+                    self.underscores.remove(&key);
+                }
+                final_keys.push((underscore, state));
+                continue;
             }
 
-            let mut final_keys = vec![];
+            if key == Key::LeftShift {
+                self.shifted = state != KeyState::RELEASED;
+            }
 
-            // Release prefixes so other clients don't see them as being pressed
-            // at the same time as the mapped key.
-            for key in keys {
-                final_keys.push((
-                    // If one of the keys was already being mapped, we release
-                    // the key it was mapped to, instead of the key itself.
-                    self.mapped_keys
-                        .remove_entry(key)
-                        .map(|(_old, new)| new)
-                        .unwrap_or(*key),
-                    KeyState::RELEASED,
-                ))
+            let as_is = MODIFIERS.contains(&key) || LOCKS.contains(&key) || is_mouse_button(key);
+            if !self.caps_word_on || state != KeyState::PRESSED || as_is {
+                final_keys.push((key, state));
+                continue;
             }
 
-            final_keys.push((mapping.new, event.value));
-            final_keys
-        } else if let Some((_old, new)) = self.mapped_keys.remove_entry(&event.key) {
-            // First, release the mapped key.
-            let mut final_keys = vec![(new, event.value)];
+            let c = self.layout.character(key);
+            let (key, shift) = match c {
+                Some(c) if c.is_alphabetic() => (key, true),
+                Some('-') if self.caps_word.underscore => match self.layout.find('_') {
+                    Some((underscore, shift)) => {
+                        self.underscores.insert(key, underscore);
+                        (underscore, shift)
+                    }
+                    None => (key, false),
+                },
+                Some(c) if c.is_ascii_digit() || c == '-' => (key, false),
+                _ if key == Key::Backspace => (key, false),
+                _ => {
+                    final_keys.append(&mut self.set_caps_word_on(false));
+                    final_keys.push((key, state));
+                    continue;
+                }
+            };
 
-            // Then, re-press any prefixes that are still being held down.
-            while let Some(key) = self.already_released.pop_back() {
-                // Each prefix might have it's own mapping, so we recursively call
-                // handle_key_event and append everything.
-                final_keys
-                    .append(&mut self.handle_key_event(&synthetic_event(key, KeyState::PRESSED)));
+            if shift != self.shifted {
+                self.shifted = shift;
+                let state = if shift {
+                    KeyState::PRESSED
+                } else {
+                    KeyState::RELEASED
+                };
+                final_keys.push((Key::LeftShift, state));
             }
 
-            final_keys
-        } else if self.already_released.contains(&event.key) {
-            self.already_released.remove(&event.key);
+            self.caps_word_deadline = self.caps_word.timeout.map(|timeout| self.now + timeout);
+            final_keys.push((key, state));
+        }
+
+        final_keys
+    }
+
+    /// Turns Caps Word on or off, releasing Shift if it's held when it's
+    /// turned off.
+    fn set_caps_word_on(&mut self, on: bool) -> Vec<(Key, KeyState)> {
+        self.caps_word_on = on;
+        self.caps_word_deadline = self
+            .caps_word
+            .timeout
+            .filter(|_| on)
+            .map(|timeout| self.now + timeout);
+
+        if !on && self.shifted {
+            self.shifted = false;
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        } else {
             vec![]
+        }
+    }
+
+    /// Handles an event without letting time pass, holding it back if a
+    /// tap-hold key is undecided.
+    fn feed_key_event(&mut self, event: &KeyEvent) -> Vec<(Key, KeyState)> {
+        if self.undecided.is_some() {
+            self.hold_back(event)
         } else {
-            vec![(event.key, event.value)]
+            self.process_key_event(event)
         }
     }
 
-    fn all_pressed(&self, prefixes: &[Key]) -> bool {
-        prefixes.iter().all(|key| self.pressed_keys.contains(key))
+    fn process_key_event(&mut self, event: &KeyEvent) -> Vec<(Key, KeyState)> {
+        let (mut final_keys, handled) = self.handle_dance_key(event);
+
+        if handled {
+            return final_keys;
+        }
+
+        if let Some(mut keys) = self.handle_tap_hold_key(event) {
+            final_keys.append(&mut keys);
+            return final_keys;
+        }
+
+        if event.value == KeyState::PRESSED {
+            let (mut keys, handled) = self.handle_sequence_key(event);
+            final_keys.append(&mut keys);
+
+            if handled {
+                return final_keys;
+            }
+
+            // A key that doesn't fit the pending combo might still start
+            // another one.
+            if !self.extends_combo(event.key) {
+                final_keys.append(&mut self.flush_pending_presses());
+            }
+
+            if self.extends_combo(event.key) {
+                self.pending_presses.push(*event);
+
+                if let Some(index) = self.complete_combo(false) {
+                    final_keys.append(&mut self.fire_combo(index));
+                }
+
+                return final_keys;
+            }
+        }
+
+        final_keys.append(&mut self.flush_pending_presses());
+        final_keys.append(&mut self.map_key_event(event));
+
+        // Tapped one-shot modifiers only apply to the key pressed after them.
+        if event.value == KeyState::PRESSED
+            && self
+                .one_shots
+                .iter()
+                .all(|one_shot| one_shot.key != event.key)
+        {
+            final_keys.append(&mut self.release_one_shots());
+        }
+
+        final_keys
+    }
+
+    /// Handles events of tap-hold and auto-shift keys while nothing is
+    /// undecided, or returns `None` for other keys.
+    fn handle_tap_hold_key(&mut self, event: &KeyEvent) -> Option<Vec<(Key, KeyState)>> {
+        if self.is_shortcut_key(event.key) {
+            return None;
+        }
+
+        let tap_hold = match self.tap_holds.get(&event.key) {
+            Some(tap_hold) => tap_hold.clone(),
+            None if self.auto_shifts(event) => self.tap_hold(event.key)?,
+            None => return None,
+        };
+        let now = event_time(event);
+
+        let final_keys = match (event.value, self.decided.get(&event.key)) {
+            (KeyState::PRESSED, _) => {
+                let quick_tap = self
+                    .last_taps
+                    .get(&event.key)
+                    .is_some_and(|tapped| now < *tapped + tap_hold.quick_tap);
+
+                if quick_tap {
+                    let tap = tap_hold.tap;
+                    self.decided.insert(event.key, Decided::Tap(tap));
+                    vec![(tap, KeyState::PRESSED)]
+                } else {
+                    self.undecided = Some((event.key, now));
+                    vec![]
+                }
+            }
+            (KeyState::AUTOREPEAT, Some(Decided::Tap(new)))
+            | (KeyState::AUTOREPEAT, Some(Decided::Hold(Modifier::Key(new)))) => {
+                vec![(*new, KeyState::AUTOREPEAT)]
+            }
+            (KeyState::AUTOREPEAT, Some(Decided::Shifted { held: true })) => {
+                vec![(event.key, KeyState::AUTOREPEAT)]
+            }
+            (KeyState::RELEASED, Some(_)) => match self.decided.remove(&event.key) {
+                Some(Decided::Shifted { held: true }) => vec![
+                    (event.key, KeyState::RELEASED),
+                    (Key::LeftShift, KeyState::RELEASED),
+                ],
+                Some(Decided::Shifted { held: false }) => vec![],
+                Some(Decided::Tap(new)) => {
+                    self.last_taps.insert(event.key, now);
+                    vec![(new, KeyState::RELEASED)]
+                }
+                Some(Decided::Hold(Modifier::Key(new))) => vec![(new, KeyState::RELEASED)],
+                Some(Decided::Hold(Modifier::Layer(layer))) => {
+                    self.held_layers.remove(&layer);
+                    vec![]
+                }
+                None => vec![],
+            },
+            _ => vec![],
+        };
+
+        Some(final_keys)
+    }
+
+    /// Holds back an event that came while a tap-hold key is undecided, unless
+    /// it decides it.
+    fn hold_back(&mut self, event: &KeyEvent) -> Vec<(Key, KeyState)> {
+        let (key, _) = match self.undecided {
+            Some(undecided) => undecided,
+            None => return vec![],
+        };
+        let decision = match self.tap_hold(key) {
+            Some(tap_hold) => tap_hold.decision,
+            None => return vec![],
+        };
+
+        if event.key == key {
+            return match event.value {
+                KeyState::RELEASED => {
+                    let mut final_keys = self.decide(false);
+                    final_keys.append(&mut self.feed_key_event(event));
+                    final_keys
+                }
+                _ => vec![],
+            };
+        }
+
+        let pressed_after = self
+            .held_back
+            .iter()
+            .any(|held_back| held_back.key == event.key && held_back.value == KeyState::PRESSED);
+
+        match event.value {
+            // Keys that were pressed before aren't affected.
+            KeyState::RELEASED if !pressed_after => return self.process_key_event(event),
+            KeyState::PRESSED if decision == Decision::HoldOnOtherKeyPress => {
+                self.held_back.push(*event);
+                return self.decide(true);
+            }
+            KeyState::RELEASED if decision == Decision::PermissiveHold => {
+                self.held_back.push(*event);
+                return self.decide(true);
+            }
+            _ => self.held_back.push(*event),
+        }
+
+        vec![]
+    }
+
+    /// Taps or holds the undecided tap-hold key, then sends the events that
+    /// were held back.
+    fn decide(&mut self, hold: bool) -> Vec<(Key, KeyState)> {
+        let (key, _) = match self.undecided.take() {
+            Some(undecided) => undecided,
+            None => return vec![],
+        };
+        let tap_hold = match self.tap_hold(key) {
+            Some(tap_hold) => tap_hold,
+            None => return vec![],
+        };
+        let repeat = self
+            .auto_shift
+            .as_ref()
+            .is_some_and(|auto_shift| auto_shift.repeat);
+
+        let mut final_keys = match (hold, tap_hold.hold) {
+            (true, _) if !self.tap_holds.contains_key(&key) => {
+                self.decided.insert(key, Decided::Shifted { held: repeat });
+
+                let mut keys = vec![
+                    (Key::LeftShift, KeyState::PRESSED),
+                    (key, KeyState::PRESSED),
+                ];
+                if !repeat {
+                    keys.push((key, KeyState::RELEASED));
+                    keys.push((Key::LeftShift, KeyState::RELEASED));
+                }
+                keys
+            }
+            (false, _) => {
+                self.decided.insert(key, Decided::Tap(tap_hold.tap));
+                vec![(tap_hold.tap, KeyState::PRESSED)]
+            }
+            (true, Modifier::Key(new)) => {
+                self.decided.insert(key, Decided::Hold(Modifier::Key(new)));
+                vec![(new, KeyState::PRESSED)]
+            }
+            (true, Modifier::Layer(layer)) => {
+                self.held_layers.insert(layer.clone());
+                self.decided
+                    .insert(key, Decided::Hold(Modifier::Layer(layer)));
+                vec![]
+            }
+        };
+
+        // These may include other tap-hold keys, which can be undecided in
+        // turn.
+        for event in std::mem::take(&mut self.held_back) {
+            final_keys.append(&mut self.feed_key_event(&event));
+        }
+
+        final_keys
+    }
+
+    /// Counts the taps of a tap-dance key, and handles the key while it's
+    /// held after its dance ended. Returns any keys to send, and whether the
+    /// event was used up. Pressing another key ends the dance first.
+    fn handle_dance_key(&mut self, event: &KeyEvent) -> (Vec<(Key, KeyState)>, bool) {
+        if let Some(decided) = self.danced.get(&event.key) {
+            let final_keys = match (event.value, decided) {
+                (KeyState::AUTOREPEAT, Decided::Tap(new))
+                | (KeyState::AUTOREPEAT, Decided::Hold(Modifier::Key(new))) => {
+                    vec![(*new, KeyState::AUTOREPEAT)]
+                }
+                (KeyState::RELEASED, _) => self.release_dance(event.key),
+                _ => vec![],
+            };
+
+            update_pressed_keys(&mut self.pressed_keys, event);
+            return (final_keys, true);
+        }
+
+        let dance = match &mut self.dance {
+            Some(dance) => dance,
+            None => return (vec![], false),
+        };
+
+        if dance.key != event.key {
+            return match event.value {
+                KeyState::PRESSED => (self.end_dance(), false),
+                _ => (vec![], false),
+            };
+        }
+
+        let deadline = event_time(event) + dance.tap_dance.term;
+        let mut ended = false;
+
+        match event.value {
+            KeyState::PRESSED => {
+                dance.taps += 1;
+                dance.held = true;
+                dance.deadline = deadline;
+            }
+            KeyState::RELEASED => {
+                dance.held = false;
+                dance.deadline = deadline;
+                ended = dance.taps >= dance.tap_dance.taps.len();
+            }
+            _ => (),
+        }
+
+        update_pressed_keys(&mut self.pressed_keys, event);
+
+        if ended {
+            (self.end_dance(), true)
+        } else {
+            (vec![], true)
+        }
+    }
+
+    /// Ends the tap-dance, tapping the key for how often it was tapped, or
+    /// holding it if the key is still held. Prefixes that are still held are
+    /// released around it, like for any other mapping.
+    fn end_dance(&mut self) -> Vec<(Key, KeyState)> {
+        let dance = match self.dance.take() {
+            Some(dance) => dance,
+            None => return vec![],
+        };
+        let taps = &dance.tap_dance.taps;
+        let tap = match taps.get(dance.taps - 1).or(taps.last()) {
+            Some(tap) => *tap,
+            None => return vec![],
+        };
+
+        let prefixes = dance
+            .prefixes
+            .iter()
+            .filter(|key| self.pressed_keys.contains(key))
+            .copied()
+            .collect::<Vec<_>>();
+        let mut final_keys = self.release_prefixes(&prefixes);
+
+        let decided = match (dance.held, dance.tap_dance.hold) {
+            (false, _) => {
+                final_keys.push((tap, KeyState::PRESSED));
+                final_keys.push((tap, KeyState::RELEASED));
+                final_keys.append(&mut self.press_released_prefixes());
+                return final_keys;
+            }
+            (true, None) => {
+                final_keys.push((tap, KeyState::PRESSED));
+                Decided::Tap(tap)
+            }
+            (true, Some(Modifier::Key(new))) => {
+                final_keys.push((new, KeyState::PRESSED));
+                Decided::Hold(Modifier::Key(new))
+            }
+            (true, Some(Modifier::Layer(layer))) => {
+                self.held_layers.insert(layer.clone());
+                Decided::Hold(Modifier::Layer(layer))
+            }
+        };
+
+        self.danced.insert(dance.key, decided);
+        final_keys
+    }
+
+    /// Releases whatever a tap-dance key held after its dance ended, and
+    /// presses its prefixes again.
+    fn release_dance(&mut self, key: Key) -> Vec<(Key, KeyState)> {
+        let mut final_keys = match self.danced.remove(&key) {
+            Some(Decided::Tap(new)) | Some(Decided::Hold(Modifier::Key(new))) => {
+                vec![(new, KeyState::RELEASED)]
+            }
+            Some(Decided::Hold(Modifier::Layer(layer))) => {
+                self.held_layers.remove(&layer);
+                vec![]
+            }
+            Some(Decided::Shifted { .. }) | None => vec![],
+        };
+
+        final_keys.append(&mut self.press_released_prefixes());
+        final_keys
+    }
+
+    fn tap_hold_deadline(&self) -> Option<Duration> {
+        let (key, pressed) = self.undecided?;
+        Some(pressed + self.tap_hold(key)?.timeout)
+    }
+
+    /// The tap-hold settings of a key, which auto-shift keys have too.
+    fn tap_hold(&self, key: Key) -> Option<TapHold> {
+        if let Some(tap_hold) = self.tap_holds.get(&key) {
+            return Some(tap_hold.clone());
+        }
+
+        let auto_shift = self.auto_shift.as_ref()?;
+        if !auto_shift.keys.contains(&key) {
+            return None;
+        }
+
+        Some(TapHold {
+            tap: key,
+            hold: Modifier::Key(Key::LeftShift),
+            timeout: auto_shift.timeout,
+            quick_tap: Duration::ZERO,
+            decision: Decision::Timeout,
+        })
+    }
+
+    /// Whether an event is for an auto-shift key. Presses are only shifted when
+    /// no mapping matches, and no modifiers are held, so those work as usual.
+    fn auto_shifts(&self, event: &KeyEvent) -> bool {
+        let is_auto_shift = !is_mouse_button(event.key)
+            && self
+                .auto_shift
+                .as_ref()
+                .is_some_and(|auto_shift| auto_shift.keys.contains(&event.key));
+
+        if !is_auto_shift || event.value != KeyState::PRESSED {
+            return is_auto_shift && self.decided.contains_key(&event.key);
+        }
+
+        let mapped = self
+            .mappings
+            .iter()
+            .any(|mapping| self.matches(mapping, event.key));
+        let modified = self.pressed_keys.iter().any(|key| MODIFIERS.contains(key));
+
+        !mapped && !modified
+    }
+
+    /// Starts or continues a sequence with a key press. Returns any keys to
+    /// send, and whether the press was used up.
+    fn handle_sequence_key(&mut self, event: &KeyEvent) -> (Vec<(Key, KeyState)>, bool) {
+        let leader = match &self.leader {
+            Some(leader) => leader,
+            None => return (vec![], false),
+        };
+        let deadline = event_time(event) + leader.timeout;
+
+        let mut keys = match self.typed_sequence.take() {
+            Some(_) if Some(event.key) == leader.cancel => {
+                self.swallowed_keys.insert(event.key);
+                update_pressed_keys(&mut self.pressed_keys, event);
+                return (vec![], true);
+            }
+            Some((keys, _)) => keys,
+            None if event.key == leader.key && !self.is_shortcut_key(event.key) => {
+                // Combo keys pressed before the leader aren't part of the
+                // sequence.
+                let flushed = self.flush_pending_presses();
+
+                self.swallowed_keys.insert(event.key);
+                update_pressed_keys(&mut self.pressed_keys, event);
+                self.typed_sequence = Some((vec![], deadline));
+                return (flushed, true);
+            }
+            None => return (vec![], false),
+        };
+
+        keys.push(event.key);
+
+        match self.find_sequence(&keys).map(|node| node.next.is_empty()) {
+            Some(is_last) => {
+                self.swallowed_keys.insert(event.key);
+                update_pressed_keys(&mut self.pressed_keys, event);
+
+                if is_last {
+                    (self.end_sequence(&keys), true)
+                } else {
+                    // A longer sequence could still be typed, so this one
+                    // waits for the timeout.
+                    self.typed_sequence = Some((keys, deadline));
+                    (vec![], true)
+                }
+            }
+            None => {
+                keys.pop();
+                let replay = leader.replay_unmatched;
+                let final_keys = self.end_sequence(&keys);
+
+                // The key that didn't match is sent along with the rest, or
+                // dropped with them.
+                if !replay {
+                    self.swallowed_keys.insert(event.key);
+                    update_pressed_keys(&mut self.pressed_keys, event);
+                }
+
+                (final_keys, !replay)
+            }
+        }
+    }
+
+    fn find_sequence(&self, keys: &[Key]) -> Option<&SequenceNode> {
+        keys.iter()
+            .try_fold(&self.sequences, |node, key| node.next.get(key))
+    }
+
+    /// Finishes a sequence, running its action if it has one. Otherwise the
+    /// leader and the keys typed after it are tapped, if they're replayed.
+    fn end_sequence(&mut self, keys: &[Key]) -> Vec<(Key, KeyState)> {
+        let leader = match &self.leader {
+            Some(leader) => leader,
+            None => return vec![],
+        };
+
+        match self
+            .find_sequence(keys)
+            .and_then(|node| node.action.clone())
+        {
+            Some(Action::Key(new)) | Some(Action::Swap(new)) => {
+                vec![(new, KeyState::PRESSED), (new, KeyState::RELEASED)]
+            }
+            Some(Action::Exec(command)) => {
+                self.commands.push(command);
+                vec![]
+            }
+            Some(Action::Text(text)) => self.type_text(&text),
+            Some(Action::CapsWord) => self.set_caps_word_on(!self.caps_word_on),
+            // There's no key to hold for these.
+            Some(Action::OneShot(_))
+            | Some(Action::Mouse(_))
+            | Some(Action::TapDance(_))
+            | Some(Action::Noop)
+            | Some(Action::Disable) => vec![],
+            None if leader.replay_unmatched => Some(&leader.key)
+                .into_iter()
+                .chain(keys.iter())
+                .flat_map(|key| vec![(*key, KeyState::PRESSED), (*key, KeyState::RELEASED)])
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn map_key_event(&mut self, event: &KeyEvent) -> Vec<(Key, KeyState)> {
+        if self.swallowed_keys.contains(&event.key) {
+            let mut final_keys = vec![];
+
+            if event.value == KeyState::RELEASED {
+                self.swallowed_keys.remove(&event.key);
+                self.release_mouse_key(event.key);
+
+                if self.text_keys.remove(&event.key) {
+                    final_keys.append(&mut self.press_released_prefixes());
+                }
+
+                if let Some(index) = self
+                    .held_combos
+                    .iter()
+                    .position(|(keys, _)| keys.contains(&event.key))
+                {
+                    let (_, new) = self.held_combos.remove(index);
+                    final_keys.push((new, KeyState::RELEASED));
+                }
+            }
+
+            update_pressed_keys(&mut self.pressed_keys, event);
+            return final_keys;
+        }
+
+        if let Some(final_keys) = self.handle_one_shot_key(event) {
+            return final_keys;
+        }
+
+        let matched_mapping = match event.value {
+            KeyState::PRESSED | KeyState::AUTOREPEAT => self.mappings.iter().find(|mapping| {
+                self.matches(mapping, event.key)
+                    // Commands only run when a key is first pressed, and not
+                    // when it repeats.
+                    && (event.value == KeyState::PRESSED || !mapping.action.is_exec())
+            }),
+            _ => None,
+        };
+
+        update_pressed_keys(&mut self.pressed_keys, event);
+
+        if let Some(mapping) = matched_mapping {
+            let new = match &mapping.action {
+                Action::Key(new) | Action::Swap(new) => *new,
+                Action::Exec(command) => {
+                    self.commands.push(command.clone());
+                    self.swallowed_keys.insert(event.key);
+                    return vec![];
+                }
+                Action::Mouse(action) => {
+                    self.swallowed_keys.insert(event.key);
+                    self.press_mouse_key(event.key, *action);
+                    return vec![];
+                }
+                Action::Text(text) => {
+                    // Typed on their own, so held keys don't change what's typed.
+                    let prefixes = self.held_prefixes(mapping);
+                    let text = self.type_text(text);
+                    let mut final_keys = self.release_prefixes(&prefixes);
+                    final_keys.extend(text);
+
+                    self.swallowed_keys.insert(event.key);
+                    self.text_keys.insert(event.key);
+                    return final_keys;
+                }
+                Action::CapsWord => {
+                    self.swallowed_keys.insert(event.key);
+                    return self.set_caps_word_on(!self.caps_word_on);
+                }
+                Action::Noop | Action::Disable => {
+                    self.swallowed_keys.insert(event.key);
+                    return vec![];
+                }
+                Action::TapDance(tap_dance) => {
+                    self.dance = Some(Dance {
+                        key: event.key,
+                        prefixes: self.held_prefixes(mapping),
+                        tap_dance: tap_dance.clone(),
+                        taps: 1,
+                        held: true,
+                        deadline: self.now + tap_dance.term,
+                    });
+                    return vec![];
+                }
+                Action::OneShot(modifier) => {
+                    self.one_shots.push(OneShot {
+                        key: event.key,
+                        modifier: modifier.clone(),
+                        held: true,
+                        interrupted: false,
+                        taps: 0,
+                    });
+
+                    match modifier {
+                        // Pressed like any other mapped key, so it's released
+                        // properly if it's a prefix of another mapping.
+                        Modifier::Key(new) => *new,
+                        Modifier::Layer(layer) => {
+                            self.one_shot_layers.insert(layer.clone());
+                            return vec![];
+                        }
+                    }
+                }
+            };
+
+            self.mapped_keys.insert(event.key, new);
+
+            // Release prefixes so other clients don't see them as being pressed
+            // at the same time as the mapped key.
+            let prefixes = self.held_prefixes(mapping);
+            let mut final_keys = self.release_prefixes(&prefixes);
+
+            final_keys.push((new, event.value));
+            final_keys
+        } else if let Some((_old, new)) = self.mapped_keys.remove_entry(&event.key) {
+            // First, release the mapped key.
+            let mut final_keys = vec![(new, event.value)];
+
+            // Then, re-press any prefixes that are still being held down.
+            final_keys.append(&mut self.press_released_prefixes());
+            final_keys
+        } else if self.already_released.contains(&event.key) {
+            self.already_released.remove(&event.key);
+            vec![]
+        } else {
+            vec![(event.key, event.value)]
+        }
+    }
+
+    /// Handles presses and releases of keys with a one-shot action that's in
+    /// use, or returns `None` to map the event as usual.
+    fn handle_one_shot_key(&mut self, event: &KeyEvent) -> Option<Vec<(Key, KeyState)>> {
+        let index = match self
+            .one_shots
+            .iter()
+            .position(|one_shot| one_shot.key == event.key)
+        {
+            Some(index) => index,
+            None => {
+                // Held one-shot modifiers that are used with another key work
+                // like normal modifiers.
+                if event.value == KeyState::PRESSED {
+                    for one_shot in self.one_shots.iter_mut().filter(|one_shot| one_shot.held) {
+                        one_shot.interrupted = true;
+                    }
+                }
+
+                return None;
+            }
+        };
+
+        let one_shot = &mut self.one_shots[index];
+        let mut final_keys = vec![];
+
+        match event.value {
+            KeyState::PRESSED => {
+                // A key that's pressed while it's still held was released to
+                // isolate another mapping, and is now being pressed again.
+                match (one_shot.held, &one_shot.modifier) {
+                    (true, Modifier::Key(new)) => final_keys.push((*new, KeyState::PRESSED)),
+                    (true, Modifier::Layer(_)) => (),
+                    (false, _) => {
+                        one_shot.held = true;
+                        one_shot.interrupted = false;
+                    }
+                }
+
+                if let Modifier::Key(new) = one_shot.modifier {
+                    self.mapped_keys.insert(event.key, new);
+                }
+            }
+            KeyState::RELEASED if one_shot.interrupted => {
+                let one_shot = self.one_shots.remove(index);
+
+                match one_shot.modifier {
+                    // Released like any other mapped key.
+                    Modifier::Key(_) => return None,
+                    Modifier::Layer(layer) => {
+                        self.one_shot_layers.remove(&layer);
+                    }
+                }
+            }
+            KeyState::RELEASED => {
+                one_shot.held = false;
+                one_shot.taps += 1;
+                self.mapped_keys.remove(&event.key);
+
+                if one_shot.taps > 2 {
+                    let one_shot = self.one_shots.remove(index);
+                    final_keys.extend(self.release_one_shot(one_shot));
+                }
+            }
+            _ => (),
+        }
+
+        update_pressed_keys(&mut self.pressed_keys, event);
+        Some(final_keys)
+    }
+
+    /// Releases one-shot modifiers that were tapped once.
+    fn release_one_shots(&mut self) -> Vec<(Key, KeyState)> {
+        let (released, kept) = std::mem::take(&mut self.one_shots)
+            .into_iter()
+            .partition::<Vec<_>, _>(|one_shot| !one_shot.held && one_shot.taps == 1);
+        self.one_shots = kept;
+
+        released
+            .into_iter()
+            .flat_map(|one_shot| self.release_one_shot(one_shot))
+            .collect()
+    }
+
+    fn release_one_shot(&mut self, one_shot: OneShot) -> Option<(Key, KeyState)> {
+        match one_shot.modifier {
+            Modifier::Key(new) => Some((new, KeyState::RELEASED)),
+            Modifier::Layer(layer) => {
+                self.one_shot_layers.remove(&layer);
+                None
+            }
+        }
+    }
+
+    /// Releases the prefixes of a mapping that haven't been released yet.
+    fn release_prefixes(&mut self, prefixes: &[Key]) -> Vec<(Key, KeyState)> {
+        // Swallowed keys were never sent, so there's nothing to release.
+        let keys = prefixes
+            .iter()
+            .filter(|key| {
+                !self.already_released.contains(key) && !self.swallowed_keys.contains(key)
+            })
+            .copied()
+            .collect::<Vec<_>>();
+
+        // Ignore release events later for these keys, as they'll already
+        // have been reported.
+        for key in keys.iter() {
+            self.already_released.insert(*key);
+        }
+
+        keys.into_iter()
+            .map(|key| {
+                (
+                    // If one of the keys was already being mapped, we release
+                    // the key it was mapped to, instead of the key itself.
+                    self.mapped_keys
+                        .remove_entry(&key)
+                        .map(|(_old, new)| new)
+                        .unwrap_or(key),
+                    KeyState::RELEASED,
+                )
+            })
+            .collect()
+    }
+
+    /// Presses the prefixes that were released, and are still held down.
+    fn press_released_prefixes(&mut self) -> Vec<(Key, KeyState)> {
+        let mut final_keys = vec![];
+
+        while let Some(key) = self.already_released.pop_back() {
+            // Each prefix might have it's own mapping, so we recursively call
+            // handle_key_event and append everything.
+            final_keys.append(&mut self.map_key_event(&synthetic_event(key, KeyState::PRESSED)));
+        }
+
+        final_keys
+    }
+
+    /// The keys that type some text. Text that can't be typed with the method
+    /// was already reported when loading the configuration, so it's skipped.
+    fn type_text(&self, text: &str) -> Vec<(Key, KeyState)> {
+        text::keys(text, &self.text_method, &self.layout).unwrap_or_default()
+    }
+
+    /// Starts moving the mouse, or moves it in a new direction too.
+    fn press_mouse_key(&mut self, key: Key, action: MouseAction) {
+        if self.mouse_keys.iter().any(|(held, _)| *held == key) {
+            return;
+        }
+
+        self.mouse_keys.push((key, action));
+
+        match action {
+            MouseAction::Move { .. } if self.pointer.is_none() => {
+                self.pointer = Some((self.now, self.now));
+            }
+            MouseAction::Scroll { .. } if self.scrolling.is_none() => {
+                self.scrolling = Some(self.now);
+            }
+            _ => (),
+        }
+
+        // The first step happens right away.
+        self.move_mouse();
+    }
+
+    fn release_mouse_key(&mut self, key: Key) {
+        self.mouse_keys.retain(|(held, _)| *held != key);
+
+        let actions = self.mouse_keys.iter().map(|(_, action)| action);
+        let (moving, scrolling) =
+            actions.fold((false, false), |(moving, scrolling), action| match action {
+                MouseAction::Move { .. } => (true, scrolling),
+                MouseAction::Scroll { .. } => (moving, true),
+            });
+
+        if !moving {
+            self.pointer = None;
+        }
+
+        if !scrolling {
+            self.scrolling = None;
+        }
+    }
+
+    /// Takes any steps of moving the pointer or scrolling that are due.
+    fn move_mouse(&mut self) {
+        let now = self.now;
+
+        if let Some((started, next)) = self.pointer {
+            if now >= next {
+                let speed = self.mouse.speed_after(now - started);
+                let (x, y) = self.direction(false);
+
+                self.movements.push(Movement {
+                    x: x * speed,
+                    y: y * speed,
+                    ..Movement::default()
+                });
+                self.pointer = Some((started, now + self.mouse.interval));
+            }
+        }
+
+        if let Some(next) = self.scrolling {
+            if now >= next {
+                let (x, y) = self.direction(true);
+
+                // The wheel counts up when scrolling up.
+                self.movements.push(Movement {
+                    wheel: -y,
+                    horizontal_wheel: x,
+                    ..Movement::default()
+                });
+                self.scrolling = Some(now + self.mouse.scroll_interval);
+            }
+        }
+    }
+
+    /// Which way the held mouse keys move the pointer, or scroll. Opposite
+    /// directions cancel out.
+    fn direction(&self, scroll: bool) -> (i32, i32) {
+        let (x, y) = self
+            .mouse_keys
+            .iter()
+            .filter_map(|(_, action)| match (action, scroll) {
+                (MouseAction::Move { x, y }, false) | (MouseAction::Scroll { x, y }, true) => {
+                    Some((*x, *y))
+                }
+                _ => None,
+            })
+            .fold((0, 0), |(x, y), (dx, dy)| (x + dx, y + dy));
+
+        (x.signum(), y.signum())
+    }
+
+    fn combo_deadline(&self) -> Option<Duration> {
+        let first = self.pending_presses.first()?;
+        let pending = self.pending_keys();
+
+        self.candidate_combos(&pending)
+            .map(|combo| combo.timeout)
+            .max()
+            .map(|timeout| event_time(first) + timeout)
+    }
+
+    fn pending_keys(&self) -> Vec<Key> {
+        self.pending_presses.iter().map(|event| event.key).collect()
+    }
+
+    /// Combos that have every one of the keys.
+    fn candidate_combos<'a>(&'a self, keys: &'a [Key]) -> impl Iterator<Item = &'a Combo> {
+        self.combos
+            .iter()
+            .filter(move |combo| keys.iter().all(|key| combo.keys.contains(key)))
+    }
+
+    fn extends_combo(&self, key: Key) -> bool {
+        let mut keys = self.pending_keys();
+        if keys.contains(&key) || self.is_shortcut_key(key) {
+            return false;
+        }
+
+        keys.push(key);
+        self.candidate_combos(&keys).count() > 0
+    }
+
+    /// Finds the combo made up of exactly the pending keys. Unless the combo
+    /// timed out, it has to wait if a bigger combo could still be completed.
+    fn complete_combo(&self, timed_out: bool) -> Option<usize> {
+        let pending = self.pending_keys();
+        let mut candidates = self.candidate_combos(&pending);
+
+        if !timed_out && candidates.any(|combo| combo.keys.len() > pending.len()) {
+            return None;
+        }
+
+        self.combos.iter().position(|combo| {
+            combo.keys.len() == pending.len() && pending.iter().all(|key| combo.keys.contains(key))
+        })
+    }
+
+    fn fire_combo(&mut self, index: usize) -> Vec<(Key, KeyState)> {
+        let keys = self.pending_keys();
+
+        for event in self.pending_presses.drain(..) {
+            update_pressed_keys(&mut self.pressed_keys, &event);
+            self.swallowed_keys.insert(event.key);
+        }
+
+        match &self.combos[index].action {
+            Action::Key(new) | Action::Swap(new) => {
+                self.held_combos.push((keys, *new));
+                vec![(*new, KeyState::PRESSED)]
+            }
+            Action::Exec(command) => {
+                self.commands.push(command.clone());
+                vec![]
+            }
+            Action::Text(text) => self.type_text(text),
+            Action::CapsWord => self.set_caps_word_on(!self.caps_word_on),
+            // There's no key to hold for these.
+            Action::OneShot(_)
+            | Action::Mouse(_)
+            | Action::TapDance(_)
+            | Action::Noop
+            | Action::Disable => vec![],
+        }
+    }
+
+    /// Sends keys that were held back for a combo that didn't happen, as if
+    /// they were just pressed.
+    fn flush_pending_presses(&mut self) -> Vec<(Key, KeyState)> {
+        let mut final_keys = vec![];
+
+        for event in std::mem::take(&mut self.pending_presses) {
+            final_keys.append(&mut self.map_key_event(&event));
+        }
+
+        final_keys
+    }
+
+    fn all_pressed(&self, prefixes: &[Key]) -> bool {
+        prefixes.iter().all(|key| self.pressed_keys.contains(key))
+    }
+
+    /// Whether a key is disabled or swapped, so that it's only ever mapped
+    /// like that.
+    fn is_shortcut_key(&self, key: Key) -> bool {
+        self.mappings.iter().any(|mapping| {
+            mapping.action.is_shortcut() && mapping.old == key && mapping.prefixes.is_empty()
+        })
+    }
+
+    fn matches(&self, mapping: &Mapping, key: Key) -> bool {
+        let conditions = &mapping.conditions;
+
+        mapping.old == key
+            && self.all_pressed(&mapping.prefixes)
+            && conditions
+                .any
+                .iter()
+                .all(|keys| keys.iter().any(|key| self.pressed_keys.contains(key)))
+            && !conditions
+                .unless
+                .iter()
+                .any(|key| self.pressed_keys.contains(key))
+            && self.is_enabled(mapping)
+    }
+
+    /// The prefixes of a mapping, along with the keys held for its `any`
+    /// groups, and any other modifiers if it strips them.
+    fn held_prefixes(&self, mapping: &Mapping) -> Vec<Key> {
+        let held = mapping
+            .conditions
+            .any
+            .iter()
+            .flatten()
+            .filter(|key| self.pressed_keys.contains(key) && !mapping.prefixes.contains(key));
+        let mut prefixes = mapping
+            .prefixes
+            .iter()
+            .chain(held)
+            .copied()
+            .collect::<Vec<_>>();
+
+        if mapping.modifiers == HeldModifiers::Strip {
+            let mut modifiers = self
+                .pressed_keys
+                .iter()
+                .filter(|key| {
+                    let sent = self.mapped_keys.get(key).unwrap_or(key);
+
+                    **key != mapping.old
+                        && MODIFIERS.contains(sent)
+                        && !self.swallowed_keys.contains(key)
+                        && !prefixes.contains(key)
+                })
+                .copied()
+                .collect::<Vec<_>>();
+
+            // In a stable order, since they're held at the same time anyway.
+            modifiers.sort();
+            prefixes.append(&mut modifiers);
+        }
+
+        prefixes
+    }
+
+    fn is_enabled(&self, mapping: &Mapping) -> bool {
+        mapping.layer.as_ref().is_none_or(|layer| {
+            self.enabled_layers.contains(layer)
+                || self.one_shot_layers.contains(layer)
+                || self.held_layers.contains(layer)
+        })
+    }
+}
+
+impl Action {
+    fn is_exec(&self) -> bool {
+        matches!(self, Action::Exec(_))
+    }
+
+    /// Whether this disables or swaps a key.
+    fn is_shortcut(&self) -> bool {
+        matches!(self, Action::Disable | Action::Swap(_))
+    }
+
+    fn uses_pointer(&self) -> bool {
+        match self {
+            Action::Key(key) | Action::Swap(key) => is_mouse_button(*key),
+            Action::TapDance(dance) => dance.taps.iter().any(|key| is_mouse_button(*key)),
+            Action::Mouse(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl SequenceNode {
+    fn uses_pointer(&self) -> bool {
+        self.action.as_ref().is_some_and(Action::uses_pointer)
+            || self.next.values().any(SequenceNode::uses_pointer)
+    }
+}
+
+impl MouseAction {
+    /// Looks up an action by name, like `up` or `scroll-down`.
+    pub fn parse(name: &str) -> Option<Self> {
+        MOUSE_ACTIONS
+            .iter()
+            .find(|(action_name, _)| action_name.eq_ignore_ascii_case(name.trim()))
+            .map(|(_, action)| *action)
+    }
+}
+
+impl fmt::Display for MouseAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match MOUSE_ACTIONS.iter().find(|(_, action)| action == self) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl MouseSettings {
+    /// Pixels to move in each step, after the pointer has been moving for a
+    /// while.
+    fn speed_after(&self, elapsed: Duration) -> i32 {
+        let progress = if self.acceleration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / self.acceleration.as_secs_f64()).min(1.0)
+        };
+        let speed = self.speed + (self.max_speed - self.speed) * progress.powf(self.curve);
+
+        (speed.round() as i32).max(1)
+    }
+}
+
+impl Default for Repeat {
+    /// The same as the kernel's.
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(250),
+            period: Duration::from_millis(33),
+        }
+    }
+}
+
+impl Default for MouseSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(16),
+            speed: 2.0,
+            max_speed: 24.0,
+            acceleration: Duration::from_millis(1000),
+            curve: 2.0,
+            scroll_interval: Duration::from_millis(80),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Key(key) => write!(f, "{}", key_names::name(*key)),
+            Action::Exec(command) => write!(f, "exec `{}`", command),
+            Action::OneShot(Modifier::Key(key)) => {
+                write!(f, "one-shot {}", key_names::name(*key))
+            }
+            Action::OneShot(Modifier::Layer(layer)) => write!(f, "one-shot layer `{}`", layer),
+            Action::Mouse(action) => write!(f, "mouse {}", action),
+            Action::Text(text) => write!(f, "text `{}`", text),
+            Action::TapDance(tap_dance) => {
+                let taps = tap_dance
+                    .taps
+                    .iter()
+                    .map(|key| key_names::name(*key))
+                    .collect::<Vec<_>>();
+                write!(f, "tap-dance {}", taps.join(", "))
+            }
+            Action::CapsWord => write!(f, "caps-word"),
+            Action::Noop => write!(f, "noop"),
+            Action::Disable => write!(f, "disable"),
+            Action::Swap(key) => write!(f, "swap with {}", key_names::name(*key)),
+        }
+    }
+}
+
+impl Default for KeyMapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn update_pressed_keys(pressed_keys: &mut HashSet<Key>, event: &KeyEvent) {
+    match event.value {
+        KeyState::PRESSED | KeyState::AUTOREPEAT => {
+            pressed_keys.insert(event.key);
+        }
+        KeyState::RELEASED => {
+            pressed_keys.remove(&event.key);
+        }
+        _ => (),
+    }
+}
+
+/// An event for re-pressing keys while mapping, where the time doesn't matter.
+fn synthetic_event(key: Key, value: KeyState) -> KeyEvent {
+    KeyEvent::new(EventTime::new(0, 0), key, value)
+}
+
+/// Disabled and swapped keys are tried before anything else. Other mappings
+/// are tried by number of prefixes, counting `any` groups, and then mappings
+/// that only apply in a layer go first. Ties are tried in the order they were
+/// added.
+pub fn mapping_order(
+    prefixes: usize,
+    conditions: &Conditions,
+    layer: Option<&str>,
+    action: &Action,
+) -> (bool, usize, bool) {
+    (
+        !action.is_shortcut(),
+        prefixes + conditions.any.len(),
+        layer.is_none(),
+    )
+}
+
+/// Whether a key is a mouse button, which keyswitch sends on a pointer instead
+/// of a keyboard, and keeps out of features meant for typing.
+pub fn is_mouse_button(key: Key) -> bool {
+    (Key::ButtonLeft as u16..=Key::ButtonTask as u16).contains(&(key as u16))
+}
+
+/// The time of an event, as a duration in whichever clock the device uses.
+/// `KeyMapper` never reads the clock itself, so the same clock has to be used
+/// for `tick`.
+pub fn event_time(event: &KeyEvent) -> Duration {
+    Duration::from_secs(event.time.seconds() as u64)
+        + Duration::from_micros(event.time.microseconds() as u64)
+}
+
+/// An event at a time in the same clock as `event_time`.
+pub fn event_at(time: Duration, key: Key, value: KeyState) -> KeyEvent {
+    let time = EventTime::new(time.as_secs() as i64, time.subsec_micros() as i64);
+    KeyEvent::new(time, key, value)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    EmptyMappingError,
+    /// A combo with fewer than two different keys.
+    BadComboError,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        synthetic_event, Action, AutoShift, CapsWord, Conditions, Decision, HeldModifiers,
+        KeyMapper, Leader, Modifier, MouseAction, MouseSettings, Movement, Repeat, TapDance,
+        TapHold,
+    };
+    use crate::exec;
+    use input_linux::{EventTime, Key, KeyEvent, KeyState};
+    use std::{collections::HashSet, time::Duration};
+
+    fn timed_event(key: Key, value: KeyState, millis: i64) -> KeyEvent {
+        KeyEvent::new(EventTime::new(0, millis * 1000), key, value)
+    }
+
+    fn combo_mapper() -> KeyMapper {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_combo(
+                &[Key::J, Key::K],
+                Action::Key(Key::Esc),
+                Duration::from_millis(30),
+            )
+            .unwrap();
+        mapper
+    }
+
+    #[test]
+    fn it_returns_same_key_if_no_mappings() {
+        let mut mapper = KeyMapper::new();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![(Key::J, KeyState::PRESSED)]
+        );
+    }
+
+    #[test]
+    fn it_returns_same_key_if_no_mappings_match() {
+        let mut mapper = KeyMapper::new();
+        mapper.add_mapping(&[Key::CapsLock], &Key::J).unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![(Key::J, KeyState::PRESSED)]
+        );
+    }
+
+    #[test]
+    fn it_maps_a_single_key() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock], &Key::LeftCtrl)
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::LeftCtrl, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::LeftCtrl, KeyState::RELEASED)),
+            vec![(Key::LeftCtrl, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_maps_a_sequence() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            vec![
+                (Key::Down, KeyState::RELEASED),
+                (Key::CapsLock, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::CapsLock, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_maps_a_sequence_with_more_than_one_prefix() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::LeftShift, Key::J], &Key::Down)
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::PRESSED)),
+            vec![(Key::LeftShift, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            vec![
+                (Key::Down, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::CapsLock, KeyState::PRESSED),
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::RELEASED)),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::CapsLock, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_keeps_held_modifiers_that_are_not_prefixes() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_conditional_action(
+                None,
+                &[Key::CapsLock, Key::H],
+                Conditions::default(),
+                HeldModifiers::Keep,
+                Action::Key(Key::Left),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+        mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::PRESSED));
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::H, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::Left, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::H, KeyState::RELEASED)),
+            vec![
+                (Key::Left, KeyState::RELEASED),
+                (Key::CapsLock, KeyState::PRESSED),
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::RELEASED)),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_strips_held_modifiers_that_are_not_prefixes() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_conditional_action(
+                None,
+                &[Key::CapsLock, Key::H],
+                Conditions::default(),
+                HeldModifiers::Strip,
+                Action::Key(Key::Left),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+        mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::PRESSED));
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::H, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::Left, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::H, KeyState::RELEASED)),
+            vec![
+                (Key::Left, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::CapsLock, KeyState::PRESSED),
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::RELEASED)),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_swallows_disabled_keys() {
+        let mut mapper = KeyMapper::new();
+        mapper.disable(Key::CapsLock).unwrap();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+
+        for state in [KeyState::PRESSED, KeyState::AUTOREPEAT] {
+            assert_eq!(
+                mapper.handle_key_event(&synthetic_event(Key::CapsLock, state)),
+                vec![]
+            );
+        }
+
+        // It's still held as a prefix, but there's nothing to release.
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![(Key::Down, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            vec![(Key::Down, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![(Key::J, KeyState::PRESSED)]
+        );
+    }
+
+    #[test]
+    fn it_swaps_keys() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_layer_action("nav", &[Key::Esc], Action::Key(Key::Home))
+            .unwrap();
+        mapper.swap(Key::Esc, Key::CapsLock).unwrap();
+
+        // Swaps are tried before layers, even though they're added later.
+        mapper.set_enabled_layers(HashSet::from([String::from("nav")]));
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Esc, KeyState::PRESSED)),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::Esc, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Esc, KeyState::RELEASED)),
+            vec![(Key::CapsLock, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::Esc, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_disables_tap_hold_keys() {
+        let mut mapper = tap_hold_mapper(Decision::Timeout);
+        mapper.disable(Key::F).unwrap();
+
+        assert_eq!(press(&mut mapper, Key::F, 0), vec![]);
+        assert_eq!(mapper.next_deadline(), None);
+        assert_eq!(release(&mut mapper, Key::F, 300), vec![]);
+    }
+
+    #[test]
+    fn it_swaps_combo_keys() {
+        let mut mapper = combo_mapper();
+        mapper.swap(Key::J, Key::Semicolon).unwrap();
+
+        // The swapped key doesn't complete the combo, so K is sent as usual.
+        assert_eq!(press(&mut mapper, Key::K, 0), vec![]);
+        assert_eq!(
+            press(&mut mapper, Key::J, 10),
+            vec![
+                (Key::K, KeyState::PRESSED),
+                (Key::Semicolon, KeyState::PRESSED)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_disables_tap_dance_keys() {
+        let mut mapper = tap_dance_mapper(&[Key::D], None);
+        mapper.disable(Key::D).unwrap();
+
+        assert_eq!(tap(&mut mapper, Key::D, 0), vec![]);
+        assert_eq!(mapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn it_swaps_the_leader_key() {
+        let mut mapper = leader_mapper(false);
+        mapper.swap(Key::RightAlt, Key::RightCtrl).unwrap();
+
+        assert_eq!(
+            tap(&mut mapper, Key::RightAlt, 0),
+            vec![
+                (Key::RightCtrl, KeyState::PRESSED),
+                (Key::RightCtrl, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::G, 100),
+            vec![(Key::G, KeyState::PRESSED), (Key::G, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_maps_key_on_release_even_if_prefix_released() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            vec![(Key::Down, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_handles_prefixes_pressed_after_mapped_key() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![(Key::J, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            vec![(Key::J, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::CapsLock, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_only_maps_layer_mappings_while_enabled() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+        mapper
+            .add_layer_action(
+                "browser",
+                &[Key::CapsLock, Key::J],
+                Action::Key(Key::PageDown),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED)
+            ]
+        );
+
+        mapper.set_enabled_layers(HashSet::from([String::from("browser")]));
+
+        // The key that was already mapped is still released correctly.
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            vec![
+                (Key::Down, KeyState::RELEASED),
+                (Key::CapsLock, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::PageDown, KeyState::PRESSED)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_runs_commands_only_on_press() {
+        let command = exec::Command {
+            args: vec![String::from("foot")],
+            env: vec![],
+            user: None,
+        };
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_action(&[Key::CapsLock, Key::Enter], Action::Exec(command.clone()))
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Enter, KeyState::PRESSED)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Enter, KeyState::AUTOREPEAT)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Enter, KeyState::RELEASED)),
+            vec![]
+        );
+        assert_eq!(mapper.take_commands(), vec![command]);
+        assert_eq!(mapper.take_commands(), vec![]);
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::CapsLock, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_does_not_run_commands_when_a_key_repeats() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_action(
+                &[Key::CapsLock, Key::Enter],
+                Action::Exec(exec::Command {
+                    args: vec![String::from("foot")],
+                    env: vec![],
+                    user: None,
+                }),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::Enter, KeyState::PRESSED));
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Enter, KeyState::AUTOREPEAT)),
+            vec![(Key::Enter, KeyState::AUTOREPEAT)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::Enter, KeyState::RELEASED)),
+            vec![(Key::Enter, KeyState::RELEASED)]
+        );
+        assert_eq!(mapper.take_commands(), vec![]);
+    }
+
+    #[test]
+    fn it_sends_a_combo_when_its_keys_are_pressed_together() {
+        let mut mapper = combo_mapper();
+
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::K, KeyState::PRESSED, 0)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::J, KeyState::PRESSED, 10)),
+            vec![(Key::Esc, KeyState::PRESSED)]
+        );
+        assert_eq!(mapper.next_deadline(), None);
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::J, KeyState::AUTOREPEAT, 300)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::J, KeyState::RELEASED, 310)),
+            vec![(Key::Esc, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::K, KeyState::RELEASED, 320)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn it_sends_held_back_keys_when_a_combo_times_out() {
+        let mut mapper = combo_mapper();
+
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::J, KeyState::PRESSED, 0)),
+            vec![]
+        );
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(30)));
+        assert_eq!(mapper.tick(Duration::from_millis(20)), vec![]);
+        assert_eq!(
+            mapper.tick(Duration::from_millis(30)),
+            vec![(Key::J, KeyState::PRESSED)]
+        );
+
+        // Pressing the other key late doesn't complete the combo.
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::K, KeyState::PRESSED, 40)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::K, KeyState::RELEASED, 50)),
+            vec![(Key::K, KeyState::PRESSED), (Key::K, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_sends_held_back_keys_in_order_when_another_key_is_pressed() {
+        let mut mapper = combo_mapper();
+        mapper.add_mapping(&[Key::J, Key::L], &Key::Right).unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::J, KeyState::PRESSED, 0)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::L, KeyState::PRESSED, 10)),
+            vec![
+                (Key::J, KeyState::PRESSED),
+                (Key::J, KeyState::RELEASED),
+                (Key::Right, KeyState::PRESSED)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_waits_for_bigger_combos() {
+        let mut mapper = combo_mapper();
+        mapper
+            .add_combo(
+                &[Key::J, Key::K, Key::L],
+                Action::Key(Key::Enter),
+                Duration::from_millis(50),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&timed_event(Key::J, KeyState::PRESSED, 0));
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::K, KeyState::PRESSED, 10)),
+            vec![]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::L, KeyState::PRESSED, 20)),
+            vec![(Key::Enter, KeyState::PRESSED)]
+        );
+
+        mapper.handle_key_event(&timed_event(Key::J, KeyState::RELEASED, 100));
+        mapper.handle_key_event(&timed_event(Key::K, KeyState::RELEASED, 100));
+        mapper.handle_key_event(&timed_event(Key::L, KeyState::RELEASED, 100));
+
+        mapper.handle_key_event(&timed_event(Key::J, KeyState::PRESSED, 200));
+        mapper.handle_key_event(&timed_event(Key::K, KeyState::PRESSED, 210));
+        assert_eq!(
+            mapper.tick(Duration::from_millis(250)),
+            vec![(Key::Esc, KeyState::PRESSED)]
+        );
+    }
+
+    fn leader_mapper(replay_unmatched: bool) -> KeyMapper {
+        let mut mapper = KeyMapper::new();
+        mapper.set_leader(Leader {
+            key: Key::RightAlt,
+            timeout: Duration::from_millis(1000),
+            cancel: Some(Key::Esc),
+            replay_unmatched,
+        });
+        mapper
+            .add_sequence(&[Key::G, Key::H], Action::Key(Key::F5))
+            .unwrap();
+        mapper
+    }
+
+    fn tap(mapper: &mut KeyMapper, key: Key, millis: i64) -> Vec<(Key, KeyState)> {
+        let mut keys = mapper.handle_key_event(&timed_event(key, KeyState::PRESSED, millis));
+        keys.append(&mut mapper.handle_key_event(&timed_event(key, KeyState::RELEASED, millis)));
+        keys
+    }
+
+    #[test]
+    fn it_maps_sequences_typed_after_the_leader() {
+        let mut mapper = leader_mapper(false);
+
+        assert_eq!(tap(&mut mapper, Key::RightAlt, 0), vec![]);
+        assert_eq!(tap(&mut mapper, Key::G, 100), vec![]);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(1100)));
+        assert_eq!(
+            tap(&mut mapper, Key::H, 200),
+            vec![(Key::F5, KeyState::PRESSED), (Key::F5, KeyState::RELEASED)]
+        );
+        assert_eq!(mapper.next_deadline(), None);
+        assert_eq!(
+            tap(&mut mapper, Key::H, 300),
+            vec![(Key::H, KeyState::PRESSED), (Key::H, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_replays_sequences_that_do_not_match() {
+        let mut mapper = leader_mapper(true);
+
+        tap(&mut mapper, Key::RightAlt, 0);
+        tap(&mut mapper, Key::G, 100);
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::X, KeyState::PRESSED, 200)),
+            vec![
+                (Key::RightAlt, KeyState::PRESSED),
+                (Key::RightAlt, KeyState::RELEASED),
+                (Key::G, KeyState::PRESSED),
+                (Key::G, KeyState::RELEASED),
+                (Key::X, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::X, KeyState::RELEASED, 300)),
+            vec![(Key::X, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_drops_sequences_that_do_not_match_or_are_cancelled() {
+        let mut mapper = leader_mapper(false);
+
+        tap(&mut mapper, Key::RightAlt, 0);
+        assert_eq!(tap(&mut mapper, Key::X, 100), vec![]);
+
+        tap(&mut mapper, Key::RightAlt, 200);
+        tap(&mut mapper, Key::G, 300);
+        assert_eq!(tap(&mut mapper, Key::Esc, 400), vec![]);
+        assert_eq!(
+            tap(&mut mapper, Key::H, 500),
+            vec![(Key::H, KeyState::PRESSED), (Key::H, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_waits_for_longer_sequences_until_the_timeout() {
+        let mut mapper = leader_mapper(false);
+        mapper
+            .add_sequence(&[Key::G], Action::Key(Key::F1))
+            .unwrap();
+
+        tap(&mut mapper, Key::RightAlt, 0);
+        assert_eq!(tap(&mut mapper, Key::G, 100), vec![]);
+        assert_eq!(mapper.tick(Duration::from_millis(1099)), vec![]);
+        assert_eq!(
+            mapper.tick(Duration::from_millis(1100)),
+            vec![(Key::F1, KeyState::PRESSED), (Key::F1, KeyState::RELEASED)]
+        );
+    }
+
+    fn one_shot_mapper() -> KeyMapper {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_action(
+                &[Key::CapsLock],
+                Action::OneShot(Modifier::Key(Key::LeftShift)),
+            )
+            .unwrap();
+        mapper
+            .add_action(
+                &[Key::RightAlt],
+                Action::OneShot(Modifier::Layer(String::from("nav"))),
+            )
+            .unwrap();
+        mapper
+            .add_layer_action("nav", &[Key::H], Action::Key(Key::Left))
+            .unwrap();
+        mapper
+    }
+
+    #[test]
+    fn it_applies_tapped_one_shot_modifiers_to_the_next_key() {
+        let mut mapper = one_shot_mapper();
+
+        assert_eq!(
+            tap(&mut mapper, Key::CapsLock, 0),
+            vec![(Key::LeftShift, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::A, 100),
+            vec![
+                (Key::A, KeyState::PRESSED),
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::A, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::A, 200),
+            vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
+        );
+
+        assert_eq!(tap(&mut mapper, Key::RightAlt, 300), vec![]);
+        assert_eq!(
+            tap(&mut mapper, Key::H, 400),
+            vec![
+                (Key::Left, KeyState::PRESSED),
+                (Key::Left, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::H, 500),
+            vec![(Key::H, KeyState::PRESSED), (Key::H, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_uses_held_one_shot_modifiers_as_normal_modifiers() {
+        let mut mapper = one_shot_mapper();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
+            vec![(Key::LeftShift, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::A, 0),
+            vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::A, 100),
+            vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_keeps_one_shot_modifiers_held_around_prefix_mappings() {
+        let mut mapper = one_shot_mapper();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+        assert_eq!(
+            tap(&mut mapper, Key::J, 0),
+            vec![
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED),
+                (Key::Down, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            tap(&mut mapper, Key::A, 100),
+            vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_locks_one_shot_modifiers_tapped_twice() {
+        let mut mapper = one_shot_mapper();
+
+        assert_eq!(
+            tap(&mut mapper, Key::CapsLock, 0),
+            vec![(Key::LeftShift, KeyState::PRESSED)]
+        );
+        assert_eq!(tap(&mut mapper, Key::CapsLock, 100), vec![]);
+
+        for millis in [200, 300].iter() {
+            assert_eq!(
+                tap(&mut mapper, Key::A, *millis),
+                vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
+            );
+        }
+
+        assert_eq!(
+            tap(&mut mapper, Key::CapsLock, 400),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+    }
+
+    fn tap_hold_mapper(decision: Decision) -> KeyMapper {
+        let mut mapper = KeyMapper::new();
+        mapper.add_tap_hold(
+            Key::F,
+            TapHold {
+                tap: Key::F,
+                hold: Modifier::Key(Key::LeftCtrl),
+                timeout: Duration::from_millis(200),
+                quick_tap: Duration::from_millis(150),
+                decision,
+            },
+        );
+        mapper
+    }
+
+    fn press(mapper: &mut KeyMapper, key: Key, millis: i64) -> Vec<(Key, KeyState)> {
+        mapper.handle_key_event(&timed_event(key, KeyState::PRESSED, millis))
+    }
+
+    fn release(mapper: &mut KeyMapper, key: Key, millis: i64) -> Vec<(Key, KeyState)> {
+        mapper.handle_key_event(&timed_event(key, KeyState::RELEASED, millis))
+    }
+
+    #[test]
+    fn it_taps_tap_hold_keys_released_before_the_timeout() {
+        let mut mapper = tap_hold_mapper(Decision::Timeout);
+
+        assert_eq!(press(&mut mapper, Key::F, 0), vec![]);
+        assert_eq!(press(&mut mapper, Key::J, 50), vec![]);
+        assert_eq!(release(&mut mapper, Key::J, 80), vec![]);
+        assert_eq!(
+            release(&mut mapper, Key::F, 100),
+            vec![
+                (Key::F, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED),
+                (Key::J, KeyState::RELEASED),
+                (Key::F, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(mapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn it_holds_tap_hold_keys_after_the_timeout() {
+        let mut mapper = tap_hold_mapper(Decision::Timeout);
+
+        assert_eq!(press(&mut mapper, Key::F, 0), vec![]);
+        assert_eq!(press(&mut mapper, Key::J, 50), vec![]);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(200)));
+        assert_eq!(mapper.tick(Duration::from_millis(199)), vec![]);
+        assert_eq!(
+            mapper.tick(Duration::from_millis(200)),
+            vec![
+                (Key::LeftCtrl, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::J, 250),
+            vec![(Key::J, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::F, 300),
+            vec![(Key::LeftCtrl, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_holds_tap_hold_keys_when_another_key_is_tapped_with_permissive_hold() {
+        let mut mapper = tap_hold_mapper(Decision::PermissiveHold);
+
+        assert_eq!(press(&mut mapper, Key::F, 0), vec![]);
+        assert_eq!(press(&mut mapper, Key::J, 50), vec![]);
+        assert_eq!(
+            release(&mut mapper, Key::J, 80),
+            vec![
+                (Key::LeftCtrl, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED),
+                (Key::J, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::F, 100),
+            vec![(Key::LeftCtrl, KeyState::RELEASED)]
+        );
+
+        // Rolling from one key to the next is still typing.
+        assert_eq!(press(&mut mapper, Key::F, 1000), vec![]);
+        assert_eq!(press(&mut mapper, Key::J, 1050), vec![]);
+        assert_eq!(
+            release(&mut mapper, Key::F, 1080),
+            vec![
+                (Key::F, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED),
+                (Key::F, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::J, 1100),
+            vec![(Key::J, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_holds_tap_hold_keys_when_another_key_is_pressed() {
+        let mut mapper = tap_hold_mapper(Decision::HoldOnOtherKeyPress);
+
+        // Keys pressed before aren't held back.
+        assert_eq!(
+            press(&mut mapper, Key::K, 0),
+            vec![(Key::K, KeyState::PRESSED)]
+        );
+        assert_eq!(press(&mut mapper, Key::F, 10), vec![]);
+        assert_eq!(
+            release(&mut mapper, Key::K, 20),
+            vec![(Key::K, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            press(&mut mapper, Key::J, 50),
+            vec![
+                (Key::LeftCtrl, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_taps_tap_hold_keys_pressed_again_quickly() {
+        let mut mapper = tap_hold_mapper(Decision::Timeout);
+
+        assert_eq!(
+            tap(&mut mapper, Key::F, 0),
+            vec![(Key::F, KeyState::PRESSED), (Key::F, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            press(&mut mapper, Key::F, 100),
+            vec![(Key::F, KeyState::PRESSED)]
+        );
+        assert_eq!(
+            mapper.handle_key_event(&timed_event(Key::F, KeyState::AUTOREPEAT, 600)),
+            vec![(Key::F, KeyState::AUTOREPEAT)]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::F, 700),
+            vec![(Key::F, KeyState::RELEASED)]
+        );
+
+        assert_eq!(press(&mut mapper, Key::F, 1000), vec![]);
+    }
+
+    #[test]
+    fn it_only_times_out_when_ticked() {
+        let mut mapper = tap_hold_mapper(Decision::Timeout);
+        mapper
+            .add_combo(
+                &[Key::J, Key::K],
+                Action::Key(Key::Esc),
+                Duration::from_millis(30),
+            )
+            .unwrap();
+
+        assert_eq!(mapper.next_deadline(), None);
+        assert_eq!(mapper.tick(Duration::from_secs(1000)), vec![]);
+
+        // The clock starts wherever the device's does.
+        let start = 1_000_000;
+        assert_eq!(press(&mut mapper, Key::F, start), vec![]);
+        assert_eq!(press(&mut mapper, Key::J, start + 150), vec![]);
+        assert_eq!(
+            mapper.next_deadline(),
+            Some(Duration::from_millis(start as u64 + 200))
+        );
+        assert_eq!(
+            mapper.tick(Duration::from_millis(start as u64 + 199)),
+            vec![]
+        );
+
+        // The held back J starts a combo that has already timed out by its
+        // own timestamp, so it's sent right away too.
+        assert_eq!(
+            mapper.tick(Duration::from_millis(start as u64 + 200)),
+            vec![
+                (Key::LeftCtrl, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(mapper.next_deadline(), None);
+    }
+
+    fn mouse_mapper() -> KeyMapper {
+        let mut mapper = KeyMapper::new();
+        mapper.set_mouse(MouseSettings {
+            interval: Duration::from_millis(10),
+            speed: 1.0,
+            max_speed: 11.0,
+            acceleration: Duration::from_millis(100),
+            curve: 1.0,
+            scroll_interval: Duration::from_millis(50),
+        });
+        mapper
+            .add_action(
+                &[Key::CapsLock, Key::H],
+                Action::Mouse(MouseAction::parse("left").unwrap()),
+            )
+            .unwrap();
+        mapper
+            .add_action(
+                &[Key::CapsLock, Key::K],
+                Action::Mouse(MouseAction::parse("up").unwrap()),
+            )
+            .unwrap();
+        mapper
+            .add_action(
+                &[Key::CapsLock, Key::J],
+                Action::Mouse(MouseAction::parse("scroll-down").unwrap()),
+            )
+            .unwrap();
+        mapper
     }
-}
 
-fn update_pressed_keys(pressed_keys: &mut HashSet<Key>, event: &KeyEvent) {
-    match event.value {
-        KeyState::PRESSED | KeyState::AUTOREPEAT => {
-            pressed_keys.insert(event.key);
-        }
-        KeyState::RELEASED => {
-            pressed_keys.remove(&event.key);
+    fn movement(x: i32, y: i32) -> Movement {
+        Movement {
+            x,
+            y,
+            ..Movement::default()
         }
-        _ => (),
     }
-}
-
-fn synthetic_event(key: Key, value: KeyState) -> KeyEvent {
-    KeyEvent::new(EventTime::new(0, 0), key, value)
-}
 
-#[derive(Debug)]
-pub enum Error {
-    EmptyMappingError,
-}
+    #[test]
+    fn it_moves_the_pointer_faster_while_mouse_keys_are_held() {
+        let mut mapper = mouse_mapper();
 
-#[cfg(test)]
-mod tests {
-    use super::{synthetic_event, KeyMapper};
-    use input_linux::{Key, KeyState};
+        assert_eq!(
+            press(&mut mapper, Key::CapsLock, 0),
+            vec![(Key::CapsLock, KeyState::PRESSED)]
+        );
+        assert_eq!(press(&mut mapper, Key::H, 0), vec![]);
+        assert_eq!(mapper.take_movements(), vec![movement(-1, 0)]);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(10)));
 
-    #[test]
-    fn it_returns_same_key_if_no_mappings() {
-        let mut mapper = KeyMapper::new();
+        mapper.tick(Duration::from_millis(5));
+        assert_eq!(mapper.take_movements(), vec![]);
 
+        mapper.tick(Duration::from_millis(50));
+        assert_eq!(press(&mut mapper, Key::K, 50), vec![]);
+        mapper.tick(Duration::from_millis(60));
+        mapper.tick(Duration::from_millis(200));
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
-            vec![(Key::J, KeyState::PRESSED)]
+            mapper.take_movements(),
+            vec![movement(-6, 0), movement(-7, -7), movement(-11, -11)]
         );
+
+        assert_eq!(release(&mut mapper, Key::H, 210), vec![]);
+        assert_eq!(release(&mut mapper, Key::K, 210), vec![]);
+        assert_eq!(mapper.next_deadline(), None);
     }
 
     #[test]
-    fn it_returns_same_key_if_no_mappings_match() {
+    fn it_scrolls_while_mouse_keys_are_held() {
+        let mut mapper = mouse_mapper();
+
+        press(&mut mapper, Key::CapsLock, 0);
+        press(&mut mapper, Key::J, 0);
+        mapper.tick(Duration::from_millis(40));
+        mapper.tick(Duration::from_millis(50));
+        release(&mut mapper, Key::J, 60);
+
+        let scroll_down = Movement {
+            wheel: -1,
+            ..Movement::default()
+        };
+        assert_eq!(mapper.take_movements(), vec![scroll_down, scroll_down]);
+        assert_eq!(mapper.next_deadline(), None);
+    }
+
+    #[test]
+    fn it_only_uses_a_pointer_for_mouse_actions_and_buttons() {
         let mut mapper = KeyMapper::new();
-        mapper.add_mapping(&[Key::CapsLock], &Key::J).unwrap();
+        mapper.add_mapping(&[Key::CapsLock], &Key::Esc).unwrap();
+        assert!(!mapper.uses_pointer());
 
-        assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
-            vec![(Key::J, KeyState::PRESSED)]
-        );
+        mapper
+            .add_sequence(&[Key::M], Action::Key(Key::ButtonMiddle))
+            .unwrap();
+        assert!(mapper.uses_pointer());
+
+        assert!(mouse_mapper().uses_pointer());
     }
 
     #[test]
-    fn it_maps_a_single_key() {
+    fn it_types_text_without_held_prefixes() {
         let mut mapper = KeyMapper::new();
         mapper
             .add_mapping(&[Key::CapsLock], &Key::LeftCtrl)
             .unwrap();
+        mapper
+            .add_action(&[Key::CapsLock, Key::E], Action::Text(String::from("é")))
+            .unwrap();
 
+        press(&mut mapper, Key::CapsLock, 0);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
-            vec![(Key::LeftCtrl, KeyState::PRESSED)]
+            press(&mut mapper, Key::E, 10),
+            vec![
+                (Key::LeftCtrl, KeyState::RELEASED),
+                (Key::LeftCtrl, KeyState::PRESSED),
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::U, KeyState::PRESSED),
+                (Key::U, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::LeftCtrl, KeyState::RELEASED),
+                (Key::E, KeyState::PRESSED),
+                (Key::E, KeyState::RELEASED),
+                (Key::Num9, KeyState::PRESSED),
+                (Key::Num9, KeyState::RELEASED),
+                (Key::Space, KeyState::PRESSED),
+                (Key::Space, KeyState::RELEASED),
+            ]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::LeftCtrl, KeyState::RELEASED)),
-            vec![(Key::LeftCtrl, KeyState::RELEASED)]
+            mapper.handle_key_event(&timed_event(Key::E, KeyState::AUTOREPEAT, 20)),
+            vec![]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::E, 30),
+            vec![(Key::LeftCtrl, KeyState::PRESSED)]
         );
     }
 
-    #[test]
-    fn it_maps_a_sequence() {
+    fn tap_dance_mapper(keys: &[Key], hold: Option<Modifier>) -> KeyMapper {
         let mut mapper = KeyMapper::new();
         mapper
-            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .add_action(
+                keys,
+                Action::TapDance(TapDance {
+                    taps: vec![Key::X, Key::Y, Key::Z],
+                    hold,
+                    term: Duration::from_millis(200),
+                }),
+            )
             .unwrap();
+        mapper
+    }
+
+    #[test]
+    fn it_taps_tap_dance_keys_for_how_often_they_are_tapped() {
+        let mut mapper = tap_dance_mapper(&[Key::D], Some(Modifier::Key(Key::LeftShift)));
 
+        assert_eq!(press(&mut mapper, Key::D, 0), vec![]);
+        assert_eq!(release(&mut mapper, Key::D, 50), vec![]);
+        assert_eq!(press(&mut mapper, Key::D, 100), vec![]);
+        assert_eq!(release(&mut mapper, Key::D, 150), vec![]);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(350)));
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
-            vec![(Key::CapsLock, KeyState::PRESSED)]
+            mapper.tick(Duration::from_millis(350)),
+            vec![(Key::Y, KeyState::PRESSED), (Key::Y, KeyState::RELEASED)]
         );
+
+        // The dance ends as soon as the last key is tapped.
+        for millis in [400, 450].iter() {
+            tap(&mut mapper, Key::D, *millis);
+        }
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            tap(&mut mapper, Key::D, 500),
+            vec![(Key::Z, KeyState::PRESSED), (Key::Z, KeyState::RELEASED)]
+        );
+        assert_eq!(mapper.next_deadline(), None);
+
+        // Held when another key is pressed.
+        press(&mut mapper, Key::D, 1000);
+        assert_eq!(
+            press(&mut mapper, Key::A, 1050),
             vec![
-                (Key::CapsLock, KeyState::RELEASED),
-                (Key::Down, KeyState::PRESSED)
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::A, KeyState::PRESSED)
             ]
         );
+        release(&mut mapper, Key::A, 1100);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            release(&mut mapper, Key::D, 1150),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_presses_prefixes_again_after_interrupted_tap_dances() {
+        let mut mapper = tap_dance_mapper(&[Key::LeftCtrl, Key::D], None);
+
+        press(&mut mapper, Key::LeftCtrl, 0);
+        tap(&mut mapper, Key::D, 10);
+        assert_eq!(press(&mut mapper, Key::D, 30), vec![]);
+        assert_eq!(
+            press(&mut mapper, Key::J, 50),
             vec![
-                (Key::Down, KeyState::RELEASED),
-                (Key::CapsLock, KeyState::PRESSED)
+                (Key::LeftCtrl, KeyState::RELEASED),
+                (Key::Y, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED)
             ]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
-            vec![(Key::CapsLock, KeyState::RELEASED)]
+            release(&mut mapper, Key::D, 60),
+            vec![
+                (Key::Y, KeyState::RELEASED),
+                (Key::LeftCtrl, KeyState::PRESSED)
+            ]
+        );
+        assert_eq!(
+            release(&mut mapper, Key::J, 70),
+            vec![(Key::J, KeyState::RELEASED)]
+        );
+
+        // Prefixes released during the dance aren't pressed again.
+        tap(&mut mapper, Key::D, 100);
+        assert_eq!(
+            release(&mut mapper, Key::LeftCtrl, 120),
+            vec![(Key::LeftCtrl, KeyState::RELEASED)]
+        );
+        assert_eq!(
+            mapper.tick(Duration::from_millis(400)),
+            vec![(Key::X, KeyState::PRESSED), (Key::X, KeyState::RELEASED)]
         );
     }
 
-    #[test]
-    fn it_maps_a_sequence_with_more_than_one_prefix() {
+    fn auto_shift_mapper(repeat: bool) -> KeyMapper {
         let mut mapper = KeyMapper::new();
         mapper
-            .add_mapping(&[Key::CapsLock, Key::LeftShift, Key::J], &Key::Down)
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
             .unwrap();
+        mapper.set_auto_shift(AutoShift {
+            keys: [Key::A, Key::J].iter().copied().collect(),
+            timeout: Duration::from_millis(150),
+            repeat,
+        });
+        mapper
+    }
 
+    #[test]
+    fn it_shifts_auto_shift_keys_held_past_the_timeout() {
+        let mut mapper = auto_shift_mapper(false);
+
+        assert_eq!(press(&mut mapper, Key::A, 0), vec![]);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
-            vec![(Key::CapsLock, KeyState::PRESSED)]
+            release(&mut mapper, Key::A, 50),
+            vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
         );
+
+        press(&mut mapper, Key::A, 100);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(250)));
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::PRESSED)),
-            vec![(Key::LeftShift, KeyState::PRESSED)]
+            mapper.tick(Duration::from_millis(250)),
+            vec![
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::A, KeyState::PRESSED),
+                (Key::A, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED)
+            ]
         );
+        assert_eq!(release(&mut mapper, Key::A, 300), vec![]);
+
+        // Keys that are mapped aren't held back.
+        press(&mut mapper, Key::CapsLock, 400);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            press(&mut mapper, Key::J, 410),
             vec![
                 (Key::CapsLock, KeyState::RELEASED),
-                (Key::LeftShift, KeyState::RELEASED),
                 (Key::Down, KeyState::PRESSED)
             ]
         );
+    }
+
+    #[test]
+    fn it_repeats_shifted_auto_shift_keys() {
+        let mut mapper = auto_shift_mapper(true);
+
+        press(&mut mapper, Key::J, 0);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
+            mapper.tick(Duration::from_millis(150)),
             vec![
-                (Key::Down, KeyState::RELEASED),
                 (Key::LeftShift, KeyState::PRESSED),
-                (Key::CapsLock, KeyState::PRESSED),
+                (Key::J, KeyState::PRESSED)
             ]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::RELEASED)),
-            vec![(Key::LeftShift, KeyState::RELEASED)]
+            mapper.handle_key_event(&timed_event(Key::J, KeyState::AUTOREPEAT, 400)),
+            vec![(Key::J, KeyState::AUTOREPEAT)]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
-            vec![(Key::CapsLock, KeyState::RELEASED)]
+            release(&mut mapper, Key::J, 450),
+            vec![
+                (Key::J, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED)
+            ]
         );
     }
 
-    #[test]
-    fn it_maps_key_on_release_even_if_prefix_released() {
+    fn caps_word_mapper() -> KeyMapper {
         let mut mapper = KeyMapper::new();
         mapper
-            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .add_action(&[Key::CapsLock], Action::CapsWord)
             .unwrap();
+        mapper.set_caps_word(CapsWord {
+            timeout: Some(Duration::from_millis(1000)),
+            underscore: true,
+        });
+        mapper
+    }
+
+    #[test]
+    fn it_shifts_letters_until_the_end_of_a_caps_word() {
+        let mut mapper = caps_word_mapper();
 
+        assert_eq!(tap(&mut mapper, Key::CapsLock, 0), vec![]);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
-            vec![(Key::CapsLock, KeyState::PRESSED)]
+            press(&mut mapper, Key::A, 10),
+            vec![
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::A, KeyState::PRESSED)
+            ]
         );
+        release(&mut mapper, Key::A, 15);
+
+        // Shift is still held, so `-` types `_`.
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            tap(&mut mapper, Key::Minus, 20),
             vec![
-                (Key::CapsLock, KeyState::RELEASED),
-                (Key::Down, KeyState::PRESSED)
+                (Key::Minus, KeyState::PRESSED),
+                (Key::Minus, KeyState::RELEASED)
             ]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
-            vec![]
+            tap(&mut mapper, Key::Num1, 30),
+            vec![
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::Num1, KeyState::PRESSED),
+                (Key::Num1, KeyState::RELEASED)
+            ]
         );
+        tap(&mut mapper, Key::B, 40);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
-            vec![(Key::Down, KeyState::RELEASED)]
+            tap(&mut mapper, Key::Space, 50),
+            vec![
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::Space, KeyState::PRESSED),
+                (Key::Space, KeyState::RELEASED)
+            ]
         );
+        assert_eq!(
+            tap(&mut mapper, Key::C, 60),
+            vec![(Key::C, KeyState::PRESSED), (Key::C, KeyState::RELEASED)]
+        );
+        assert_eq!(mapper.next_deadline(), None);
     }
 
     #[test]
-    fn it_handles_prefixes_pressed_after_mapped_key() {
+    fn it_leaves_mouse_buttons_out_of_caps_word_and_auto_shift() {
+        let mut mapper = caps_word_mapper();
+
+        tap(&mut mapper, Key::CapsLock, 0);
+        assert_eq!(
+            tap(&mut mapper, Key::ButtonLeft, 10),
+            vec![
+                (Key::ButtonLeft, KeyState::PRESSED),
+                (Key::ButtonLeft, KeyState::RELEASED)
+            ]
+        );
+        assert_eq!(
+            press(&mut mapper, Key::A, 20),
+            vec![
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::A, KeyState::PRESSED)
+            ]
+        );
+
         let mut mapper = KeyMapper::new();
-        mapper
-            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
-            .unwrap();
+        mapper.set_auto_shift(AutoShift {
+            keys: [Key::ButtonLeft].iter().copied().collect(),
+            timeout: Duration::from_millis(150),
+            repeat: false,
+        });
+        assert_eq!(
+            press(&mut mapper, Key::ButtonLeft, 0),
+            vec![(Key::ButtonLeft, KeyState::PRESSED)]
+        );
+        assert_eq!(mapper.next_deadline(), None);
+    }
 
+    #[test]
+    fn it_turns_caps_word_off_after_the_timeout() {
+        let mut mapper = caps_word_mapper();
+
+        tap(&mut mapper, Key::CapsLock, 0);
+        tap(&mut mapper, Key::A, 500);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(1500)));
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
-            vec![(Key::J, KeyState::PRESSED)]
+            mapper.tick(Duration::from_millis(1500)),
+            vec![(Key::LeftShift, KeyState::RELEASED)]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED)),
-            vec![(Key::CapsLock, KeyState::PRESSED)]
+            tap(&mut mapper, Key::A, 1600),
+            vec![(Key::A, KeyState::PRESSED), (Key::A, KeyState::RELEASED)]
+        );
+    }
+
+    #[test]
+    fn it_repeats_the_last_key_held() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_mapping(&[Key::CapsLock, Key::J], &Key::Down)
+            .unwrap();
+        mapper.set_repeat(
+            Repeat {
+                delay: Duration::from_millis(250),
+                period: Duration::from_millis(30),
+            },
+            &[Key::Space],
         );
+
+        press(&mut mapper, Key::CapsLock, 0);
+        press(&mut mapper, Key::J, 10);
+        assert_eq!(mapper.next_deadline(), Some(Duration::from_millis(260)));
+        assert_eq!(mapper.tick(Duration::from_millis(200)), vec![]);
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED)),
-            vec![(Key::J, KeyState::RELEASED)]
+            mapper.tick(Duration::from_millis(260)),
+            vec![(Key::Down, KeyState::AUTOREPEAT)]
         );
         assert_eq!(
-            mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::RELEASED)),
-            vec![(Key::CapsLock, KeyState::RELEASED)]
+            mapper.tick(Duration::from_millis(290)),
+            vec![(Key::Down, KeyState::AUTOREPEAT)]
         );
+        release(&mut mapper, Key::J, 300);
+        assert_eq!(mapper.next_deadline(), None);
+
+        // Modifiers and excluded keys don't repeat.
+        press(&mut mapper, Key::LeftShift, 400);
+        press(&mut mapper, Key::Space, 400);
+        assert_eq!(mapper.next_deadline(), None);
     }
 
     #[test]
@@ -342,4 +3535,114 @@ mod tests {
             vec![(Key::LeftCtrl, KeyState::RELEASED),]
         );
     }
+
+    #[test]
+    fn it_skips_mappings_while_unless_keys_are_held() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_conditional_action(
+                None,
+                &[Key::CapsLock, Key::J],
+                Conditions {
+                    any: vec![],
+                    unless: vec![Key::LeftShift, Key::RightShift],
+                },
+                HeldModifiers::Keep,
+                Action::Key(Key::Down),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::Down, KeyState::PRESSED)
+            ]
+        );
+        mapper.handle_key_event(&synthetic_event(Key::J, KeyState::RELEASED));
+
+        mapper.handle_key_event(&synthetic_event(Key::RightShift, KeyState::PRESSED));
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![(Key::J, KeyState::PRESSED)]
+        );
+    }
+
+    #[test]
+    fn it_maps_keys_with_any_key_of_a_group_held() {
+        let mut mapper = KeyMapper::new();
+        let either_alt = Conditions {
+            any: vec![vec![Key::LeftAlt, Key::RightAlt]],
+            unless: vec![],
+        };
+        mapper
+            .add_conditional_action(
+                None,
+                &[Key::H],
+                either_alt,
+                HeldModifiers::Keep,
+                Action::Key(Key::Left),
+            )
+            .unwrap();
+
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::H, KeyState::PRESSED)),
+            vec![(Key::H, KeyState::PRESSED)]
+        );
+        mapper.handle_key_event(&synthetic_event(Key::H, KeyState::RELEASED));
+
+        for alt in [Key::LeftAlt, Key::RightAlt] {
+            mapper.handle_key_event(&synthetic_event(alt, KeyState::PRESSED));
+            assert_eq!(
+                mapper.handle_key_event(&synthetic_event(Key::H, KeyState::PRESSED)),
+                vec![(alt, KeyState::RELEASED), (Key::Left, KeyState::PRESSED)]
+            );
+            assert_eq!(
+                mapper.handle_key_event(&synthetic_event(Key::H, KeyState::RELEASED)),
+                vec![(Key::Left, KeyState::RELEASED), (alt, KeyState::PRESSED)]
+            );
+            mapper.handle_key_event(&synthetic_event(alt, KeyState::RELEASED));
+        }
+    }
+
+    #[test]
+    fn conditional_mappings_fall_back_to_other_mappings() {
+        let mut mapper = KeyMapper::new();
+        mapper
+            .add_conditional_action(
+                None,
+                &[Key::CapsLock, Key::J],
+                Conditions {
+                    any: vec![],
+                    unless: vec![Key::LeftShift],
+                },
+                HeldModifiers::Keep,
+                Action::Key(Key::Down),
+            )
+            .unwrap();
+        mapper
+            .add_conditional_action(
+                None,
+                &[Key::CapsLock, Key::J],
+                Conditions {
+                    any: vec![vec![Key::LeftShift, Key::RightShift]],
+                    unless: vec![],
+                },
+                HeldModifiers::Keep,
+                Action::Key(Key::PageDown),
+            )
+            .unwrap();
+
+        mapper.handle_key_event(&synthetic_event(Key::CapsLock, KeyState::PRESSED));
+        mapper.handle_key_event(&synthetic_event(Key::LeftShift, KeyState::PRESSED));
+        assert_eq!(
+            mapper.handle_key_event(&synthetic_event(Key::J, KeyState::PRESSED)),
+            vec![
+                (Key::CapsLock, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::PageDown, KeyState::PRESSED)
+            ]
+        );
+    }
 }
@@ -0,0 +1,67 @@
+use input_linux::Key;
+
+/// Short names for keys that are awkward to spell out in full.
+const ALIASES: &[(&str, Key)] = &[
+    ("caps", Key::CapsLock),
+    ("ctrl", Key::LeftCtrl),
+    ("lctrl", Key::LeftCtrl),
+    ("rctrl", Key::RightCtrl),
+    ("shift", Key::LeftShift),
+    ("lshift", Key::LeftShift),
+    ("rshift", Key::RightShift),
+    ("alt", Key::LeftAlt),
+    ("lalt", Key::LeftAlt),
+    ("ralt", Key::RightAlt),
+    ("altgr", Key::RightAlt),
+    ("meta", Key::LeftMeta),
+    ("lmeta", Key::LeftMeta),
+    ("rmeta", Key::RightMeta),
+    ("super", Key::LeftMeta),
+    ("escape", Key::Esc),
+    ("return", Key::Enter),
+    ("del", Key::Delete),
+    ("ins", Key::Insert),
+    ("pgup", Key::PageUp),
+    ("pgdown", Key::PageDown),
+    ("0", Key::Num0),
+    ("1", Key::Num1),
+    ("2", Key::Num2),
+    ("3", Key::Num3),
+    ("4", Key::Num4),
+    ("5", Key::Num5),
+    ("6", Key::Num6),
+    ("7", Key::Num7),
+    ("8", Key::Num8),
+    ("9", Key::Num9),
+];
+
+/// Looks up a key by name. Names are matched case-insensitively against the
+/// variants of `Key`, along with a few common aliases like `caps` or `ctrl`.
+pub fn parse(name: &str) -> Option<Key> {
+    let name = name.trim();
+
+    ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map(|(_, key)| *key)
+        .or_else(|| Key::iter().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name)))
+}
+
+/// Parses a list of key names joined by `+`, like `caps+j`.
+pub fn parse_combination(names: &str) -> Result<Vec<Key>, String> {
+    names
+        .split('+')
+        .map(|name| parse(name).ok_or_else(|| name.trim().to_owned()))
+        .collect()
+}
+
+pub fn name(key: Key) -> String {
+    format!("{:?}", key)
+}
+
+pub fn join(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| name(*key))
+        .collect::<Vec<_>>()
+        .join("+")
+}
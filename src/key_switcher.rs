@@ -1,18 +1,28 @@
 use crate::{
-    device,
-    key_mapper::{self, KeyMapper},
+    accessibility::{Accessibility, Feature},
+    config::{self, Config, ContextRule},
+    control::{Command, Context, ControlSocket},
+    debounce::Debouncer,
+    device::Device,
+    exec::{Children, User},
+    key_mapper::{self, KeyMapper, Repeat},
+    notify::Notifier,
+    privileges,
 };
 use input_linux::{
-    EvdevHandle, Event, EventKind, EventTime, InputEvent, InputId, Key, KeyEvent, SynchronizeEvent,
-    UInputHandle,
+    AutorepeatEvent, AutorepeatKind, EvdevHandle, Event, EventKind, EventTime, InputEvent, InputId,
+    Key, KeyEvent, KeyState, RelativeAxis, RelativeEvent, SynchronizeEvent, UInputHandle,
 };
 use libc::{input_event, timeval};
 use std::{
+    collections::HashSet,
     convert::{From, TryInto},
     fmt::Debug,
     fs::{self, File},
     io,
-    time::{SystemTime, SystemTimeError},
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
+    time::Duration,
 };
 
 /// Taken from <linux/input.h>
@@ -23,85 +33,637 @@ const VENDOR: u16 = 0x3232;
 const VERSION: u16 = 0x1234;
 const PRODUCT: u16 = 0x5678;
 
+const POINTER_PRODUCT: u16 = 0x5679;
+
+const OUTPUT_NAME: &str = "Keyswitcher Virtual Input";
+const POINTER_NAME: &str = "Keyswitcher Virtual Pointer";
+
 pub struct KeySwitcher {
-    input_device: EvdevHandle<File>,
-    output_device: UInputHandle<File>,
+    inputs: Vec<Input>,
+
+    outputs: Vec<Output>,
+
+    control: Option<ControlSocket>,
+    context_rules: Vec<ContextRule>,
+
+    /// Commands started by mappings that haven't exited yet.
+    children: Children,
+
+    /// Set when running as a systemd service.
+    notifier: Option<Notifier>,
+
+    /// Set when keys are repeated by the kernel or the mappers, instead of by
+    /// the keyboards.
+    drop_repeats: bool,
+}
+
+/// The virtual devices created for an output name.
+struct Output {
+    name: Option<String>,
+    keyboard: UInputHandle<File>,
+
+    /// Mouse buttons and motion go out on their own device, so they're seen
+    /// as coming from a mouse. It's only created for outputs that send them.
+    pointer: Option<UInputHandle<File>>,
+}
+
+/// A grabbed device, and the mappings that apply to it.
+struct Input {
+    name: String,
+    device: EvdevHandle<File>,
+    debouncer: Debouncer,
+    accessibility: Accessibility,
     key_mapper: KeyMapper,
+
+    /// Index into `KeySwitcher::outputs`.
+    output: usize,
+
+    /// Motion from a pointing device since its last report, which is passed
+    /// through as it is.
+    motion: Vec<RelativeEvent>,
 }
 
 impl KeySwitcher {
-    pub fn new(device: device::Device) -> Result<Self, Error> {
-        let input_device = EvdevHandle::from(device);
-        input_device.grab(true)?;
-
-        let uinput = fs::OpenOptions::new().write(true).open("/dev/uinput")?;
-        let output_device = UInputHandle::new(uinput);
-
-        output_device.set_evbit(EventKind::Key)?;
-        output_device.set_evbit(EventKind::Synchronize)?;
-
-        for key in Key::iter() {
-            output_device.set_keybit(key)?;
-        }
-
-        output_device.create(
-            &InputId {
-                bustype: BUS_USB,
-                vendor: VENDOR,
-                product: PRODUCT,
-                version: VERSION,
-            },
-            "Keyswitcher Virtual Input".as_bytes(),
-            0,
-            &[],
-        )?;
-
-        Ok(Self {
-            input_device,
-            output_device,
-            key_mapper: test_key_mapper()?,
-        })
+    pub fn new(devices: Vec<Device>, config: &Config) -> Result<Self, Error> {
+        let mut inputs = Vec::new();
+        let mut outputs: Vec<Output> = Vec::new();
+
+        // The kernel repeats keys on the virtual devices, unless keyswitch
+        // repeats them itself.
+        let kernel_repeat = config
+            .repeat()
+            .filter(|repeat| !repeat.software)
+            .map(|repeat| repeat.repeat);
+
+        for device in devices {
+            let device_config = config.device_config(&device);
+            let key_mapper = config.key_mapper(device_config)?;
+            let output_name = device_config.and_then(|device_config| device_config.output.clone());
+            let name = String::from(device.name());
+
+            let input_device = EvdevHandle::from(device);
+            input_device.grab(true)?;
+
+            // Mappers time combos and other timeouts by event timestamps, which
+            // shouldn't jump when the system time is changed.
+            input_device.set_clock_id(libc::CLOCK_MONOTONIC)?;
+
+            let needs_pointer = key_mapper.uses_pointer() || is_pointing_device(&input_device)?;
+            let output = match outputs.iter().position(|output| output.name == output_name) {
+                Some(output) => output,
+                None => {
+                    outputs.push(Output {
+                        keyboard: create_keyboard_device(output_name.as_deref(), kernel_repeat)?,
+                        pointer: None,
+                        name: output_name,
+                    });
+                    outputs.len() - 1
+                }
+            };
+
+            if needs_pointer && outputs[output].pointer.is_none() {
+                let pointer = create_pointer_device(outputs[output].name.as_deref())?;
+                outputs[output].pointer = Some(pointer);
+            }
+
+            inputs.push(Input {
+                name,
+                device: input_device,
+                debouncer: config.debouncer(),
+                accessibility: config.accessibility(),
+                key_mapper,
+                output,
+                motion: Vec::new(),
+            });
+        }
+
+        let control = match config.control() {
+            Some(control) => Some(
+                ControlSocket::bind(&control.socket, control.mode)
+                    .map_err(|error| Error::ControlSocketError(control.socket.clone(), error))?,
+            ),
+            None => None,
+        };
+
+        let mut key_switcher = Self {
+            inputs,
+            outputs,
+            control,
+            context_rules: config.context_rules().to_vec(),
+            children: Children::default(),
+            notifier: Notifier::from_env(),
+            drop_repeats: config.repeat().is_some(),
+        };
+        key_switcher.set_context(Context::new());
+
+        Ok(key_switcher)
+    }
+
+    /// Gives up root once every device is open. The control socket is handed
+    /// to the user too, so they can connect to it.
+    pub fn drop_privileges(&self, user: &User) -> Result<(), Error> {
+        if let Some(control) = &self.control {
+            control.set_owner(user.uid, user.gid)?;
+        }
+
+        privileges::drop_to(user).map_err(Error::PrivilegesError)
     }
 
+    /// Tells systemd keyswitch is ready first, so it isn't ready before
+    /// privileges are dropped.
     pub fn run(&mut self) -> Result<(), Error> {
+        if let Some(notifier) = &self.notifier {
+            // Not being able to reach systemd shouldn't stop keyswitch.
+            let _ = notifier.ready();
+        }
+
         loop {
-            // Initialize empty input_event buffer
-            let mut raw_events = [EMPTY_INPUT_EVENT; 24];
+            let mut fds = self
+                .inputs
+                .iter()
+                .map(|input| input.device.as_raw_fd())
+                .collect::<Vec<_>>();
+            if let Some(control) = &self.control {
+                fds.extend(control.fds());
+            }
+
+            // Wake up once in a while to clean up after commands that have
+            // exited, to ping the watchdog, and when debouncers, accessibility
+            // features or mappers have something that times out, even if no
+            // keys are pressed.
+            let now = current_time();
+            let timeout = [
+                Some(Duration::from_secs(1)).filter(|_| !self.children.is_empty()),
+                self.notifier.as_ref().and_then(Notifier::watchdog_timeout),
+            ]
+            .iter()
+            .copied()
+            .chain(self.inputs.iter().map(|input| {
+                input
+                    .debouncer
+                    .next_deadline()
+                    .into_iter()
+                    .chain(input.accessibility.next_deadline())
+                    .chain(input.key_mapper.next_deadline())
+                    .min()
+                    .map(|deadline| deadline.saturating_sub(now))
+            }))
+            .flatten()
+            .min()
+            .map_or(-1, |timeout| {
+                // Rounded up, so whatever is due is due by the time poll
+                // returns.
+                timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            });
+            let ready = poll(&fds, timeout)?;
+
+            let (ready_inputs, ready_control) = ready.split_at(self.inputs.len());
+
+            for (index, _) in ready_inputs.iter().enumerate().filter(|(_, ready)| **ready) {
+                // Errors and hangups are reported by reading the device.
+                self.read_events(index)?;
+            }
+
+            let now = current_time();
+            for index in 0..self.inputs.len() {
+                let events = self.inputs[index].debouncer.tick(now);
+                self.map_keys(index, events)?;
+
+                let events = self.inputs[index].accessibility.tick(now);
+                self.handle_keys(index, events)?;
+
+                let keys = self.inputs[index].key_mapper.tick(now);
+                self.send_keys(index, keys)?;
+            }
+
+            if ready_control.iter().any(|ready| *ready) {
+                let commands = self
+                    .control
+                    .as_mut()
+                    .map(|control| control.receive())
+                    .unwrap_or_default();
+
+                for command in commands {
+                    self.handle_command(command)?;
+                }
+            }
 
-            let len = self.input_device.read(&mut raw_events)?;
+            self.children.reap();
 
-            for raw_event in raw_events.iter().take(len) {
-                let event = InputEvent::from_raw(raw_event)?.to_owned();
-                self.handle_event(event)?;
+            if let Some(notifier) = &mut self.notifier {
+                let _ = notifier.watchdog();
             }
         }
     }
 
-    fn handle_event(&mut self, event: InputEvent) -> Result<(), Error> {
-        if let Ok(Event::Key(key_event)) = Event::new(event) {
-            for (mapped_key, state) in self.key_mapper.handle_key_event(&key_event) {
-                let events: [input_event; 2] = [
-                    InputEvent::from(KeyEvent::new(get_timestamp()?, mapped_key, state))
-                        .as_raw()
-                        .to_owned(),
-                    InputEvent::from(SynchronizeEvent::report(get_timestamp()?))
-                        .as_raw()
-                        .to_owned(),
-                ];
+    fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+        match command {
+            Command::SetContext(context) => self.set_context(context),
+            Command::SetFeature(feature, enabled) => {
+                let enabled = enabled.unwrap_or_else(|| !self.is_enabled(feature));
+                self.set_feature(feature, enabled)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether an accessibility feature is on. It's on or off for every device
+    /// at once, so the first one is asked.
+    fn is_enabled(&self, feature: Feature) -> bool {
+        self.inputs
+            .first()
+            .is_some_and(|input| input.accessibility.is_enabled(feature))
+    }
 
-                self.output_device.write(&events)?;
+    fn set_feature(&mut self, feature: Feature, enabled: bool) -> Result<(), Error> {
+        eprintln!("Turned {} {}.", feature, if enabled { "on" } else { "off" });
+
+        for index in 0..self.inputs.len() {
+            let events = self.inputs[index]
+                .accessibility
+                .set_enabled(feature, enabled);
+            self.handle_keys(index, events)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_context(&mut self, context: Context) {
+        let layers = config::context_layers(&self.context_rules, &context);
+
+        if let Some(notifier) = &self.notifier {
+            let _ = notifier.status(&self.status(&layers));
+        }
+
+        for input in self.inputs.iter_mut() {
+            input.key_mapper.set_enabled_layers(layers.clone());
+        }
+    }
+
+    /// Describes what's being mapped, like `Mapping daskeyboard with layers
+    /// browser`.
+    fn status(&self, layers: &HashSet<String>) -> String {
+        let names = self
+            .inputs
+            .iter()
+            .map(|input| input.name.as_str())
+            .collect::<Vec<_>>();
+        let mut status = format!("Mapping {}", names.join(", "));
+
+        if !layers.is_empty() {
+            let mut layers = layers.iter().map(String::as_str).collect::<Vec<_>>();
+            layers.sort_unstable();
+            status.push_str(&format!(" with layers {}", layers.join(", ")));
+        }
+
+        status
+    }
+
+    fn read_events(&mut self, index: usize) -> Result<(), Error> {
+        // Initialize empty input_event buffer
+        let mut raw_events = [EMPTY_INPUT_EVENT; 24];
+
+        let len = self.inputs[index].device.read(&mut raw_events)?;
+
+        for raw_event in raw_events.iter().take(len) {
+            let event = InputEvent::from_raw(raw_event)?.to_owned();
+            self.handle_event(index, event)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_event(&mut self, index: usize, event: InputEvent) -> Result<(), Error> {
+        match Event::new(event) {
+            Ok(Event::Key(key_event))
+                if self.drop_repeats && key_event.value == KeyState::AUTOREPEAT => {}
+            // Mouse buttons are keys too, so they're mapped like any other.
+            Ok(Event::Key(key_event)) => {
+                let events = self.inputs[index].debouncer.filter(&key_event);
+                self.map_keys(index, events)?;
             }
+            Ok(Event::Relative(relative_event)) => self.inputs[index].motion.push(relative_event),
+            Ok(Event::Synchronize(_)) => self.send_motion(index)?,
+            _ => (),
         }
 
         Ok(())
     }
+
+    /// Maps key events that made it through the debouncer and accessibility
+    /// features, and reports any chatter that didn't.
+    fn map_keys(&mut self, index: usize, events: Vec<KeyEvent>) -> Result<(), Error> {
+        let input = &mut self.inputs[index];
+
+        for (key, count) in input.debouncer.take_filtered() {
+            eprintln!(
+                "Filtered chatter on {:?} of {} ({} times so far).",
+                key, input.name, count
+            );
+        }
+
+        for event in events {
+            let events = self.inputs[index].accessibility.filter(&event);
+            self.handle_keys(index, events)?;
+        }
+
+        for feature in self.inputs[index].accessibility.take_toggled() {
+            self.set_feature(feature, !self.is_enabled(feature))?;
+        }
+
+        Ok(())
+    }
+
+    /// Maps key events that are ready for the mapper.
+    fn handle_keys(&mut self, index: usize, events: Vec<KeyEvent>) -> Result<(), Error> {
+        for event in events {
+            let keys = self.inputs[index].key_mapper.handle_key_event(&event);
+            self.send_keys(index, keys)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the motion of a pointing device to its output's pointer, as one
+    /// report.
+    fn send_motion(&mut self, index: usize) -> Result<(), Error> {
+        let input = &mut self.inputs[index];
+        if input.motion.is_empty() {
+            return Ok(());
+        }
+
+        let output_device = match &self.outputs[input.output].pointer {
+            Some(pointer) => pointer,
+            None => {
+                input.motion.clear();
+                return Ok(());
+            }
+        };
+        let mut events = input
+            .motion
+            .drain(..)
+            .map(|event| InputEvent::from(event).as_raw().to_owned())
+            .collect::<Vec<_>>();
+        events.push(
+            InputEvent::from(SynchronizeEvent::report(get_timestamp()?))
+                .as_raw()
+                .to_owned(),
+        );
+
+        output_device.write(&events)?;
+        Ok(())
+    }
+
+    /// Writes keys from an input's mapper to its output, and runs any
+    /// commands the mapper asked for.
+    fn send_keys(&mut self, index: usize, keys: Vec<(Key, KeyState)>) -> Result<(), Error> {
+        let input = &mut self.inputs[index];
+        let output = &self.outputs[input.output];
+
+        for (mapped_key, state) in keys {
+            let output_device = if key_mapper::is_mouse_button(mapped_key) {
+                match &output.pointer {
+                    Some(pointer) => pointer,
+                    None => continue,
+                }
+            } else {
+                &output.keyboard
+            };
+            let events: [input_event; 2] = [
+                InputEvent::from(KeyEvent::new(get_timestamp()?, mapped_key, state))
+                    .as_raw()
+                    .to_owned(),
+                InputEvent::from(SynchronizeEvent::report(get_timestamp()?))
+                    .as_raw()
+                    .to_owned(),
+            ];
+
+            output_device.write(&events)?;
+        }
+
+        for movement in input.key_mapper.take_movements() {
+            // Scrolling is sent in high resolution too, since that's all
+            // libinput reads from devices that support it.
+            let axes = [
+                (RelativeAxis::X, movement.x),
+                (RelativeAxis::Y, movement.y),
+                (RelativeAxis::Wheel, movement.wheel),
+                (RelativeAxis::WheelHiRes, movement.wheel * 120),
+                (RelativeAxis::HorizontalWheel, movement.horizontal_wheel),
+                (
+                    RelativeAxis::HorizontalWheelHiRes,
+                    movement.horizontal_wheel * 120,
+                ),
+            ];
+            let mut events = axes
+                .iter()
+                .filter(|(_, value)| *value != 0)
+                .map(|(axis, value)| {
+                    Ok(
+                        InputEvent::from(RelativeEvent::new(get_timestamp()?, *axis, *value))
+                            .as_raw()
+                            .to_owned(),
+                    )
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            events.push(
+                InputEvent::from(SynchronizeEvent::report(get_timestamp()?))
+                    .as_raw()
+                    .to_owned(),
+            );
+
+            if let Some(pointer) = &output.pointer {
+                pointer.write(&events)?;
+            }
+        }
+
+        for command in input.key_mapper.take_commands() {
+            // A command that fails to start shouldn't stop keyswitch.
+            if let Err(error) = self.children.spawn(&command) {
+                eprintln!("Unable to run `{}`: {}", command, error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Waits until any of the file descriptors can be read, or the timeout in
+/// milliseconds passes, and returns which ones are ready. Errors and hangups
+/// count as ready, so they get noticed when reading.
+fn poll(fds: &[RawFd], timeout: i32) -> Result<Vec<bool>, Error> {
+    let mut poll_fds = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+
+    loop {
+        let result = unsafe {
+            libc::poll(
+                poll_fds.as_mut_ptr(),
+                poll_fds.len() as libc::nfds_t,
+                timeout,
+            )
+        };
+
+        if result >= 0 {
+            return Ok(poll_fds
+                .iter()
+                .map(|poll_fd| poll_fd.revents != 0)
+                .collect());
+        }
+
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(Error::from(error));
+        }
+    }
+}
+
+fn create_keyboard_device(
+    name: Option<&str>,
+    repeat: Option<Repeat>,
+) -> Result<UInputHandle<File>, Error> {
+    let output_device = open_uinput()?;
+
+    output_device.set_evbit(EventKind::Key)?;
+    output_device.set_evbit(EventKind::Synchronize)?;
+
+    if repeat.is_some() {
+        output_device.set_evbit(EventKind::Autorepeat)?;
+    }
+
+    for key in Key::iter().filter(|key| !key_mapper::is_mouse_button(*key)) {
+        output_device.set_keybit(key)?;
+    }
+
+    create_device(&output_device, OUTPUT_NAME, name, PRODUCT)?;
+
+    // The kernel starts out with its own delay and period.
+    if let Some(repeat) = repeat {
+        let settings = [
+            (AutorepeatKind::Delay, repeat.delay),
+            (AutorepeatKind::Period, repeat.period),
+        ];
+        let events = settings
+            .iter()
+            .map(|(kind, time)| {
+                Ok(InputEvent::from(AutorepeatEvent::new(
+                    get_timestamp()?,
+                    *kind,
+                    time.as_millis() as i32,
+                ))
+                .as_raw()
+                .to_owned())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        output_device.write(&events)?;
+    }
+
+    Ok(output_device)
+}
+
+/// Whether a device moves a pointer or has mouse buttons, whose events need a
+/// pointer to be passed through on.
+fn is_pointing_device(device: &EvdevHandle<File>) -> Result<bool, Error> {
+    Ok(device.event_bits()?.get(EventKind::Relative)
+        || device.key_bits()?.iter().any(key_mapper::is_mouse_button))
+}
+
+/// A device for the buttons and motion of grabbed mice, as well as for mouse
+/// keys.
+fn create_pointer_device(name: Option<&str>) -> Result<UInputHandle<File>, Error> {
+    let output_device = open_uinput()?;
+
+    output_device.set_evbit(EventKind::Key)?;
+    output_device.set_evbit(EventKind::Relative)?;
+    output_device.set_evbit(EventKind::Synchronize)?;
+
+    for key in Key::iter().filter(|key| key_mapper::is_mouse_button(*key)) {
+        output_device.set_keybit(key)?;
+    }
+
+    for axis in [
+        RelativeAxis::X,
+        RelativeAxis::Y,
+        RelativeAxis::Wheel,
+        RelativeAxis::WheelHiRes,
+        RelativeAxis::HorizontalWheel,
+        RelativeAxis::HorizontalWheelHiRes,
+    ]
+    .iter()
+    {
+        output_device.set_relbit(*axis)?;
+    }
+
+    create_device(&output_device, POINTER_NAME, name, POINTER_PRODUCT)?;
+    Ok(output_device)
+}
+
+fn open_uinput() -> Result<UInputHandle<File>, Error> {
+    let uinput = fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/uinput")
+        .map_err(Error::UInputError)?;
+
+    Ok(UInputHandle::new(uinput))
+}
+
+/// Creates a virtual device once its events are set up, named after the output
+/// it's for, if it has a name.
+fn create_device(
+    device: &UInputHandle<File>,
+    base_name: &str,
+    name: Option<&str>,
+    product: u16,
+) -> Result<(), Error> {
+    let device_name = match name {
+        Some(name) => format!("{} ({})", base_name, name),
+        None => String::from(base_name),
+    };
+
+    device.create(
+        &InputId {
+            bustype: BUS_USB,
+            vendor: VENDOR,
+            product,
+            version: VERSION,
+        },
+        device_name.as_bytes(),
+        0,
+        &[],
+    )?;
+
+    Ok(())
+}
+
+/// The current time in the clock used by event timestamps, which is the
+/// monotonic clock once it's set on the devices.
+fn current_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+
+    // This can't fail with a valid clock and pointer.
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
 }
 
 fn get_timestamp() -> Result<EventTime, Error> {
-    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let time = current_time();
 
     Ok(EventTime::new(
-        (time.as_secs() as u64)
+        time.as_secs()
             .try_into()
             .map_err(|_| Error::SystemTimeError)?,
         (time.subsec_micros() as u64)
@@ -110,19 +672,6 @@ fn get_timestamp() -> Result<EventTime, Error> {
     ))
 }
 
-/// TODO: Replace this with a real configuration system
-fn test_key_mapper() -> Result<KeyMapper, key_mapper::Error> {
-    let mut mapper = KeyMapper::new();
-
-    mapper.add_mapping(&[Key::CapsLock], &Key::LeftCtrl)?;
-    mapper.add_mapping(&[Key::CapsLock, Key::H], &Key::Left)?;
-    mapper.add_mapping(&[Key::CapsLock, Key::J], &Key::Down)?;
-    mapper.add_mapping(&[Key::CapsLock, Key::K], &Key::Up)?;
-    mapper.add_mapping(&[Key::CapsLock, Key::L], &Key::Right)?;
-
-    Ok(mapper)
-}
-
 const EMPTY_INPUT_EVENT: input_event = input_event {
     time: timeval {
         tv_sec: 0,
@@ -136,7 +685,14 @@ const EMPTY_INPUT_EVENT: input_event = input_event {
 #[derive(Debug)]
 pub enum Error {
     IOError(io::Error),
+    /// `/dev/uinput` couldn't be opened to create a virtual device.
+    UInputError(io::Error),
+    /// The control socket couldn't be created, like when another keyswitch is
+    /// listening on it.
+    ControlSocketError(PathBuf, io::Error),
+    PrivilegesError(privileges::Error),
     InputEventRangeError,
+    /// The current time doesn't fit in an event timestamp.
     SystemTimeError,
     BadMappingError(key_mapper::Error),
 }
@@ -153,12 +709,6 @@ impl From<input_linux::RangeError> for Error {
     }
 }
 
-impl From<SystemTimeError> for Error {
-    fn from(_: SystemTimeError) -> Self {
-        Error::SystemTimeError
-    }
-}
-
 impl From<key_mapper::Error> for Error {
     fn from(error: key_mapper::Error) -> Self {
         Error::BadMappingError(error)
@@ -0,0 +1,467 @@
+use input_linux::Key;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Where XKB keeps its layouts, one file of variants for each.
+pub const XKB_SYMBOLS: &str = "/usr/share/X11/xkb/symbols";
+
+/// The keys a layout defines characters for, by their XKB names. Bundled
+/// layouts list their characters in this order.
+const KEYS: &[(&str, Key)] = &[
+    ("TLDE", Key::Grave),
+    ("AE01", Key::Num1),
+    ("AE02", Key::Num2),
+    ("AE03", Key::Num3),
+    ("AE04", Key::Num4),
+    ("AE05", Key::Num5),
+    ("AE06", Key::Num6),
+    ("AE07", Key::Num7),
+    ("AE08", Key::Num8),
+    ("AE09", Key::Num9),
+    ("AE10", Key::Num0),
+    ("AE11", Key::Minus),
+    ("AE12", Key::Equal),
+    ("AD01", Key::Q),
+    ("AD02", Key::W),
+    ("AD03", Key::E),
+    ("AD04", Key::R),
+    ("AD05", Key::T),
+    ("AD06", Key::Y),
+    ("AD07", Key::U),
+    ("AD08", Key::I),
+    ("AD09", Key::O),
+    ("AD10", Key::P),
+    ("AD11", Key::LeftBrace),
+    ("AD12", Key::RightBrace),
+    ("BKSL", Key::Backslash),
+    ("AC01", Key::A),
+    ("AC02", Key::S),
+    ("AC03", Key::D),
+    ("AC04", Key::F),
+    ("AC05", Key::G),
+    ("AC06", Key::H),
+    ("AC07", Key::J),
+    ("AC08", Key::K),
+    ("AC09", Key::L),
+    ("AC10", Key::Semicolon),
+    ("AC11", Key::Apostrophe),
+    ("AB01", Key::Z),
+    ("AB02", Key::X),
+    ("AB03", Key::C),
+    ("AB04", Key::V),
+    ("AB05", Key::B),
+    ("AB06", Key::N),
+    ("AB07", Key::M),
+    ("AB08", Key::Comma),
+    ("AB09", Key::Dot),
+    ("AB10", Key::Slash),
+    ("LSGT", Key::NonUsBackslashAndPipe),
+];
+
+/// Layouts for when XKB isn't installed, as the characters of `KEYS` without
+/// and with Shift.
+const BUNDLED: &[(&str, Option<&str>, &str, &str)] = &[
+    (
+        "us",
+        None,
+        "`1234567890-=qwertyuiop[]\\asdfghjkl;'zxcvbnm,./",
+        "~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"ZXCVBNM<>?",
+    ),
+    (
+        "us",
+        Some("dvorak"),
+        "`1234567890[]',.pyfgcrl/=\\aoeuidhtns-;qjkxbmwvz",
+        "~!@#$%^&*(){}\"<>PYFGCRL?+|AOEUIDHTNS_:QJKXBMWVZ",
+    ),
+    (
+        "us",
+        Some("colemak"),
+        "`1234567890-=qwfpgjluy;[]\\arstdhneio'zxcvbkm,./",
+        "~!@#$%^&*()_+QWFPGJLUY:{}|ARSTDHNEIO\"ZXCVBKM<>?",
+    ),
+];
+
+/// Keysyms that name a character, other than letters and digits.
+const KEYSYMS: &[(&str, char)] = &[
+    ("space", ' '),
+    ("grave", '`'),
+    ("asciitilde", '~'),
+    ("exclam", '!'),
+    ("at", '@'),
+    ("numbersign", '#'),
+    ("dollar", '$'),
+    ("percent", '%'),
+    ("asciicircum", '^'),
+    ("ampersand", '&'),
+    ("asterisk", '*'),
+    ("parenleft", '('),
+    ("parenright", ')'),
+    ("minus", '-'),
+    ("underscore", '_'),
+    ("equal", '='),
+    ("plus", '+'),
+    ("bracketleft", '['),
+    ("braceleft", '{'),
+    ("bracketright", ']'),
+    ("braceright", '}'),
+    ("backslash", '\\'),
+    ("bar", '|'),
+    ("semicolon", ';'),
+    ("colon", ':'),
+    ("apostrophe", '\''),
+    ("quotedbl", '"'),
+    ("comma", ','),
+    ("less", '<'),
+    ("period", '.'),
+    ("greater", '>'),
+    ("slash", '/'),
+    ("question", '?'),
+    ("section", '§'),
+    ("degree", '°'),
+    ("sterling", '£'),
+    ("EuroSign", '€'),
+    ("ssharp", 'ß'),
+    ("adiaeresis", 'ä'),
+    ("odiaeresis", 'ö'),
+    ("udiaeresis", 'ü'),
+    ("Adiaeresis", 'Ä'),
+    ("Odiaeresis", 'Ö'),
+    ("Udiaeresis", 'Ü'),
+    ("aring", 'å'),
+    ("Aring", 'Å'),
+    ("ae", 'æ'),
+    ("AE", 'Æ'),
+    ("oslash", 'ø'),
+    ("Oslash", 'Ø'),
+    ("agrave", 'à'),
+    ("egrave", 'è'),
+    ("eacute", 'é'),
+    ("ugrave", 'ù'),
+    ("ccedilla", 'ç'),
+    ("ntilde", 'ñ'),
+    ("Ntilde", 'Ñ'),
+];
+
+/// Layouts can include each other, but not forever.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Which characters the keys type, so configs can name keys by the character
+/// on them instead of where they are on a US keyboard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    /// The characters each key types without and with Shift.
+    keys: Vec<(Key, [Option<char>; 2])>,
+}
+
+impl Layout {
+    /// Loads a layout from the XKB files if they're installed, or from the
+    /// layouts bundled with keyswitch if not.
+    pub fn load(name: &str, variant: Option<&str>) -> Option<Self> {
+        let symbols = Path::new(XKB_SYMBOLS);
+
+        if symbols.is_dir() {
+            let read = |file: &str| fs::read_to_string(symbols.join(file)).ok();
+            return Self::parse(&read(name)?, variant, &read);
+        }
+
+        Self::bundled(name, variant)
+    }
+
+    /// A layout bundled with keyswitch.
+    pub fn bundled(name: &str, variant: Option<&str>) -> Option<Self> {
+        let variant = variant.filter(|variant| *variant != "basic");
+        let (_, _, plain, shifted) = BUNDLED
+            .iter()
+            .find(|layout| layout.0 == name && layout.1 == variant)?;
+
+        Some(Self {
+            keys: KEYS
+                .iter()
+                .zip(plain.chars().zip(shifted.chars()))
+                .map(|((_, key), (plain, shifted))| (*key, [Some(plain), Some(shifted)]))
+                .collect(),
+        })
+    }
+
+    /// Parses a variant of an XKB symbols file, or its default variant.
+    /// Included files are read with `read`.
+    pub fn parse(
+        source: &str,
+        variant: Option<&str>,
+        read: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<Self> {
+        let mut levels = HashMap::new();
+        parse_symbols(source, variant, read, &mut levels, 0)?;
+
+        Some(Self {
+            keys: KEYS
+                .iter()
+                .filter_map(|(_, key)| Some((*key, *levels.get(key)?)))
+                .collect(),
+        })
+    }
+
+    /// The key that types a character, and whether it needs Shift.
+    pub fn find(&self, c: char) -> Option<(Key, bool)> {
+        // Layouts don't change these.
+        match c {
+            ' ' => return Some((Key::Space, false)),
+            '\n' => return Some((Key::Enter, false)),
+            '\t' => return Some((Key::Tab, false)),
+            _ => (),
+        }
+
+        for (level, shift) in [(0, false), (1, true)].iter() {
+            if let Some((key, _)) = self.keys.iter().find(|(_, chars)| chars[*level] == Some(c)) {
+                return Some((*key, *shift));
+            }
+        }
+
+        None
+    }
+
+    /// Keys that type a letter or digit without Shift.
+    pub fn alphanumeric_keys(&self) -> Vec<Key> {
+        self.keys
+            .iter()
+            .filter(|(_, chars)| chars[0].is_some_and(char::is_alphanumeric))
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// The character a key types without Shift.
+    pub fn character(&self, key: Key) -> Option<char> {
+        self.keys
+            .iter()
+            .find(|(found, _)| *found == key)
+            .and_then(|(_, chars)| chars[0])
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::bundled("us", None).expect("the US layout should be bundled")
+    }
+}
+
+/// Adds the keys of a variant to `levels`, replacing any that were already
+/// there. Returns `None` if there's no such variant.
+fn parse_symbols(
+    source: &str,
+    variant: Option<&str>,
+    read: &dyn Fn(&str) -> Option<String>,
+    levels: &mut HashMap<Key, [Option<char>; 2]>,
+    depth: usize,
+) -> Option<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return None;
+    }
+
+    let source = source
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let blocks = blocks(&source);
+    let (_, _, body) = match variant {
+        Some(variant) => blocks.iter().find(|(name, _, _)| *name == variant)?,
+        None => blocks
+            .iter()
+            .find(|(_, default, _)| *default)
+            .or_else(|| blocks.first())?,
+    };
+
+    for mut statement in body.split(';').map(str::trim) {
+        // Includes don't end with a semicolon, so there can be another
+        // statement after them.
+        while let Some(rest) = statement.strip_prefix("include") {
+            let mut parts = rest.splitn(3, '"');
+            let includes = parts.nth(1).unwrap_or("");
+            statement = parts.next().unwrap_or("").trim();
+
+            for include in includes.split(['+', '|']) {
+                let (file, variant) = match include.split_once('(') {
+                    Some((file, variant)) => (file, Some(variant.trim_end_matches(')'))),
+                    None => (include, None),
+                };
+
+                if let Some(source) = read(file.trim()) {
+                    parse_symbols(&source, variant, read, levels, depth + 1);
+                }
+            }
+        }
+
+        if let Some(rest) = statement.strip_prefix("key") {
+            let name = rest
+                .split('<')
+                .nth(1)
+                .and_then(|rest| rest.split('>').next());
+            let key = KEYS.iter().find(|(xkb, _)| Some(*xkb) == name);
+
+            if let (Some((_, key)), Some(symbols)) = (key, symbols(rest)) {
+                let mut symbols = symbols.split(',').map(|name| keysym(name.trim()));
+                let plain = symbols.next().flatten();
+                let shifted = symbols.next().flatten();
+                levels.insert(*key, [plain, shifted]);
+            }
+        }
+    }
+
+    Some(())
+}
+
+/// The variants of a symbols file, with whether they're the default, and
+/// what's between their braces.
+fn blocks(source: &str) -> Vec<(&str, bool, &str)> {
+    let mut blocks = vec![];
+    let mut rest = source;
+
+    while let Some(start) = rest.find("xkb_symbols") {
+        let default = rest[..start].contains("default");
+        let after = &rest[start..];
+        let name = quoted(after).unwrap_or("");
+
+        let open = match after.find('{') {
+            Some(open) => open,
+            None => break,
+        };
+        let mut depth = 0;
+        let mut close = after.len();
+
+        for (index, c) in after[open..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = open + index;
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        blocks.push((name, default, &after[open + 1..close]));
+        rest = after.get(close + 1..).unwrap_or("");
+        // Flags like `default` only apply to the next block.
+        rest = rest.split_once(';').map(|(_, rest)| rest).unwrap_or(rest);
+    }
+
+    blocks
+}
+
+/// The first string in quotes.
+fn quoted(source: &str) -> Option<&str> {
+    source.split('"').nth(1)
+}
+
+/// The keysyms of the first group of a key, like `q, Q` in
+/// `<AD01> { [ q, Q ] }`, or in `symbols[Group1]= [ q, Q ]`.
+fn symbols(key: &str) -> Option<&str> {
+    let start = match key.find("symbols[") {
+        Some(start) => start + key[start..].find(']')? + 1,
+        None => key.find('{')?,
+    };
+    let mut rest = &key[start..];
+
+    // Skip other settings like `type[Group1]= "..."`, which aren't keysyms.
+    loop {
+        let open = rest.find('[')?;
+        let before = rest[..open].trim_end();
+
+        if before.ends_with(['{', ',', '=']) || before.is_empty() {
+            let close = open + rest[open..].find(']')?;
+            return Some(&rest[open + 1..close]);
+        }
+
+        rest = &rest[open + rest[open..].find(']')? + 1..];
+    }
+}
+
+/// The character a keysym types, if it types one.
+fn keysym(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphanumeric() => return Some(c),
+        (Some('U'), Some(_)) if name.len() > 4 => {
+            if let Some(c) = u32::from_str_radix(&name[1..], 16)
+                .ok()
+                .and_then(char::from_u32)
+            {
+                return Some(c);
+            }
+        }
+        _ => (),
+    }
+
+    KEYSYMS
+        .iter()
+        .find(|(keysym, _)| *keysym == name)
+        .map(|(_, c)| *c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Layout;
+    use input_linux::Key;
+
+    const SYMBOLS: &str = r#"
+        default partial alphanumeric_keys
+        xkb_symbols "basic" {
+            name[Group1]= "Test";
+            key <AD01> { [ q, Q ] };
+            key <AC07> { [ j, J ] };
+            key <AB10> { [ slash, question ] };
+        };
+
+        partial alphanumeric_keys
+        xkb_symbols "swapped" {
+            include "test(basic)"
+            // Comments { are ignored.
+            key <AD01> { type[Group1]= "FOUR_LEVEL", [ j, J, dead_acute ] };
+            key <AC07> { symbols[Group1]= [ q, Q ] };
+            key <AC11> { [ U00E9, Eacute ] };
+        };
+    "#;
+
+    fn read(file: &str) -> Option<String> {
+        Some(String::from(SYMBOLS)).filter(|_| file == "test")
+    }
+
+    #[test]
+    fn it_parses_the_default_variant() {
+        let layout = Layout::parse(SYMBOLS, None, &read).unwrap();
+
+        assert_eq!(layout.find('j'), Some((Key::J, false)));
+        assert_eq!(layout.find('?'), Some((Key::Slash, true)));
+        assert_eq!(layout.find(' '), Some((Key::Space, false)));
+        assert_eq!(layout.find('x'), None);
+    }
+
+    #[test]
+    fn it_parses_variants_with_includes() {
+        let layout = Layout::parse(SYMBOLS, Some("swapped"), &read).unwrap();
+
+        assert_eq!(layout.find('j'), Some((Key::Q, false)));
+        assert_eq!(layout.find('Q'), Some((Key::J, true)));
+        assert_eq!(layout.find('/'), Some((Key::Slash, false)));
+        assert_eq!(layout.find('é'), Some((Key::Apostrophe, false)));
+        assert_eq!(Layout::parse(SYMBOLS, Some("nope"), &read), None);
+    }
+
+    #[test]
+    fn it_bundles_common_layouts() {
+        let dvorak = Layout::bundled("us", Some("dvorak")).unwrap();
+
+        assert_eq!(dvorak.find('j'), Some((Key::C, false)));
+        assert_eq!(dvorak.find('"'), Some((Key::Q, true)));
+        assert_eq!(dvorak.character(Key::Minus), Some('['));
+        assert_eq!(dvorak.alphanumeric_keys().len(), 36);
+        assert_eq!(
+            Layout::bundled("us", Some("colemak")).unwrap().find(';'),
+            Some((Key::P, false))
+        );
+        assert_eq!(Layout::default().find('j'), Some((Key::J, false)));
+    }
+}
@@ -1,3 +1,14 @@
+pub mod accessibility;
+pub mod config;
+pub mod control;
+pub mod debounce;
 pub mod device;
-mod key_mapper;
+pub mod exec;
+pub mod install;
+pub mod key_mapper;
+pub mod key_names;
 pub mod key_switcher;
+pub mod layout;
+pub mod notify;
+pub mod privileges;
+pub mod text;
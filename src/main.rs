@@ -1,9 +1,12 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use keyswitch::{
-    device::{self, Device},
+    config::{self, Config},
+    device::{self, Device, Selector},
+    install, key_names,
     key_switcher::{self, KeySwitcher},
+    privileges,
 };
-use std::{io, path::PathBuf, process};
+use std::{env, io, path::PathBuf, process};
 
 fn main() {
     let mode = get_mode_from_args().unwrap_or_else(|| {
@@ -23,27 +26,174 @@ fn main() {
 fn run(mode: Mode) -> Result<(), Error> {
     match mode {
         Mode::ListDevices => Device::print_available().map_err(Error::from),
-        Mode::ReadDevice(id) => {
-            let device_result = match id {
-                DeviceId::ByPath(path) => Device::open(PathBuf::from(&path))
-                    .map_err(|err| Error::DeviceOpenError(path, err)),
-                DeviceId::ByName(name) => {
-                    Device::available()
+        Mode::CheckConfig(path) => check_config(path),
+        Mode::InstallUdev(matches, config_path) => {
+            let selectors = matches
+                .iter()
+                .map(|source| Selector::parse(source))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Error::BadSelectorError)?;
+            let selectors = match config_path {
+                Some(_) if selectors.is_empty() => load_config(config_path)?
+                    .devices()
+                    .iter()
+                    .map(|device_config| device_config.selector.clone())
+                    .collect(),
+                _ => selectors,
+            };
+
+            if selectors.is_empty() {
+                return Err(Error::NoDevicesConfiguredError);
+            }
+
+            print!(
+                "{}",
+                install::udev_rules(&selectors).map_err(Error::InstallError)?
+            );
+            Ok(())
+        }
+        Mode::SystemdUnit(config_path) => {
+            let executable = env::current_exe()?;
+            // The unit doesn't run in the current directory.
+            let config_path = match config_path {
+                Some(path) => Some(env::current_dir()?.join(path)),
+                None => None,
+            };
+
+            print!(
+                "{}",
+                install::systemd_unit(&executable, config_path.as_deref())
+                    .map_err(Error::InstallError)?
+            );
+            Ok(())
+        }
+        Mode::Explain(keys, config_path, device_name) => {
+            let keys = key_names::parse_combination(&keys).map_err(Error::UnknownKeyError)?;
+            let config = load_config(config_path)?;
+            let device_config = match device_name {
+                Some(name) => Some(
+                    config
+                        .devices()
+                        .iter()
+                        .find(|device_config| device_config.selector.name.as_ref() == Some(&name))
+                        .ok_or(Error::NoDeviceConfigFoundError(name))?,
+                ),
+                None => None,
+            };
+            let explanation = config
+                .explain(&keys, device_config)
+                .map_err(key_switcher::Error::from)?;
+
+            print!("{}", explanation);
+            Ok(())
+        }
+        Mode::ReadDevice(id, config_path) => {
+            let config = load_config(config_path)?;
+            let user = privileges::target(config.privileges()).map_err(Error::PrivilegesError)?;
+
+            // Commands can't switch users once privileges are dropped. `check`
+            // catches this for a configured user, but not one from sudo.
+            if let Some(user) = &user {
+                let other = config
+                    .commands()
+                    .filter_map(|command| command.user.as_ref())
+                    .find(|other| other.uid != user.uid);
+
+                if let Some(other) = other {
+                    return Err(Error::CommandUserError(
+                        other.name.clone(),
+                        user.name.clone(),
+                    ));
+                }
+            }
+
+            let devices_result =
+                match id {
+                    Some(DeviceId::ByPath(path)) => Device::open(PathBuf::from(&path))
+                        .map(|device| vec![device])
+                        .map_err(|err| Error::DeviceOpenError(path, err)),
+                    Some(DeviceId::ByName(name)) => Device::available()
                         .map_err(Error::from)
                         .and_then(|mut devices| {
                             devices
                                 .find(|d| d.name() == name)
+                                .map(|device| vec![device])
                                 .ok_or(Error::NoDeviceFoundError(name))
-                        })
-                }
-            };
+                        }),
+                    None => configured_devices(&config),
+                };
+
+            let mut key_switcher = KeySwitcher::new(devices_result?, &config)?;
+
+            match &user {
+                Some(user) => key_switcher.drop_privileges(user)?,
+                None if privileges::is_root() => eprintln!(
+                    "Warning: still running as root. Set a user in the `[privileges]` section of the config, or start keyswitch with sudo to drop to your user."
+                ),
+                None => (),
+            }
+
+            key_switcher.run().map_err(Error::from)
+        }
+    }
+}
+
+/// Finds every device matched by a device section in the config.
+fn configured_devices(config: &Config) -> Result<Vec<Device>, Error> {
+    if config.devices().is_empty() {
+        return Err(Error::NoDevicesConfiguredError);
+    }
+
+    let mut devices: Vec<Device> = Vec::new();
+
+    for device_config in config.devices() {
+        let mut found = false;
+
+        for device in Device::matching(&device_config.selector)? {
+            found = true;
+            if devices.iter().all(|d| d.path() != device.path()) {
+                devices.push(device);
+            }
+        }
+
+        if !found {
+            eprintln!("No device found for {}.", device_config.selector);
+        }
+    }
+
+    if devices.is_empty() {
+        Err(Error::NoDevicesConfiguredError)
+    } else {
+        Ok(devices)
+    }
+}
+
+fn load_config(path: Option<PathBuf>) -> Result<Config, Error> {
+    match path {
+        Some(path) => Config::load(&path).map_err(|err| Error::ConfigError(path, err)),
+        None => Ok(Config::default()),
+    }
+}
+
+fn check_config(path: PathBuf) -> Result<(), Error> {
+    let (_config, problems) = Config::check(&path).map_err(|err| Error::ConfigError(path, err))?;
+
+    for problem in problems.iter() {
+        let severity = if problem.is_error() {
+            "error"
+        } else {
+            "warning"
+        };
+        println!("{}: {}", severity, problem);
+    }
 
-            device_result.and_then(|device| {
-                KeySwitcher::new(device)
-                    .and_then(|mut s| s.run())
-                    .map_err(Error::from)
-            })
+    match problems.iter().filter(|problem| problem.is_error()).count() {
+        0 if problems.is_empty() => {
+            println!("No problems found.");
+            Ok(())
         }
+        0 => Ok(()),
+        count => Err(Error::ConfigCheckError(count)),
     }
 }
 
@@ -54,7 +204,9 @@ fn format_error(error: Error) -> String {
                 device::Error::UnsupportedDeviceError(_) => "Device does not send key events.",
                 device::Error::Utf8Error(_) => "The path is weird.",
                 device::Error::IOError(io_error) => match io_error.kind() {
-                    io::ErrorKind::PermissionDenied => "Permission denied. Try running with sudo.",
+                    io::ErrorKind::PermissionDenied => {
+                        "Permission denied. Add your user to the `input` group, or run with sudo."
+                    }
                     _ => "Unknown io error.",
                 },
             };
@@ -65,9 +217,42 @@ fn format_error(error: Error) -> String {
             format!("Device does not send key events: {:?}", path)
         }
         Error::DeviceListingError(_) => {
-            format!("Ran into an error when attempting to list devices.")
+            "Ran into an error when attempting to list devices.".to_string()
+        }
+        Error::NoDeviceFoundError(name) => format!(
+            "Device with name not found: {}\nOnly devices you can read are listed. Add your user to the `input` group, or run with sudo.",
+            name
+        ),
+        Error::KeySwitcherError(key_switcher::Error::UInputError(io_error)) => {
+            match io_error.kind() {
+                io::ErrorKind::PermissionDenied => String::from(
+                    "Unable to open /dev/uinput: Permission denied.\nAdd your user to the `uinput` group and give the group access with a udev rule, or run with sudo.",
+                ),
+                io::ErrorKind::NotFound => String::from(
+                    "Unable to open /dev/uinput: File not found.\nTry loading the uinput module with `modprobe uinput`.",
+                ),
+                _ => format!("Unable to open /dev/uinput: {}", io_error),
+            }
         }
-        Error::NoDeviceFoundError(name) => format!("Device with name not found: {}", name),
+        Error::KeySwitcherError(key_switcher::Error::ControlSocketError(path, io_error)) => {
+            match io_error.kind() {
+                io::ErrorKind::AddrInUse => format!(
+                    "Unable to create control socket {}: Another keyswitch is listening on it.",
+                    path.display()
+                ),
+                _ => format!(
+                    "Unable to create control socket {}: {}",
+                    path.display(),
+                    io_error
+                ),
+            }
+        }
+        Error::KeySwitcherError(key_switcher::Error::PrivilegesError(error))
+        | Error::PrivilegesError(error) => format_privileges_error(error),
+        Error::CommandUserError(name, target) => format!(
+            "A command runs as `{}`, but keyswitch runs as `{}` after opening devices.\nRemove the command's `user`, or set it to `{}`.",
+            name, target, target
+        ),
         Error::KeySwitcherError(err) => {
             let message = match err {
                 key_switcher::Error::BadMappingError(_) => {
@@ -76,14 +261,69 @@ fn format_error(error: Error) -> String {
                 _ => "Encountered an unexpected error when mapping a key.",
             };
 
-            format!("{}", message)
+            message.to_string()
+        }
+        Error::ConfigError(path, error) => {
+            let reason = match error {
+                config::Error::IOError(io_error) => match io_error.kind() {
+                    io::ErrorKind::NotFound => String::from("File not found."),
+                    io::ErrorKind::PermissionDenied => String::from("Permission denied."),
+                    _ => String::from("Unknown io error."),
+                },
+                config::Error::ParseError(toml_error) => toml_error.to_string(),
+                config::Error::InvalidConfigError(problems) => {
+                    let mut reason = problems
+                        .iter()
+                        .map(|problem| problem.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    reason.push_str("\nRun `keyswitch check` for more details.");
+                    reason
+                }
+            };
+
+            format!("Unable to load config: {}\n{}", path.display(), reason)
+        }
+        Error::ConfigCheckError(count) => format!("Found {} error(s) in the config.", count),
+        Error::UnknownKeyError(name) => format!("Unknown key: {}", name),
+        Error::NoDeviceConfigFoundError(name) => {
+            format!("No device with name in the config: {}", name)
         }
+        Error::InstallError(install::Error::EmptySelectorError) => String::from(
+            "A device selector needs a name or path, otherwise it matches every device.",
+        ),
+        Error::InstallError(install::Error::UnsupportedValueError) => String::from(
+            "A device name or path has characters that can't be written in the output, like quotes.",
+        ),
+        Error::IOError(io_error) => format!("Unexpected io error: {}", io_error),
+        Error::BadSelectorError(reason) => format!("Bad device selector: {}", reason),
+        Error::NoDevicesConfiguredError => String::from(
+            "No configured devices were found. Add a device section to the config, or use -d or -n.",
+        ),
     }
 }
 
+fn format_privileges_error(error: privileges::Error) -> String {
+    let reason = match error {
+        privileges::Error::UnknownUserError(name) => format!("Unknown user: {}", name),
+        privileges::Error::UnknownGroupError(name) => format!("Unknown group: {}", name),
+        privileges::Error::IOError(io_error) => io_error.to_string(),
+        privileges::Error::StillPrivilegedError => String::from("Root access could be regained."),
+    };
+
+    format!("Unable to drop privileges.\n{}", reason)
+}
+
 enum Mode {
-    ReadDevice(DeviceId),
+    /// Reads the given device, or the devices in the config if not given.
+    ReadDevice(Option<DeviceId>, Option<PathBuf>),
     ListDevices,
+    CheckConfig(PathBuf),
+    Explain(String, Option<PathBuf>, Option<String>),
+    /// Prints udev rules for the given devices, or the devices in the config
+    /// if none are given.
+    InstallUdev(Vec<String>, Option<PathBuf>),
+    SystemdUnit(Option<PathBuf>),
 }
 
 enum DeviceId {
@@ -96,6 +336,8 @@ fn get_mode_from_args() -> Option<Mode> {
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("Maps keys at a low-level.")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(config_arg())
         .arg(
             Arg::with_name("device")
                 .short("d")
@@ -103,7 +345,7 @@ fn get_mode_from_args() -> Option<Mode> {
                 .number_of_values(1)
                 .long_help("The device file to read events from.")
                 .conflicts_with("device-name")
-                .required_unless("list"),
+                .required_unless_one(&["list", "config"]),
         )
         .arg(
             Arg::with_name("device-name")
@@ -112,33 +354,148 @@ fn get_mode_from_args() -> Option<Mode> {
                 .number_of_values(1)
                 .long_help("The name of the device to read events from.")
                 .conflicts_with("device")
-                .required_unless("list"),
+                .required_unless_one(&["list", "config"]),
         )
         .arg(
             Arg::with_name("list")
                 .short("l")
                 .long_help("List devices that are readable.")
-                .required_unless_one(&["device", "device-name"]),
+                .required_unless_one(&["device", "device-name", "config"]),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks a configuration file for problems.")
+                .arg(
+                    Arg::with_name("config")
+                        .required(true)
+                        .long_help("The configuration file to check."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Shows which mapping a key combination triggers.")
+                .arg(config_arg())
+                .arg(
+                    Arg::with_name("device-name")
+                        .short("n")
+                        .long("device-name")
+                        .number_of_values(1)
+                        .long_help("Use the mappings of the device with this name in the config."),
+                )
+                .arg(
+                    Arg::with_name("keys")
+                        .required(true)
+                        .long_help("Keys pressed in order, joined by `+`. For example, `caps+j`."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("install-udev")
+                .about("Prints udev rules that start keyswitch when a device is plugged in.")
+                .arg(config_arg().long_help(
+                    "Use the devices in this configuration file, if no --match is given.",
+                ))
+                .arg(
+                    Arg::with_name("match")
+                        .short("m")
+                        .long("match")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required_unless("config")
+                        .long_help(
+                            "A device to start keyswitch for, like `name=daskeyboard` or `path=/dev/input/event2`. Can be given more than once.",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("systemd-unit")
+                .about("Prints the systemd unit started by the udev rules.")
+                .arg(config_arg().long_help(
+                    "The configuration file the unit should use. Uses built-in mappings if omitted.",
+                )),
         )
         .get_matches();
 
-    if let Some(path) = args.value_of("device") {
-        Some(Mode::ReadDevice(DeviceId::ByPath(path.to_owned())))
+    let config_path = args.value_of("config").map(PathBuf::from);
+
+    if let Some(check_args) = args.subcommand_matches("check") {
+        check_args
+            .value_of("config")
+            .map(|path| Mode::CheckConfig(PathBuf::from(path)))
+    } else if let Some(explain_args) = args.subcommand_matches("explain") {
+        explain_args.value_of("keys").map(|keys| {
+            Mode::Explain(
+                keys.to_owned(),
+                explain_args.value_of("config").map(PathBuf::from),
+                explain_args.value_of("device-name").map(String::from),
+            )
+        })
+    } else if let Some(install_args) = args.subcommand_matches("install-udev") {
+        Some(Mode::InstallUdev(
+            install_args
+                .values_of("match")
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect(),
+            install_args.value_of("config").map(PathBuf::from),
+        ))
+    } else if let Some(unit_args) = args.subcommand_matches("systemd-unit") {
+        Some(Mode::SystemdUnit(
+            unit_args.value_of("config").map(PathBuf::from),
+        ))
+    } else if let Some(path) = args.value_of("device") {
+        Some(Mode::ReadDevice(
+            Some(DeviceId::ByPath(path.to_owned())),
+            config_path,
+        ))
     } else if let Some(name) = args.value_of("device-name") {
-        Some(Mode::ReadDevice(DeviceId::ByName(name.to_owned())))
+        Some(Mode::ReadDevice(
+            Some(DeviceId::ByName(name.to_owned())),
+            config_path,
+        ))
     } else if args.is_present("list") {
         Some(Mode::ListDevices)
+    } else if config_path.is_some() {
+        Some(Mode::ReadDevice(None, config_path))
     } else {
         None
     }
 }
 
+fn config_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .number_of_values(1)
+        .long_help(
+            "The configuration file to read mappings from. Uses built-in mappings if omitted.",
+        )
+}
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     DeviceListingError(device::Error),
     DeviceOpenError(String, device::Error),
     NoDeviceFoundError(String),
     KeySwitcherError(key_switcher::Error),
+    ConfigError(PathBuf, config::Error),
+    ConfigCheckError(usize),
+    UnknownKeyError(String),
+    NoDeviceConfigFoundError(String),
+    NoDevicesConfiguredError,
+    PrivilegesError(privileges::Error),
+    /// A command runs as a user other than the one keyswitch drops to.
+    CommandUserError(String, String),
+    InstallError(install::Error),
+    BadSelectorError(String),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(io_error: io::Error) -> Self {
+        Error::IOError(io_error)
+    }
 }
 
 impl From<device::Error> for Error {
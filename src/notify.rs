@@ -0,0 +1,143 @@
+use std::{
+    env, io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process,
+    time::{Duration, Instant},
+};
+
+/// Tells systemd how keyswitch is doing, over the socket it passes in
+/// `NOTIFY_SOCKET`. See `sd_notify(3)`.
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+
+    /// How often to ping the watchdog, and when it's next due.
+    watchdog: Option<(Duration, Instant)>,
+}
+
+impl Notifier {
+    /// Returns `None` when not started by systemd, or the unit isn't
+    /// `Type=notify`. The variables are removed, so commands run by mappings
+    /// don't notify systemd on keyswitch's behalf.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog = watchdog_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+
+        for name in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"].iter() {
+            env::remove_var(name);
+        }
+
+        Self::new(&path, watchdog).ok()
+    }
+
+    /// Paths starting with `@` are in the abstract namespace.
+    pub fn new(path: &str, watchdog: Option<Duration>) -> io::Result<Self> {
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            address,
+            watchdog: watchdog.map(|interval| (interval, Instant::now() + interval)),
+        })
+    }
+
+    pub fn ready(&self) -> io::Result<()> {
+        self.send("READY=1")
+    }
+
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.send(&format!("STATUS={}", status))
+    }
+
+    /// How long until the watchdog needs to be pinged, if it's enabled.
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog
+            .map(|(_, due)| due.saturating_duration_since(Instant::now()))
+    }
+
+    /// Pings the watchdog, if it's due.
+    pub fn watchdog(&mut self) -> io::Result<()> {
+        match &mut self.watchdog {
+            Some((interval, due)) if *due <= Instant::now() => {
+                *due = Instant::now() + *interval;
+                self.send("WATCHDOG=1")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn send(&self, state: &str) -> io::Result<()> {
+        self.socket
+            .send_to_addr(state.as_bytes(), &self.address)
+            .map(|_| ())
+    }
+}
+
+/// Works out how often to ping the watchdog from `WATCHDOG_USEC` and
+/// `WATCHDOG_PID`. Pings are sent at half the timeout, so a slow wakeup
+/// doesn't get keyswitch restarted.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    // The watchdog may be meant for another process, like a shell that
+    // started keyswitch.
+    if pid.is_some_and(|pid| pid.parse() != Ok(process::id())) {
+        return None;
+    }
+
+    usec.and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(|usec| Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{watchdog_interval, Notifier};
+    use std::{env, fs, os::unix::net::UnixDatagram, process, time::Duration};
+
+    #[test]
+    fn it_sends_notifications() {
+        let path = env::temp_dir().join(format!("keyswitch-notify-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let mut notifier = Notifier::new(path.to_str().unwrap(), Some(Duration::ZERO)).unwrap();
+        notifier.status("Mapping daskeyboard").unwrap();
+        notifier.ready().unwrap();
+        notifier.watchdog().unwrap();
+
+        let mut buffer = [0; 256];
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"STATUS=Mapping daskeyboard");
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"WATCHDOG=1");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_pings_the_watchdog_at_half_the_timeout() {
+        let pid = process::id().to_string();
+
+        assert_eq!(
+            watchdog_interval(Some("10000000"), Some(&pid)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog_interval(Some("10000000"), None),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(watchdog_interval(Some("10000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::exec::User;
+use std::{env, ffi::CString, io, mem, ptr};
+
+/// Who to run as once devices are open, from the `[privileges]` section.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrivilegesConfig {
+    pub user: Option<String>,
+
+    /// Defaults to the primary group of the user.
+    pub group: Option<String>,
+}
+
+/// Finds the user to drop to, or `None` if there's no need to, because
+/// keyswitch isn't running as root.
+///
+/// Without a configured user, this falls back to whoever ran `sudo`.
+pub fn target(config: Option<&PrivilegesConfig>) -> Result<Option<User>, Error> {
+    if !is_root() {
+        return Ok(None);
+    }
+
+    resolve(
+        config,
+        env::var("SUDO_UID").ok().as_deref(),
+        env::var("SUDO_GID").ok().as_deref(),
+    )
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn resolve(
+    config: Option<&PrivilegesConfig>,
+    sudo_uid: Option<&str>,
+    sudo_gid: Option<&str>,
+) -> Result<Option<User>, Error> {
+    let config = config.cloned().unwrap_or_default();
+
+    let mut user = match (&config.user, sudo_uid) {
+        (Some(name), _) => User::find(name).ok_or_else(|| Error::UnknownUserError(name.clone()))?,
+        (None, Some(uid)) => {
+            let mut user = uid
+                .parse()
+                .ok()
+                .and_then(User::find_by_uid)
+                .ok_or_else(|| Error::UnknownUserError(uid.to_owned()))?;
+
+            if let Some(gid) = sudo_gid.and_then(|gid| gid.parse().ok()) {
+                user.gid = gid;
+            }
+
+            user
+        }
+        (None, None) => return Ok(None),
+    };
+
+    if let Some(name) = &config.group {
+        user.gid = find_group(name).ok_or_else(|| Error::UnknownGroupError(name.clone()))?;
+    }
+
+    Ok(Some(user))
+}
+
+/// Looks up a group's id in the group database.
+pub fn find_group(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 4096];
+
+    loop {
+        let error = unsafe {
+            libc::getgrnam_r(
+                c_name.as_ptr(),
+                &mut group,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+
+        match error {
+            0 => break,
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            _ => return None,
+        }
+    }
+
+    if result.is_null() {
+        None
+    } else {
+        Some(group.gr_gid)
+    }
+}
+
+/// Switches to the user and their group for good. Supplementary groups are
+/// cleared, and neither keyswitch nor the commands it runs can gain
+/// privileges again, even through setuid programs like `sudo`.
+pub fn drop_to(user: &User) -> Result<(), Error> {
+    unsafe {
+        if libc::setgroups(0, ptr::null()) != 0
+            || libc::setgid(user.gid) != 0
+            || libc::setuid(user.uid) != 0
+            || libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+        {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+
+        // Make sure there's no way back, in case the calls above didn't do
+        // what they were expected to.
+        if user.uid != 0 && libc::setuid(0) == 0 {
+            return Err(Error::StillPrivilegedError);
+        }
+    }
+
+    // Commands run from mappings inherit these.
+    env::set_var("HOME", &user.home);
+    env::set_var("USER", &user.name);
+    env::set_var("LOGNAME", &user.name);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    UnknownUserError(String),
+    UnknownGroupError(String),
+    StillPrivilegedError,
+}
+
+impl From<io::Error> for Error {
+    fn from(io_error: io::Error) -> Self {
+        Error::IOError(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Error, PrivilegesConfig};
+    use crate::exec::User;
+
+    /// The user running the tests.
+    fn me() -> User {
+        User::find_by_uid(unsafe { libc::getuid() }).unwrap()
+    }
+
+    #[test]
+    fn it_drops_to_the_sudo_user() {
+        let me = me();
+        let user = resolve(None, Some(&me.uid.to_string()), Some("12345"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, me.name);
+        assert_eq!((user.uid, user.gid), (me.uid, 12345));
+
+        assert!(resolve(None, None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_prefers_the_configured_user_and_group() {
+        let me = me();
+        let config = PrivilegesConfig {
+            user: Some(me.name.clone()),
+            group: None,
+        };
+        let user = resolve(Some(&config), Some("12345"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!((user.uid, user.gid), (me.uid, me.gid));
+
+        let config = PrivilegesConfig {
+            user: Some(me.name),
+            group: Some(String::from("no-such-group-here")),
+        };
+        assert!(matches!(
+            resolve(Some(&config), None, None),
+            Err(Error::UnknownGroupError(_))
+        ));
+
+        let config = PrivilegesConfig {
+            user: Some(String::from("no-such-user-here")),
+            group: None,
+        };
+        assert!(matches!(
+            resolve(Some(&config), None, None),
+            Err(Error::UnknownUserError(_))
+        ));
+    }
+}
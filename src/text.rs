@@ -0,0 +1,264 @@
+use crate::layout::Layout;
+use input_linux::{Key, KeyState};
+use std::fmt;
+
+/// How text is typed, since there's no way to send a character that isn't on
+/// a key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum TextMethod {
+    /// Ctrl+Shift+U, then the code point in hex, then Space, which GTK and
+    /// IBus turn into the character.
+    #[default]
+    Hex,
+    /// The compose key followed by a sequence from the default compose table,
+    /// which works wherever compose does.
+    Compose(Key),
+    /// Only characters that are on the layout, typed with Shift if they need
+    /// it.
+    Layout,
+}
+
+/// The keys to press and release to type some text on a layout, or the first
+/// character that can't be typed with the method.
+pub fn keys(
+    text: &str,
+    method: &TextMethod,
+    layout: &Layout,
+) -> Result<Vec<(Key, KeyState)>, char> {
+    let mut keys = vec![];
+
+    for c in text.chars() {
+        match method {
+            // Characters on the layout are typed as they are, which is
+            // quicker and works everywhere.
+            _ if layout.find(c).is_some() => keys.extend(type_char(c, layout)?),
+            TextMethod::Hex => {
+                let (u, _) = layout.find('u').ok_or(c)?;
+                keys.extend(
+                    [Key::LeftCtrl, Key::LeftShift, u]
+                        .iter()
+                        .map(|key| (*key, KeyState::PRESSED)),
+                );
+                keys.extend(
+                    [u, Key::LeftShift, Key::LeftCtrl]
+                        .iter()
+                        .map(|key| (*key, KeyState::RELEASED)),
+                );
+
+                for digit in format!("{:x}", c as u32).chars() {
+                    keys.extend(type_char(digit, layout)?);
+                }
+
+                keys.extend(tap(Key::Space));
+            }
+            TextMethod::Compose(compose) => {
+                let sequence = compose_sequence(c).ok_or(c)?;
+                keys.extend(tap(*compose));
+
+                for c in sequence.chars() {
+                    keys.extend(type_char(c, layout)?);
+                }
+            }
+            TextMethod::Layout => return Err(c),
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Types a character that's on the layout.
+fn type_char(c: char, layout: &Layout) -> Result<Vec<(Key, KeyState)>, char> {
+    let (key, shift) = layout.find(c).ok_or(c)?;
+
+    Ok(if shift {
+        let mut keys = vec![(Key::LeftShift, KeyState::PRESSED)];
+        keys.extend(tap(key));
+        keys.push((Key::LeftShift, KeyState::RELEASED));
+        keys
+    } else {
+        tap(key).to_vec()
+    })
+}
+
+fn tap(key: Key) -> [(Key, KeyState); 2] {
+    [(key, KeyState::PRESSED), (key, KeyState::RELEASED)]
+}
+
+/// Finds the keys typed after the compose key for a character, from the
+/// default X11 compose table.
+fn compose_sequence(c: char) -> Option<String> {
+    /// Accents typed before a letter, and the accented letters in the same
+    /// order as `BASES`.
+    const ACCENTS: &[(char, &str)] = &[
+        ('\'', "áéíóúýÁÉÍÓÚÝ"),
+        ('`', "àèìòù\u{0}ÀÈÌÒÙ\u{0}"),
+        ('^', "âêîôû\u{0}ÂÊÎÔÛ\u{0}"),
+        ('"', "äëïöüÿÄËÏÖÜŸ"),
+    ];
+    const BASES: &str = "aeiouyAEIOUY";
+    const SEQUENCES: &[(char, &str)] = &[
+        ('ã', "~a"),
+        ('õ', "~o"),
+        ('ñ', "~n"),
+        ('Ã', "~A"),
+        ('Õ', "~O"),
+        ('Ñ', "~N"),
+        ('ç', ",c"),
+        ('Ç', ",C"),
+        ('å', "oa"),
+        ('Å', "oA"),
+        ('æ', "ae"),
+        ('Æ', "AE"),
+        ('ø', "/o"),
+        ('Ø', "/O"),
+        ('ß', "ss"),
+        ('→', "->"),
+        ('←', "<-"),
+        ('€', "=e"),
+        ('£', "-l"),
+        ('¥', "=y"),
+        ('°', "oo"),
+        ('©', "oc"),
+        ('®', "or"),
+        ('™', "tm"),
+        ('±', "+-"),
+        ('×', "xx"),
+        ('÷', ":-"),
+        ('½', "12"),
+        ('¼', "14"),
+        ('¾', "34"),
+        ('«', "<<"),
+        ('»', ">>"),
+        ('¿', "??"),
+        ('¡', "!!"),
+        ('—', "---"),
+        ('–', "--."),
+        ('…', ".."),
+    ];
+
+    for (accent, letters) in ACCENTS {
+        if let Some(index) = letters.chars().position(|letter| letter == c && c != '\0') {
+            let base = BASES.chars().nth(index)?;
+            return Some(format!("{}{}", accent, base));
+        }
+    }
+
+    SEQUENCES
+        .iter()
+        .find(|(composed, _)| *composed == c)
+        .map(|(_, sequence)| String::from(*sequence))
+}
+
+impl fmt::Display for TextMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextMethod::Hex => write!(f, "hex"),
+            TextMethod::Compose(_) => write!(f, "compose"),
+            TextMethod::Layout => write!(f, "layout"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keys, TextMethod};
+    use crate::layout::Layout;
+    use input_linux::{Key, KeyState};
+
+    fn pressed(keys: &[(Key, KeyState)]) -> Vec<Key> {
+        keys.iter()
+            .filter(|(_, state)| *state == KeyState::PRESSED)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    #[test]
+    fn it_types_hex_code_points() {
+        let typed = keys("aλ", &TextMethod::Hex, &Layout::default()).unwrap();
+
+        assert_eq!(
+            pressed(&typed),
+            vec![
+                Key::A,
+                Key::LeftCtrl,
+                Key::LeftShift,
+                Key::U,
+                Key::Num3,
+                Key::B,
+                Key::B,
+                Key::Space
+            ]
+        );
+        assert_eq!(
+            typed[2..8],
+            [
+                (Key::LeftCtrl, KeyState::PRESSED),
+                (Key::LeftShift, KeyState::PRESSED),
+                (Key::U, KeyState::PRESSED),
+                (Key::U, KeyState::RELEASED),
+                (Key::LeftShift, KeyState::RELEASED),
+                (Key::LeftCtrl, KeyState::RELEASED),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_types_compose_sequences() {
+        let typed = keys(
+            "É→",
+            &TextMethod::Compose(Key::RightAlt),
+            &Layout::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            pressed(&typed),
+            vec![
+                Key::RightAlt,
+                Key::Apostrophe,
+                Key::LeftShift,
+                Key::E,
+                Key::RightAlt,
+                Key::Minus,
+                Key::LeftShift,
+                Key::Dot
+            ]
+        );
+        assert_eq!(
+            keys("λ", &TextMethod::Compose(Key::RightAlt), &Layout::default()),
+            Err('λ')
+        );
+        assert_eq!(
+            keys("ì", &TextMethod::Compose(Key::RightAlt), &Layout::default()).map(|_| ()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn it_types_characters_where_they_are_on_the_layout() {
+        let dvorak = Layout::bundled("us", Some("dvorak")).unwrap();
+
+        assert_eq!(
+            keys("uλ", &TextMethod::Hex, &dvorak).map(|typed| pressed(&typed)),
+            Ok(vec![
+                Key::F,
+                Key::LeftCtrl,
+                Key::LeftShift,
+                Key::F,
+                Key::Num3,
+                Key::N,
+                Key::N,
+                Key::Space
+            ])
+        );
+    }
+
+    #[test]
+    fn it_only_types_characters_on_the_layout() {
+        assert_eq!(
+            keys("A!", &TextMethod::Layout, &Layout::default()).map(|typed| pressed(&typed)),
+            Ok(vec![Key::LeftShift, Key::A, Key::LeftShift, Key::Num1])
+        );
+        assert_eq!(keys("é", &TextMethod::Layout, &Layout::default()), Err('é'));
+    }
+}